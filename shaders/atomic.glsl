@@ -0,0 +1,10 @@
+// storage buffer members of this type become atomic integers, the shader compiler replaces
+// calls of atomic_add with atomic additions, returning the previous value. Compilation fails
+// if a call can't be replaced, so the placeholder result is never used
+struct AtomicUint {
+    uint value;
+};
+
+uint atomic_add(AtomicUint target, uint value) {
+    return 0u;
+}
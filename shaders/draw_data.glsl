@@ -0,0 +1,15 @@
+struct DrawData {
+    mat4 model;
+    uint material_index;
+    uint object_id;
+    uint joint_offset;
+    uint morph_weight_offset;
+    uint morph_target_count;
+    int morph_delta_offset;
+    uint padding[2];
+};
+
+// indexed by the instance index, which starts at the first instance of the draw command
+layout(set = 0, binding = 1) readonly buffer Draws {
+    DrawData draws[];
+};
@@ -0,0 +1,47 @@
+struct MaterialData {
+    vec4 base_color_factor;
+    uint base_color_texture;
+    uint normal_texture;
+    float alpha_cutoff;
+    uint base_color_tex_coord;
+    uint normal_tex_coord;
+    uint padding[3];
+};
+
+layout(set = 1, binding = 0) readonly buffer Materials {
+    MaterialData materials[];
+};
+
+layout(set = 1, binding = 1) uniform sampler u_sampler;
+
+// with bindless textures all materials share one set, otherwise each material has its own.
+// Every permutation declares all textures, so all of them share the same pipeline layout
+#ifdef BINDLESS_TEXTURES
+layout(set = 1, binding = 2) uniform texture2D u_textures[];
+#else
+layout(set = 1, binding = 2) uniform texture2D u_base_color_texture;
+layout(set = 1, binding = 3) uniform texture2D u_normal_texture;
+#endif
+
+// each texture of a material reads either the first or the second uv set
+vec2 select_tex_coord(uint tex_coord, vec2 tex_coord_0, vec2 tex_coord_1) {
+    return tex_coord == 0 ? tex_coord_0 : tex_coord_1;
+}
+
+vec4 sample_base_color(MaterialData material, vec2 tex_coord) {
+#ifdef BINDLESS_TEXTURES
+    return texture(sampler2D(u_textures[material.base_color_texture], u_sampler), tex_coord);
+#else
+    return texture(sampler2D(u_base_color_texture, u_sampler), tex_coord);
+#endif
+}
+
+// tangent space normal, the texture stores it in unsigned normalized components
+vec3 sample_normal(MaterialData material, vec2 tex_coord) {
+#ifdef BINDLESS_TEXTURES
+    vec3 value = texture(sampler2D(u_textures[material.normal_texture], u_sampler), tex_coord).xyz;
+#else
+    vec3 value = texture(sampler2D(u_normal_texture, u_sampler), tex_coord).xyz;
+#endif
+    return value * 2.0 - 1.0;
+}
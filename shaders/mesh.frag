@@ -1,9 +1,50 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
+
+#include "material.glsl"
 
 layout(location = 0) in vec3 in_normal;
+layout(location = 1) in vec2 in_tex_coord_0;
+layout(location = 2) flat in uint in_material_index;
+layout(location = 3) in vec2 in_tex_coord_1;
+layout(location = 4) in vec4 in_color;
+#ifdef HAS_NORMAL_MAP
+layout(location = 5) in vec4 in_tangent;
+#endif
 
 layout(location = 0) out vec4 out_color;
 
+const vec3 LIGHT_DIRECTION = vec3(0.3, -0.5, 0.8);
+
 void main() {
-    out_color = vec4((in_normal + 1.0) / 2.0, 1.0);
+    MaterialData material = materials[in_material_index];
+
+    // vertex colors are white when the mesh has none
+    vec4 base_color = material.base_color_factor * in_color;
+#ifdef HAS_BASE_COLOR_TEXTURE
+    vec2 tex_coord = select_tex_coord(material.base_color_tex_coord, in_tex_coord_0, in_tex_coord_1);
+    base_color *= sample_base_color(material, tex_coord);
+#endif
+
+#ifdef ALPHA_MASK
+    if (base_color.a < material.alpha_cutoff) {
+        discard;
+    }
+#endif
+
+#ifdef HAS_NORMAL_MAP
+    // the interpolated tangent is made orthogonal to the normal again
+    vec3 vertex_normal = normalize(in_normal);
+    vec3 tangent = normalize(in_tangent.xyz - vertex_normal * dot(vertex_normal, in_tangent.xyz));
+    vec3 bitangent = cross(vertex_normal, tangent) * in_tangent.w;
+    vec2 normal_tex_coord = select_tex_coord(material.normal_tex_coord, in_tex_coord_0, in_tex_coord_1);
+    vec3 normal = mat3(tangent, bitangent, vertex_normal) * sample_normal(material, normal_tex_coord);
+#else
+    vec3 normal = in_normal;
+#endif
+
+    // back faces are only drawn for double sided materials
+    normal = gl_FrontFacing ? normal : -normal;
+    float diffuse = max(dot(normalize(normal), normalize(LIGHT_DIRECTION)), 0.0);
+    out_color = vec4(base_color.rgb * (0.3 + 0.7 * diffuse), base_color.a);
 }
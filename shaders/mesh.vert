@@ -1,7 +1,31 @@
 #version 450
+#extension GL_GOOGLE_include_directive : require
 
+#include "draw_data.glsl"
+#include "skinning.glsl"
+#include "morph_targets.glsl"
+
+// locations match the vertex attributes, optional ones are enabled by the vertex layout
 layout(location = 0) in vec3 in_position;
+#ifdef HAS_NORMAL
 layout(location = 1) in vec3 in_normal;
+#endif
+#ifdef HAS_TEX_COORD_0
+layout(location = 2) in vec2 in_tex_coord_0;
+#endif
+#ifdef HAS_TEX_COORD_1
+layout(location = 3) in vec2 in_tex_coord_1;
+#endif
+#ifdef HAS_NORMAL_MAP
+layout(location = 4) in vec4 in_tangent;
+#endif
+#ifdef HAS_COLOR_0
+layout(location = 5) in vec4 in_color;
+#endif
+#ifdef SKINNED
+layout(location = 6) in uvec4 in_joints;
+layout(location = 7) in vec4 in_weights;
+#endif
 
 layout(set = 0, binding = 0) uniform WorldData {
     mat4 u_view;
@@ -9,8 +33,67 @@ layout(set = 0, binding = 0) uniform WorldData {
 };
 
 layout(location = 0) out vec3 out_normal;
+layout(location = 1) out vec2 out_tex_coord_0;
+layout(location = 2) flat out uint out_material_index;
+layout(location = 3) out vec2 out_tex_coord_1;
+layout(location = 4) out vec4 out_color;
+#ifdef HAS_NORMAL_MAP
+// w is the handedness of the bitangent
+layout(location = 5) out vec4 out_tangent;
+#endif
 
 void main() {
-    gl_Position = u_projection * u_view * vec4(in_position, 1.0);
-    out_normal = in_normal;
+    DrawData draw = draws[gl_InstanceIndex];
+
+    // joint matrices already contain the transform of the instance
+#ifdef SKINNED
+    mat4 model = skin_matrix(draw.joint_offset, in_joints, in_weights);
+#else
+    mat4 model = draw.model;
+#endif
+
+    vec3 position = in_position;
+#ifdef HAS_NORMAL
+    vec3 normal = in_normal;
+#else
+    // meshes without normals are lit as if they were facing up
+    vec3 normal = vec3(0.0, 0.0, 1.0);
+#endif
+#ifdef HAS_NORMAL_MAP
+    vec3 tangent = in_tangent.xyz;
+#else
+    // only normal maps read the tangent
+    vec3 tangent = vec3(1.0, 0.0, 0.0);
+#endif
+
+    // targets displace the vertex before it is skinned
+#ifdef MORPH_TARGETS
+    apply_morph_targets(draw, int(gl_VertexIndex), position, normal, tangent);
+#endif
+
+    gl_Position = u_projection * u_view * model * vec4(position, 1.0);
+#ifdef HAS_NORMAL
+    out_normal = mat3(model) * normal;
+#else
+    out_normal = normal;
+#endif
+#ifdef HAS_TEX_COORD_0
+    out_tex_coord_0 = in_tex_coord_0;
+#else
+    out_tex_coord_0 = vec2(0.0);
+#endif
+#ifdef HAS_TEX_COORD_1
+    out_tex_coord_1 = in_tex_coord_1;
+#else
+    out_tex_coord_1 = vec2(0.0);
+#endif
+#ifdef HAS_COLOR_0
+    out_color = in_color;
+#else
+    out_color = vec4(1.0);
+#endif
+#ifdef HAS_NORMAL_MAP
+    out_tangent = vec4(mat3(model) * tangent, in_tangent.w);
+#endif
+    out_material_index = draw.material_index;
 }
@@ -0,0 +1,28 @@
+// weights of the morph targets of all instances, a morphed draw reads the range starting at its weight offset
+layout(set = 0, binding = 3) readonly buffer MorphWeights {
+    float morph_weights[];
+};
+
+struct MorphDelta {
+    vec4 position;
+    vec4 normal;
+    vec4 tangent;
+};
+
+// deltas of all morphed meshes, the deltas of a vertex are stored next to each other for all targets.
+// Every mesh permutation declares both buffers, so all of them share the same pipeline layout
+layout(set = 0, binding = 4) readonly buffer MorphDeltas {
+    MorphDelta morph_deltas[];
+};
+
+// applies the weighted deltas of all targets, the w components are ignored
+void apply_morph_targets(DrawData draw, int vertex_index, inout vec3 position, inout vec3 normal, inout vec3 tangent) {
+    uint first_delta = uint(draw.morph_delta_offset + vertex_index * int(draw.morph_target_count));
+    for (uint i = 0; i < draw.morph_target_count; i++) {
+        float weight = morph_weights[draw.morph_weight_offset + i];
+        MorphDelta delta = morph_deltas[first_delta + i];
+        position += weight * delta.position.xyz;
+        normal += weight * delta.normal.xyz;
+        tangent += weight * delta.tangent.xyz;
+    }
+}
@@ -0,0 +1,12 @@
+// joint matrices of all skins in world space, a skinned draw reads the range starting at its joint offset.
+// Every mesh permutation declares it, so all of them share the same pipeline layout
+layout(set = 0, binding = 2) readonly buffer JointMatrices {
+    mat4 joint_matrices[];
+};
+
+mat4 skin_matrix(uint joint_offset, uvec4 joints, vec4 weights) {
+    return weights.x * joint_matrices[joint_offset + joints.x]
+        + weights.y * joint_matrices[joint_offset + joints.y]
+        + weights.z * joint_matrices[joint_offset + joints.z]
+        + weights.w * joint_matrices[joint_offset + joints.w];
+}
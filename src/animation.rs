@@ -0,0 +1,535 @@
+use anyhow::{Error, Result};
+
+use crate::skeleton::NodeHierarchy;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interpolation {
+    Step,
+    Linear,
+    CubicSpline,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Property {
+    Translation,
+    Rotation,
+    Scale,
+    Weights,
+}
+
+// keyframes of one property of one node
+#[derive(Debug, Clone)]
+struct Channel {
+    node: usize,
+    property: Property,
+    interpolation: Interpolation,
+    times: Vec<f32>,
+    // flattened elements, cubic splines store an in tangent, the value and an out tangent for each keyframe
+    values: Vec<f32>,
+    // components of one element, for weights it is the number of morph targets
+    components: usize,
+}
+
+impl Channel {
+    fn from_gltf(channel: &gltf::animation::Channel, blob: &[u8]) -> Result<Self> {
+        use gltf::animation::util::ReadOutputs;
+
+        let reader = channel.reader(|_| Some(blob));
+        let times = reader
+            .read_inputs()
+            .ok_or_else(|| Error::msg("animation channel has no keyframe times"))?
+            .collect::<Vec<f32>>();
+        let (property, values) = match reader
+            .read_outputs()
+            .ok_or_else(|| Error::msg("animation channel has no keyframe values"))?
+        {
+            ReadOutputs::Translations(values) => (Property::Translation, values.flatten().collect::<Vec<_>>()),
+            ReadOutputs::Rotations(values) => (Property::Rotation, values.into_f32().flatten().collect()),
+            ReadOutputs::Scales(values) => (Property::Scale, values.flatten().collect()),
+            ReadOutputs::MorphTargetWeights(values) => (Property::Weights, values.into_f32().collect()),
+        };
+
+        let interpolation = match channel.sampler().interpolation() {
+            gltf::animation::Interpolation::Step => Interpolation::Step,
+            gltf::animation::Interpolation::Linear => Interpolation::Linear,
+            gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline,
+        };
+
+        let elements_per_keyframe = match interpolation {
+            Interpolation::CubicSpline => 3,
+            _ => 1,
+        };
+        let components = match property {
+            Property::Translation | Property::Scale => 3,
+            Property::Rotation => 4,
+            Property::Weights => values.len() / (times.len() * elements_per_keyframe).max(1),
+        };
+        if times.is_empty() || values.len() != times.len() * elements_per_keyframe * components {
+            return Err(Error::msg(format!(
+                "animation channel has {} keyframes, but {} values",
+                times.len(),
+                values.len()
+            )));
+        }
+
+        Ok(Self {
+            node: channel.target().node().index(),
+            property,
+            interpolation,
+            times,
+            values,
+            components,
+        })
+    }
+
+    #[inline]
+    fn duration(&self) -> f32 {
+        self.times.last().copied().unwrap_or(0.0)
+    }
+
+    // element of a keyframe, for cubic splines 0 is the in tangent, 1 the value and 2 the out tangent
+    fn element(&self, keyframe: usize, element: usize) -> &[f32] {
+        let elements_per_keyframe = match self.interpolation {
+            Interpolation::CubicSpline => 3,
+            _ => 1,
+        };
+        let start = (keyframe * elements_per_keyframe + element) * self.components;
+        &self.values[start..start + self.components]
+    }
+
+    // values outside of the keyframes are clamped to the first or the last one
+    fn sample(&self, time: f32, output: &mut Vec<f32>) {
+        let value_element = match self.interpolation {
+            Interpolation::CubicSpline => 1,
+            _ => 0,
+        };
+
+        output.clear();
+        let next = self.times.partition_point(|&keyframe_time| keyframe_time <= time);
+        if next == 0 || next == self.times.len() {
+            let keyframe = next.saturating_sub(1);
+            output.extend_from_slice(self.element(keyframe, value_element));
+            return;
+        }
+
+        let previous = next - 1;
+        let delta = self.times[next] - self.times[previous];
+        let t = (time - self.times[previous]) / delta;
+
+        match self.interpolation {
+            Interpolation::Step => output.extend_from_slice(self.element(previous, value_element)),
+            Interpolation::Linear if self.property == Property::Rotation => {
+                let rotation = slerp(
+                    &glm::make_quat(self.element(previous, 0)),
+                    &glm::make_quat(self.element(next, 0)),
+                    t,
+                );
+                output.extend_from_slice(rotation.coords.as_slice());
+            }
+            Interpolation::Linear => output.extend(
+                self.element(previous, 0)
+                    .iter()
+                    .zip(self.element(next, 0))
+                    .map(|(first, second)| first + (second - first) * t),
+            ),
+            Interpolation::CubicSpline => {
+                // hermite spline, tangents are scaled by the duration of the keyframe
+                let (t2, t3) = (t * t, t * t * t);
+                let value_start = 2.0 * t3 - 3.0 * t2 + 1.0;
+                let tangent_start = (t3 - 2.0 * t2 + t) * delta;
+                let value_end = -2.0 * t3 + 3.0 * t2;
+                let tangent_end = (t3 - t2) * delta;
+
+                let start = self.element(previous, 1);
+                let out_tangent = self.element(previous, 2);
+                let in_tangent = self.element(next, 0);
+                let end = self.element(next, 1);
+                output.extend((0..self.components).map(|i| {
+                    value_start * start[i]
+                        + tangent_start * out_tangent[i]
+                        + value_end * end[i]
+                        + tangent_end * in_tangent[i]
+                }));
+
+                if self.property == Property::Rotation {
+                    let rotation = glm::quat_normalize(&glm::make_quat(output));
+                    output.copy_from_slice(rotation.coords.as_slice());
+                }
+            }
+        }
+    }
+}
+
+// a named set of channels, which is played as a whole
+#[derive(Debug, Clone)]
+pub struct AnimationClip {
+    name: String,
+    channels: Vec<Channel>,
+    duration: f32,
+}
+
+impl AnimationClip {
+    pub fn from_gltf(animation: &gltf::Animation, blob: &[u8]) -> Result<Self> {
+        let channels = animation
+            .channels()
+            .map(|channel| Channel::from_gltf(&channel, blob))
+            .collect::<Result<Vec<_>>>()?;
+        let duration = channels.iter().map(Channel::duration).fold(0.0, f32::max);
+
+        Ok(Self {
+            name: animation
+                .name()
+                .map(str::to_owned)
+                .unwrap_or_else(|| format!("animation {}", animation.index())),
+            channels,
+            duration,
+        })
+    }
+
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn duration(&self) -> f32 {
+        self.duration
+    }
+}
+
+// position of a clip which is being played
+#[derive(Debug, Copy, Clone)]
+struct Track {
+    clip: usize,
+    time: f32,
+}
+
+// plays one clip at a time, switching clips can fade from the previous one
+pub struct AnimationPlayer {
+    clips: Vec<AnimationClip>,
+    current: Option<Track>,
+    // the previous clip while it fades out, with the weight of the current one
+    fading: Option<(Track, f32)>,
+    fade_duration: f32,
+    paused: bool,
+    looping: bool,
+    speed: f32,
+    sample: Vec<f32>,
+}
+
+impl AnimationPlayer {
+    pub fn new(clips: Vec<AnimationClip>) -> Self {
+        Self {
+            clips,
+            current: None,
+            fading: None,
+            fade_duration: 0.0,
+            paused: false,
+            looping: true,
+            speed: 1.0,
+            sample: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn clips(&self) -> &[AnimationClip] {
+        &self.clips
+    }
+
+    #[inline]
+    pub fn current_clip(&self) -> Option<usize> {
+        self.current.map(|track| track.clip)
+    }
+
+    // starts the clip from the beginning, replacing the current one immediately
+    pub fn play(&mut self, clip: usize) {
+        self.blend_to(clip, 0.0);
+    }
+
+    // starts the clip from the beginning and fades from the current one over the duration in seconds
+    pub fn blend_to(&mut self, clip: usize, duration: f32) {
+        if clip >= self.clips.len() {
+            return;
+        }
+
+        self.fading = match self.current {
+            Some(current) if duration > 0.0 => Some((current, 0.0)),
+            _ => None,
+        };
+        self.fade_duration = duration;
+        self.current = Some(Track { clip, time: 0.0 });
+        self.paused = false;
+    }
+
+    pub fn stop(&mut self) {
+        self.current = None;
+        self.fading = None;
+    }
+
+    #[inline]
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn set_looping(&mut self, looping: bool) {
+        self.looping = looping;
+    }
+
+    #[inline]
+    pub fn is_looping(&self) -> bool {
+        self.looping
+    }
+
+    // negative speeds play the clips backwards
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed;
+    }
+
+    #[inline]
+    pub fn speed(&self) -> f32 {
+        self.speed
+    }
+
+    // moves the current clip to the time in seconds, the fade is finished
+    pub fn seek(&mut self, time: f32) {
+        if let Some(current) = &mut self.current {
+            current.time = wrap_time(time, self.clips[current.clip].duration, self.looping);
+        }
+        self.fading = None;
+    }
+
+    pub fn update(&mut self, dt: f32) {
+        if self.paused {
+            return;
+        }
+
+        let delta = dt * self.speed;
+        if let Some(current) = &mut self.current {
+            current.time = wrap_time(current.time + delta, self.clips[current.clip].duration, self.looping);
+        }
+        if let Some((previous, weight)) = &mut self.fading {
+            previous.time = wrap_time(previous.time + delta, self.clips[previous.clip].duration, self.looping);
+            *weight += dt / self.fade_duration;
+            if *weight >= 1.0 {
+                self.fading = None;
+            }
+        }
+    }
+
+    // nodes which are not animated by the playing clips return to their rest pose
+    pub fn apply(&mut self, hierarchy: &mut NodeHierarchy) {
+        hierarchy.reset_to_rest();
+
+        let current = match self.current {
+            Some(current) => current,
+            None => return,
+        };
+
+        if let Some((previous, _)) = self.fading {
+            self.apply_track(previous, 1.0, hierarchy);
+        }
+        let weight = self.fading.map_or(1.0, |(_, weight)| weight);
+        self.apply_track(current, weight, hierarchy);
+    }
+
+    // blends the sampled values over the values already in the nodes
+    fn apply_track(&mut self, track: Track, weight: f32, hierarchy: &mut NodeHierarchy) {
+        for channel in &self.clips[track.clip].channels {
+            channel.sample(track.time, &mut self.sample);
+            let sample = &self.sample;
+
+            let node = match hierarchy.node_mut(channel.node) {
+                Some(node) => node,
+                None => continue,
+            };
+            match channel.property {
+                Property::Translation => {
+                    node.translation = glm::lerp(&node.translation, &glm::make_vec3(sample), weight)
+                }
+                Property::Rotation => node.rotation = slerp(&node.rotation, &glm::make_quat(sample), weight),
+                Property::Scale => node.scale = glm::lerp(&node.scale, &glm::make_vec3(sample), weight),
+                Property::Weights => {
+                    node.weights.resize(sample.len(), 0.0);
+                    for (target, value) in node.weights.iter_mut().zip(sample) {
+                        *target += (value - *target) * weight;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn wrap_time(time: f32, duration: f32, looping: bool) -> f32 {
+    if looping && duration > 0.0 {
+        time.rem_euclid(duration)
+    } else {
+        time.max(0.0).min(duration)
+    }
+}
+
+// interpolates along the shorter arc
+fn slerp(first: &glm::Quat, second: &glm::Quat, t: f32) -> glm::Quat {
+    let second = if glm::quat_dot(first, second) < 0.0 {
+        -second
+    } else {
+        *second
+    };
+    glm::quat_slerp(first, &second, t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(property: Property, interpolation: Interpolation, times: &[f32], values: &[f32]) -> Channel {
+        let components = match property {
+            Property::Translation | Property::Scale => 3,
+            Property::Rotation => 4,
+            Property::Weights => 1,
+        };
+        Channel {
+            node: 0,
+            property,
+            interpolation,
+            times: times.to_vec(),
+            values: values.to_vec(),
+            components,
+        }
+    }
+
+    fn sample(channel: &Channel, time: f32) -> Vec<f32> {
+        let mut output = Vec::new();
+        channel.sample(time, &mut output);
+        output
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len(), "{:?} != {:?}", actual, expected);
+        for (actual_value, expected_value) in actual.iter().zip(expected) {
+            assert!(
+                (actual_value - expected_value).abs() < 1e-5,
+                "{:?} != {:?}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    fn clip(duration: f32) -> AnimationClip {
+        AnimationClip {
+            name: String::new(),
+            channels: vec![channel(
+                Property::Translation,
+                Interpolation::Linear,
+                &[0.0, duration],
+                &[0.0; 6],
+            )],
+            duration,
+        }
+    }
+
+    #[test]
+    fn clamps_outside_of_keyframes() {
+        let linear = channel(
+            Property::Translation,
+            Interpolation::Linear,
+            &[1.0, 2.0],
+            &[0.0, 0.0, 0.0, 2.0, 4.0, 6.0],
+        );
+        assert_close(&sample(&linear, 0.0), &[0.0, 0.0, 0.0]);
+        assert_close(&sample(&linear, 1.5), &[1.0, 2.0, 3.0]);
+        assert_close(&sample(&linear, 5.0), &[2.0, 4.0, 6.0]);
+
+        // cubic splines clamp to the value, not to the tangents
+        let cubic = channel(
+            Property::Weights,
+            Interpolation::CubicSpline,
+            &[1.0, 2.0],
+            &[-1.0, 3.0, -1.0, -1.0, 7.0, -1.0],
+        );
+        assert_close(&sample(&cubic, 0.0), &[3.0]);
+        assert_close(&sample(&cubic, 5.0), &[7.0]);
+    }
+
+    #[test]
+    fn steps_to_previous_keyframe() {
+        let step = channel(Property::Weights, Interpolation::Step, &[0.0, 1.0], &[2.0, 4.0]);
+        assert_close(&sample(&step, 0.99), &[2.0]);
+        assert_close(&sample(&step, 1.0), &[4.0]);
+    }
+
+    #[test]
+    fn interpolates_hermite_spline() {
+        // value 0 with out tangent 2, then value 1 with in tangent 0, keyframes are 2 seconds apart
+        let cubic = channel(
+            Property::Weights,
+            Interpolation::CubicSpline,
+            &[0.0, 2.0],
+            &[0.0, 0.0, 2.0, 0.0, 1.0, 0.0],
+        );
+
+        // at the midpoint both values weigh 0.5 and the tangents 0.125 and -0.125, scaled by the duration
+        assert_close(
+            &sample(&cubic, 1.0),
+            &[0.5 * 0.0 + 0.125 * 2.0 * 2.0 + 0.5 * 1.0 - 0.125 * 2.0 * 0.0],
+        );
+        assert_close(&sample(&cubic, 0.0), &[0.0]);
+        assert_close(&sample(&cubic, 2.0), &[1.0]);
+    }
+
+    #[test]
+    fn interpolates_rotations_along_shorter_arc() {
+        // the second keyframe is a quarter turn around z with the opposite sign
+        let (sin, cos) = std::f32::consts::FRAC_PI_4.sin_cos();
+        let rotation = channel(
+            Property::Rotation,
+            Interpolation::Linear,
+            &[0.0, 1.0],
+            &[0.0, 0.0, 0.0, 1.0, 0.0, 0.0, -sin, -cos],
+        );
+
+        let (sin, cos) = std::f32::consts::FRAC_PI_8.sin_cos();
+        assert_close(&sample(&rotation, 0.5), &[0.0, 0.0, sin, cos]);
+    }
+
+    #[test]
+    fn wraps_time_backwards_when_looping() {
+        assert_close(&[wrap_time(-0.5, 2.0, true)], &[1.5]);
+        assert_close(&[wrap_time(4.5, 2.0, true)], &[0.5]);
+        assert_close(&[wrap_time(-0.5, 2.0, false)], &[0.0]);
+        assert_close(&[wrap_time(4.5, 2.0, false)], &[2.0]);
+
+        let mut player = AnimationPlayer::new(vec![clip(2.0)]);
+        player.set_speed(-1.0);
+        player.play(0);
+        player.update(0.5);
+        assert_close(&[player.current.unwrap().time], &[1.5]);
+
+        player.set_looping(false);
+        player.seek(0.25);
+        player.update(0.5);
+        assert_close(&[player.current.unwrap().time], &[0.0]);
+    }
+
+    #[test]
+    fn fade_finishes_at_full_weight() {
+        let mut player = AnimationPlayer::new(vec![clip(2.0), clip(1.0)]);
+        player.play(0);
+        player.update(0.5);
+        player.blend_to(1, 0.5);
+
+        player.update(0.25);
+        let (previous, weight) = player.fading.unwrap();
+        assert_eq!(previous.clip, 0);
+        assert_close(&[previous.time, weight], &[0.75, 0.5]);
+
+        player.update(0.25);
+        assert!(player.fading.is_none());
+        assert_eq!(player.current_clip(), Some(1));
+        assert_close(&[player.current.unwrap().time], &[0.5]);
+    }
+}
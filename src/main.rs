@@ -1,12 +1,12 @@
 #![windows_subsystem = "windows"]
 
-#[macro_use]
-extern crate memoffset;
-
+mod animation;
 mod camera;
 mod input;
 mod rendering;
 mod scene;
+mod settings;
+mod skeleton;
 
 extern crate nalgebra_glm as glm;
 
@@ -14,6 +14,7 @@ use std::sync::Arc;
 use std::time::Instant;
 
 use anyhow::Result;
+use ash::vk;
 use rendering::*;
 use winit::dpi::LogicalSize;
 use winit::event::VirtualKeyCode;
@@ -25,8 +26,10 @@ use winit::window::Window;
 use crate::camera::{Camera, FirstPersonController};
 use crate::input::{InputState, InputStateHandler};
 use crate::scene::Scene;
+use crate::settings::Settings;
 
 const IS_VALIDATION_ENABLED: bool = true;
+const SHADERS_DIR: &str = "shaders";
 
 struct App {
     primary_monitor: MonitorHandle,
@@ -36,8 +39,12 @@ struct App {
     validation: Validation,
     instance: Arc<Instance>,
     swapchain: Swapchain,
+    present_mode: PresentMode,
     pipeline_cache: PipelineCache,
+    layout_cache: DescriptorLayoutCache,
+    shader_watcher: ShaderWatcher,
     command_pool: Arc<CommandPool>,
+    uploader: UploadManager,
 
     scene: Scene,
     frame: Frame,
@@ -46,6 +53,7 @@ struct App {
     input_state: InputState,
     input_state_handler: InputStateHandler,
     camera_controller: FirstPersonController,
+    cull_stats: CullStats,
 
     is_fullscreen: bool,
     is_running: bool,
@@ -54,7 +62,7 @@ struct App {
 }
 
 impl App {
-    fn new() -> Result<(EventLoop<()>, Window, Self)> {
+    fn new(settings: Settings) -> Result<(EventLoop<()>, Window, Self)> {
         let entry = ash::Entry::new()?;
 
         let event_loop = EventLoop::new();
@@ -65,16 +73,54 @@ impl App {
         let instance = Arc::new(Instance::new(&entry, &window, IS_VALIDATION_ENABLED)?);
         let validation = Validation::new(&entry, &instance, IS_VALIDATION_ENABLED)?;
         let surface = Surface::new(&entry, &instance, &window)?;
-        let device = Arc::new(Device::new(instance.clone(), &surface, IS_VALIDATION_ENABLED)?);
-        let swapchain = Swapchain::new(&instance, &surface, device.clone(), &window)?;
-        let command_pool = Arc::new(CommandPool::new(device.clone())?);
+        let device = Arc::new(Device::new(
+            instance.clone(),
+            &surface,
+            IS_VALIDATION_ENABLED,
+            settings.device.as_ref(),
+        )?);
+        let present_mode = settings.present_mode;
+        let swapchain = Swapchain::new(&instance, &surface, device.clone(), &window, present_mode)?;
+        let command_pool = Arc::new(CommandPool::new(
+            device.clone(),
+            device.queues().graphics_queue_family,
+            vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER,
+        )?);
         let pipeline_cache = PipelineCache::new(device.clone())?;
-
-        let scene = Scene::new(device.clone(), &command_pool, "./models/monkey.glb")?;
-
-        let mut frame = Frame::new(device.clone(), command_pool.clone(), &pipeline_cache, &swapchain)?;
-        frame.logic_mut().update_meshes(scene.meshes());
-        frame.logic_mut().recreate_command_buffers(&swapchain)?;
+        let layout_cache = DescriptorLayoutCache::new(device.clone());
+        let mut uploader = UploadManager::new(device.clone())?;
+
+        let scene = Scene::new(
+            device.clone(),
+            &mut uploader,
+            "./models/monkey.glb",
+            settings.vertex_storage,
+        )?;
+
+        let shader_watcher = ShaderWatcher::new(SHADERS_DIR);
+        let mut frame = Frame::new(
+            device.clone(),
+            command_pool.clone(),
+            &pipeline_cache,
+            &layout_cache,
+            ShaderCompiler::new(SHADERS_DIR),
+            &swapchain,
+            FrameOptions {
+                bindless_textures: device.capabilities().descriptor_indexing && !settings.disable_bindless,
+                gpu_culling: !settings.disable_gpu_culling,
+            },
+        )?;
+        frame
+            .logic_mut()
+            .update_materials(&mut uploader, scene.materials(), scene.textures())?;
+        frame.logic_mut().update_meshes(
+            &pipeline_cache,
+            &mut uploader,
+            scene.geometry(),
+            scene.meshes(),
+            scene.materials(),
+            scene.instances(),
+        )?;
 
         let now = Instant::now();
         let input_state = InputState::new();
@@ -92,14 +138,19 @@ impl App {
                 surface,
                 instance,
                 swapchain,
+                present_mode,
                 pipeline_cache,
+                layout_cache,
+                shader_watcher,
                 command_pool,
+                uploader,
                 scene,
                 frame,
                 now,
                 input_state,
                 input_state_handler,
                 camera_controller,
+                cull_stats: CullStats::default(),
                 is_fullscreen: false,
                 is_running: true,
                 _entry: entry,
@@ -122,6 +173,15 @@ impl App {
         let dt = (then - self.now).as_secs_f32();
         self.now = then;
 
+        self.uploader.collect_completed()?;
+
+        let changed_shaders = self.shader_watcher.poll();
+        if !changed_shaders.is_empty() {
+            self.frame
+                .logic_mut()
+                .reload_shaders(&self.pipeline_cache, &changed_shaders)?;
+        }
+
         self.input_state_handler.flush();
         self.input_state.update(&self.input_state_handler);
         self.camera_controller.handle_movement(window, &self.input_state, dt);
@@ -148,25 +208,135 @@ impl App {
             self.is_fullscreen = !self.is_fullscreen;
         }
 
-        let current_frame = self.frame.current_frame();
+        let mut should_recreate_swapchain = false;
+
+        if self.input_state.keyboard().was_pressed(VirtualKeyCode::V) {
+            self.present_mode = self.present_mode.next();
+            should_recreate_swapchain = true;
+        }
+
+        self.handle_animation_keys();
+        if self.scene.update(dt) {
+            self.frame.logic_mut().update_instances(self.scene.instances());
+        }
+
         let camera = self.camera_controller.camera();
-        self.frame
-            .logic_mut()
-            .pipeline_layout_mut()
-            .uniform_buffers_mut()
-            .update_world_data(current_frame, camera.view(), camera.projection())?;
+        let was_resized = self.frame.draw(
+            &self.swapchain,
+            &mut self.uploader,
+            camera.view(),
+            camera.projection(),
+            self.scene.joint_matrices(),
+            self.scene.morph_weights(),
+        )?;
+        if self.frame.cull_stats() != self.cull_stats {
+            self.cull_stats = self.frame.cull_stats();
+            log::debug!(
+                "visible draws: {}, culled draws: {}",
+                self.cull_stats.visible,
+                self.cull_stats.culled
+            );
+        }
 
-        let was_resized = self.frame.draw(&self.swapchain)?;
-        if was_resized {
-            self.device.wait_idle()?;
-            unsafe { self.swapchain.destroy() };
-            self.swapchain = Swapchain::new(&self.instance, &self.surface, self.device.clone(), window)?;
-            self.frame.recreate_logic(&self.swapchain)?;
+        if was_resized || should_recreate_swapchain {
+            self.recreate_swapchain(window)?;
+        }
+
+        if should_recreate_swapchain {
+            log::info!(
+                "present mode: {:?} (using {:?})",
+                self.present_mode,
+                self.swapchain.present_mode()
+            );
         }
 
         Ok(())
     }
 
+    // number keys blend to a clip, zero cycles through all of them and p pauses the player,
+    // l toggles looping, r reverses, minus and equals change the speed, home rewinds and backspace stops
+    fn handle_animation_keys(&mut self) {
+        const CLIP_KEYS: [VirtualKeyCode; 9] = [
+            VirtualKeyCode::Key1,
+            VirtualKeyCode::Key2,
+            VirtualKeyCode::Key3,
+            VirtualKeyCode::Key4,
+            VirtualKeyCode::Key5,
+            VirtualKeyCode::Key6,
+            VirtualKeyCode::Key7,
+            VirtualKeyCode::Key8,
+            VirtualKeyCode::Key9,
+        ];
+        const BLEND_DURATION: f32 = 0.3;
+
+        let keyboard = self.input_state.keyboard();
+        let player = self.scene.animation_player_mut();
+        let clip_count = player.clips().len();
+        if clip_count == 0 {
+            return;
+        }
+
+        let mut next_clip = CLIP_KEYS
+            .iter()
+            .position(|key| keyboard.was_pressed(*key))
+            .filter(|&clip| clip < clip_count);
+        if keyboard.was_pressed(VirtualKeyCode::Key0) {
+            next_clip = Some(player.current_clip().map_or(0, |clip| (clip + 1) % clip_count));
+        }
+
+        if let Some(clip) = next_clip {
+            player.blend_to(clip, BLEND_DURATION);
+            let clip = &player.clips()[clip];
+            log::info!("playing animation {} ({:.2}s)", clip.name(), clip.duration());
+        }
+
+        if keyboard.was_pressed(VirtualKeyCode::P) {
+            player.set_paused(!player.is_paused());
+            log::info!("animation paused: {}", player.is_paused());
+        }
+
+        if keyboard.was_pressed(VirtualKeyCode::L) {
+            player.set_looping(!player.is_looping());
+            log::info!("animation looping: {}", player.is_looping());
+        }
+
+        let speed = player.speed();
+        let next_speed = if keyboard.was_pressed(VirtualKeyCode::R) {
+            -speed
+        } else if keyboard.was_pressed(VirtualKeyCode::Minus) {
+            speed * 0.5
+        } else if keyboard.was_pressed(VirtualKeyCode::Equals) {
+            speed * 2.0
+        } else {
+            speed
+        };
+        if next_speed != speed {
+            player.set_speed(next_speed);
+            log::info!("animation speed: {}", next_speed);
+        }
+
+        if keyboard.was_pressed(VirtualKeyCode::Home) {
+            player.seek(0.0);
+        }
+
+        if keyboard.was_pressed(VirtualKeyCode::Back) {
+            player.stop();
+        }
+    }
+
+    fn recreate_swapchain(&mut self, window: &Window) -> Result<()> {
+        self.device.wait_idle()?;
+        unsafe { self.swapchain.destroy() };
+        self.swapchain = Swapchain::new(
+            &self.instance,
+            &self.surface,
+            self.device.clone(),
+            window,
+            self.present_mode,
+        )?;
+        self.frame.recreate_logic(&self.swapchain)
+    }
+
     fn run(mut self, event_loop: EventLoop<()>, window: Window) -> ! {
         event_loop.run(move |event, _, control_flow| {
             if !self.is_running {
@@ -215,8 +385,10 @@ impl Drop for App {
         unsafe {
             self.frame.destroy();
             self.scene.destroy();
+            self.uploader.destroy();
             self.command_pool.destroy();
             self.pipeline_cache.destroy();
+            self.layout_cache.destroy();
             self.swapchain.destroy();
             self.device.destroy();
             self.surface.destroy();
@@ -227,7 +399,8 @@ impl Drop for App {
 }
 
 fn run() -> Result<()> {
-    let (event_loop, window, app) = App::new()?;
+    let settings = Settings::from_args()?;
+    let (event_loop, window, app) = App::new(settings)?;
     app.run(event_loop, window)
 }
 
@@ -4,6 +4,7 @@ use super::Device;
 pub struct Buffer {
     device: Arc<Device>,
     size: vk::DeviceSize,
+    usage: vk::BufferUsageFlags,
     buffer: vk::Buffer,
     memory: Memory,
 }
@@ -36,6 +37,7 @@ impl Buffer {
         Ok(Self {
             device,
             size,
+            usage,
             buffer,
             memory,
         })
@@ -66,13 +68,13 @@ impl Buffer {
     }
 
     #[inline]
-    pub fn handle(&self) -> vk::Buffer {
-        self.buffer
+    pub fn usage(&self) -> vk::BufferUsageFlags {
+        self.usage
     }
 
     #[inline]
-    pub fn memory(&self) -> &Memory {
-        &self.memory
+    pub fn handle(&self) -> vk::Buffer {
+        self.buffer
     }
 }
 
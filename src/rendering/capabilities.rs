@@ -0,0 +1,225 @@
+use ash::version::InstanceV1_1;
+
+use super::prelude::*;
+use super::utils;
+
+// upper bound for the global texture array, the device limit is usually much higher
+const MAX_BINDLESS_TEXTURES: u32 = 16384;
+
+#[derive(Debug, Clone, Default)]
+pub struct DeviceCapabilities {
+    pub ray_tracing_nv: bool,
+    pub ray_tracing_pipeline_khr: bool,
+    pub descriptor_indexing: bool,
+    pub max_bindless_textures: u32,
+    pub draw_indirect_count: bool,
+    pub features: vk::PhysicalDeviceFeatures,
+    enabled_extensions: Vec<&'static CStr>,
+}
+
+impl DeviceCapabilities {
+    pub fn query(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> Result<Result<Self, String>> {
+        let device_extensions = unsafe { instance.enumerate_device_extension_properties(physical_device)? };
+        let available_extensions = device_extensions
+            .iter()
+            .map(|item| utils::from_vk_string_raw(&item.extension_name).to_owned())
+            .collect::<HashSet<_>>();
+        let is_available = |name: &CStr| available_extensions.contains(name);
+
+        // check required extensions
+        let missing_extensions = required_extensions()
+            .iter()
+            .filter(|name| !is_available(name))
+            .map(|name| name.to_string_lossy())
+            .collect::<Vec<_>>();
+
+        if !missing_extensions.is_empty() {
+            return Ok(Err(format!("missing extensions {}", missing_extensions.join(", "))));
+        }
+
+        let mut result = Self {
+            enabled_extensions: required_extensions().to_vec(),
+            ..Default::default()
+        };
+
+        // check optional extensions
+        for extension in optional_extensions() {
+            let is_supported =
+                is_available(extension.name) && extension.dependencies.iter().all(|name| is_available(name));
+            if !is_supported {
+                log::debug!("optional extension {:?} is not supported", extension.name);
+                continue;
+            }
+
+            match extension.capability {
+                Capability::RayTracingNv => result.ray_tracing_nv = true,
+                Capability::RayTracingPipelineKhr => result.ray_tracing_pipeline_khr = true,
+                Capability::DescriptorIndexing => result.descriptor_indexing = true,
+                Capability::DrawIndirectCount => result.draw_indirect_count = true,
+            }
+
+            if extension.enable {
+                for &name in extension.dependencies.iter().chain(std::iter::once(&extension.name)) {
+                    if !result.enabled_extensions.contains(&name) {
+                        result.enabled_extensions.push(name);
+                    }
+                }
+            }
+        }
+
+        // check optional features
+        let supported_features = unsafe { instance.get_physical_device_features(physical_device) };
+        result.features = vk::PhysicalDeviceFeatures {
+            fill_mode_non_solid: supported_features.fill_mode_non_solid,
+            multi_draw_indirect: supported_features.multi_draw_indirect,
+            draw_indirect_first_instance: supported_features.draw_indirect_first_instance,
+            sampler_anisotropy: supported_features.sampler_anisotropy,
+            ..Default::default()
+        };
+
+        if result.descriptor_indexing {
+            match query_bindless_textures(instance, physical_device) {
+                Some(max_bindless_textures) => result.max_bindless_textures = max_bindless_textures,
+                None => {
+                    log::debug!("descriptor indexing doesn't support bindless textures");
+                    result.descriptor_indexing = false;
+                }
+            }
+        }
+
+        Ok(Ok(result))
+    }
+
+    pub fn log(&self) {
+        self.enabled_extensions.iter().for_each(|extension| {
+            log::debug!("enabled device extension: {:?}", extension);
+        });
+        log::debug!("ray tracing (NV): {}", self.ray_tracing_nv);
+        log::debug!("ray tracing pipeline (KHR): {}", self.ray_tracing_pipeline_khr);
+        log::debug!(
+            "descriptor indexing: {}, max bindless textures: {}",
+            self.descriptor_indexing,
+            self.max_bindless_textures
+        );
+        log::debug!("draw indirect count: {}", self.draw_indirect_count);
+        log::debug!("enabled device features: {:?}", self.features);
+    }
+
+    #[inline]
+    pub fn enabled_extensions(&self) -> &[&'static CStr] {
+        &self.enabled_extensions
+    }
+
+    // features which must be enabled at device creation when descriptor indexing is used
+    pub fn descriptor_indexing_features(&self) -> vk::PhysicalDeviceDescriptorIndexingFeatures {
+        vk::PhysicalDeviceDescriptorIndexingFeatures {
+            runtime_descriptor_array: vk::TRUE,
+            descriptor_binding_partially_bound: vk::TRUE,
+            descriptor_binding_variable_descriptor_count: vk::TRUE,
+            descriptor_binding_sampled_image_update_after_bind: vk::TRUE,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+enum Capability {
+    RayTracingNv,
+    RayTracingPipelineKhr,
+    DescriptorIndexing,
+    DrawIndirectCount,
+}
+
+struct OptionalExtension {
+    name: &'static CStr,
+    dependencies: Vec<&'static CStr>,
+    capability: Capability,
+    // when false, the extension is only reported as available but not enabled
+    enable: bool,
+}
+
+fn required_extensions() -> &'static [&'static CStr] {
+    REQUIRED_EXTENSIONS.get_or_init(|| vec![ash::extensions::khr::Swapchain::name()])
+}
+
+fn optional_extensions() -> &'static [OptionalExtension] {
+    OPTIONAL_EXTENSIONS.get_or_init(|| {
+        vec![
+            OptionalExtension {
+                name: ash::extensions::nv::RayTracing::name(),
+                dependencies: vec![vk::KhrGetMemoryRequirements2Fn::name()],
+                capability: Capability::RayTracingNv,
+                enable: true,
+            },
+            OptionalExtension {
+                name: cstr(b"VK_KHR_ray_tracing_pipeline\0"),
+                // the pipeline can't be used without acceleration structures, which also pull in their own
+                // dependencies. Listed here so the capability is only reported when all of them are available
+                dependencies: vec![
+                    cstr(b"VK_KHR_acceleration_structure\0"),
+                    cstr(b"VK_KHR_spirv_1_4\0"),
+                    cstr(b"VK_KHR_deferred_host_operations\0"),
+                    cstr(b"VK_KHR_buffer_device_address\0"),
+                    cstr(b"VK_KHR_shader_float_controls\0"),
+                    vk::ExtDescriptorIndexingFn::name(),
+                    vk::KhrMaintenance3Fn::name(),
+                ],
+                capability: Capability::RayTracingPipelineKhr,
+                enable: false,
+            },
+            OptionalExtension {
+                name: vk::ExtDescriptorIndexingFn::name(),
+                dependencies: vec![vk::KhrMaintenance3Fn::name()],
+                capability: Capability::DescriptorIndexing,
+                enable: true,
+            },
+            OptionalExtension {
+                name: vk::KhrDrawIndirectCountFn::name(),
+                dependencies: Vec::new(),
+                capability: Capability::DrawIndirectCount,
+                enable: true,
+            },
+        ]
+    })
+}
+
+// returns the number of textures in the global array, or none when some required feature is missing
+fn query_bindless_textures(instance: &ash::Instance, physical_device: vk::PhysicalDevice) -> Option<u32> {
+    let properties = unsafe { instance.get_physical_device_properties(physical_device) };
+    if properties.api_version < vk::make_version(1, 1, 0) {
+        return None;
+    }
+
+    // features2 builder can't be extended in this version of ash, so the chain is linked manually
+    let mut indexing_features = vk::PhysicalDeviceDescriptorIndexingFeatures::default();
+    let mut features = vk::PhysicalDeviceFeatures2 {
+        p_next: &mut indexing_features as *mut _ as *mut c_void,
+        ..Default::default()
+    };
+    unsafe { instance.get_physical_device_features2(physical_device, &mut features) };
+
+    let is_supported = indexing_features.runtime_descriptor_array == vk::TRUE
+        && indexing_features.descriptor_binding_partially_bound == vk::TRUE
+        && indexing_features.descriptor_binding_variable_descriptor_count == vk::TRUE
+        && indexing_features.descriptor_binding_sampled_image_update_after_bind == vk::TRUE;
+    if !is_supported {
+        return None;
+    }
+
+    let mut indexing_properties = vk::PhysicalDeviceDescriptorIndexingProperties::default();
+    let mut properties = vk::PhysicalDeviceProperties2::builder().push_next(&mut indexing_properties);
+    unsafe { instance.get_physical_device_properties2(physical_device, &mut properties) };
+
+    Some(
+        MAX_BINDLESS_TEXTURES
+            .min(indexing_properties.max_descriptor_set_update_after_bind_sampled_images)
+            .min(indexing_properties.max_per_stage_descriptor_update_after_bind_sampled_images),
+    )
+}
+
+fn cstr(bytes: &'static [u8]) -> &'static CStr {
+    CStr::from_bytes_with_nul(bytes).unwrap()
+}
+
+static REQUIRED_EXTENSIONS: OnceCell<Vec<&'static CStr>> = OnceCell::new();
+static OPTIONAL_EXTENSIONS: OnceCell<Vec<OptionalExtension>> = OnceCell::new();
@@ -7,9 +7,10 @@ pub struct CommandPool {
 }
 
 impl CommandPool {
-    pub fn new(device: Arc<Device>) -> Result<Self> {
-        let command_pool_create_info =
-            vk::CommandPoolCreateInfo::builder().queue_family_index(device.queues().graphics_queue_family);
+    pub fn new(device: Arc<Device>, queue_family: u32, flags: vk::CommandPoolCreateFlags) -> Result<Self> {
+        let command_pool_create_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(queue_family)
+            .flags(flags);
 
         let command_pool = unsafe { device.handle().create_command_pool(&command_pool_create_info, None)? };
         log::debug!("created command pool {:?}", command_pool);
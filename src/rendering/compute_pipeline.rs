@@ -0,0 +1,193 @@
+use std::path::PathBuf;
+
+use super::pipeline_layout::PipelineLayoutDescription;
+use super::prelude::*;
+use super::{shader, DescriptorLayoutCache, Device, PipelineCache, PipelineLayout, ShaderCompiler, ShaderModule};
+
+pub struct ComputePipeline {
+    device: Arc<Device>,
+    pipeline: vk::Pipeline,
+    local_size: [u32; 3],
+    // files the shader was compiled from, empty if the pipeline was created from a module
+    dependencies: Vec<PathBuf>,
+}
+
+impl ComputePipeline {
+    pub fn new(
+        device: Arc<Device>,
+        pipeline_cache: &PipelineCache,
+        layout: vk::PipelineLayout,
+        shader_module: &ShaderModule,
+    ) -> Result<Self> {
+        if shader_module.stage() != vk::ShaderStageFlags::COMPUTE {
+            return Err(Error::msg(format!(
+                "expected compute shader, got {:?}",
+                shader_module.stage()
+            )));
+        }
+        let local_size = shader_module.reflection().local_size.unwrap_or([1, 1, 1]);
+
+        let stage = vk::PipelineShaderStageCreateInfo::builder()
+            .module(shader_module.handle())
+            .name(shader::main_function_name())
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .build();
+
+        let compute_pipeline_create_infos = [vk::ComputePipelineCreateInfo::builder()
+            .stage(stage)
+            .layout(layout)
+            .base_pipeline_handle(vk::Pipeline::null())
+            .base_pipeline_index(-1)
+            .build()];
+
+        let pipeline = unsafe {
+            device
+                .handle()
+                .create_compute_pipelines(pipeline_cache.handle(), &compute_pipeline_create_infos, None)
+                .map_err(|(_, e)| e)?[0]
+        };
+        log::debug!("created compute pipeline {:?}", pipeline);
+
+        Ok(Self {
+            device,
+            pipeline,
+            local_size,
+            dependencies: Vec::new(),
+        })
+    }
+
+    // compiles the shader and creates the pipeline with the layout reflected from it
+    pub fn compile(
+        device: Arc<Device>,
+        pipeline_cache: &PipelineCache,
+        layout_cache: &DescriptorLayoutCache,
+        shader_compiler: &ShaderCompiler,
+        name: &str,
+    ) -> Result<(PipelineLayout, Self)> {
+        let shader = shader_compiler.compile(name, &[])?;
+        let shader_module = ShaderModule::new(device.clone(), &shader.code)?;
+
+        let result =
+            PipelineLayout::from_shaders(device.clone(), layout_cache, &[&shader_module]).and_then(|pipeline_layout| {
+                match Self::new(device, pipeline_cache, pipeline_layout.handle(), &shader_module) {
+                    Ok(pipeline) => Ok((pipeline_layout, pipeline)),
+                    Err(e) => {
+                        unsafe { pipeline_layout.destroy() };
+                        Err(e)
+                    }
+                }
+            });
+
+        // the module is not needed after the pipeline is created
+        unsafe { shader_module.destroy() };
+        let (pipeline_layout, mut pipeline) = result?;
+        pipeline.dependencies = shader.dependencies;
+        Ok((pipeline_layout, pipeline))
+    }
+
+    // rebuilds the pipeline if its shader depends on changed files, keeping the old pipeline on errors.
+    // The layout is kept, so changes which need a different layout are only applied after a restart
+    pub fn reload(
+        &mut self,
+        pipeline_cache: &PipelineCache,
+        layout: &PipelineLayout,
+        shader_compiler: &ShaderCompiler,
+        name: &str,
+        changed: &[PathBuf],
+    ) -> Result<()> {
+        if !changed.iter().any(|path| self.dependencies.contains(path)) {
+            return Ok(());
+        }
+
+        let pipeline =
+            match Self::compile_for_layout(self.device.clone(), pipeline_cache, layout, shader_compiler, name) {
+                Ok(pipeline) => pipeline,
+                Err(e) => {
+                    log::error!(
+                        "failed to reload compute pipeline {}, keeping previous one: {:?}",
+                        name,
+                        e
+                    );
+                    return Ok(());
+                }
+            };
+
+        // the old pipeline can still be used by frames in flight
+        self.device.wait_idle()?;
+        let previous = std::mem::replace(self, pipeline);
+        unsafe { previous.destroy() };
+
+        log::info!("reloaded compute pipeline {}", name);
+        Ok(())
+    }
+
+    fn compile_for_layout(
+        device: Arc<Device>,
+        pipeline_cache: &PipelineCache,
+        layout: &PipelineLayout,
+        shader_compiler: &ShaderCompiler,
+        name: &str,
+    ) -> Result<Self> {
+        let shader = shader_compiler.compile(name, &[])?;
+        let shader_module = ShaderModule::new(device.clone(), &shader.code)?;
+
+        let result = PipelineLayoutDescription::from_shaders(&[&shader_module]).and_then(|description| {
+            if !description.is_compatible(layout.description()) {
+                return Err(Error::msg(format!(
+                    "{} needs a different pipeline layout, restart to apply the change",
+                    name
+                )));
+            }
+            Self::new(device, pipeline_cache, layout.handle(), &shader_module)
+        });
+
+        unsafe { shader_module.destroy() };
+        let mut pipeline = result?;
+        pipeline.dependencies = shader.dependencies;
+        Ok(pipeline)
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.device.handle().destroy_pipeline(self.pipeline, None);
+        log::debug!("dropped pipeline {:?}", self.pipeline);
+    }
+
+    pub fn bind(&self, command_buffer: vk::CommandBuffer) {
+        unsafe {
+            self.device
+                .handle()
+                .cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+        }
+    }
+
+    pub fn dispatch(&self, command_buffer: vk::CommandBuffer, group_count: [u32; 3]) {
+        unsafe {
+            self.device
+                .handle()
+                .cmd_dispatch(command_buffer, group_count[0], group_count[1], group_count[2]);
+        }
+    }
+
+    // dispatches enough workgroups to cover the specified number of invocations in each dimension
+    pub fn dispatch_invocations(&self, command_buffer: vk::CommandBuffer, invocations: [u32; 3]) {
+        self.dispatch(command_buffer, group_count(invocations, self.local_size));
+    }
+
+    // group counts are read from a vk::DispatchIndirectCommand written by an earlier pass
+    #[allow(unused)]
+    pub fn dispatch_indirect(&self, command_buffer: vk::CommandBuffer, buffer: vk::Buffer, offset: vk::DeviceSize) {
+        unsafe {
+            self.device
+                .handle()
+                .cmd_dispatch_indirect(command_buffer, buffer, offset);
+        }
+    }
+}
+
+pub fn group_count(invocations: [u32; 3], local_size: [u32; 3]) -> [u32; 3] {
+    [
+        invocations[0].div_ceil(local_size[0]),
+        invocations[1].div_ceil(local_size[1]),
+        invocations[2].div_ceil(local_size[2]),
+    ]
+}
@@ -0,0 +1,448 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use ash::prelude::VkResult;
+
+use super::prelude::*;
+use super::{Buffer, Device, ImageView};
+
+const INITIAL_SETS_PER_POOL: u32 = 64;
+const MAX_SETS_PER_POOL: u32 = 4096;
+
+// descriptors reserved in each pool per allocated set
+const POOL_SIZE_RATIOS: [(vk::DescriptorType, f32); 11] = [
+    (vk::DescriptorType::SAMPLER, 0.5),
+    (vk::DescriptorType::COMBINED_IMAGE_SAMPLER, 4.0),
+    (vk::DescriptorType::SAMPLED_IMAGE, 4.0),
+    (vk::DescriptorType::STORAGE_IMAGE, 1.0),
+    (vk::DescriptorType::UNIFORM_TEXEL_BUFFER, 1.0),
+    (vk::DescriptorType::STORAGE_TEXEL_BUFFER, 1.0),
+    (vk::DescriptorType::UNIFORM_BUFFER, 2.0),
+    (vk::DescriptorType::STORAGE_BUFFER, 2.0),
+    (vk::DescriptorType::UNIFORM_BUFFER_DYNAMIC, 1.0),
+    (vk::DescriptorType::STORAGE_BUFFER_DYNAMIC, 1.0),
+    (vk::DescriptorType::INPUT_ATTACHMENT, 0.5),
+];
+
+// allocates descriptor sets of any layout, creating a new pool whenever the current one is exhausted
+pub struct DescriptorAllocator {
+    device: Arc<Device>,
+    sets_per_pool: u32,
+    current_pool: Option<vk::DescriptorPool>,
+    used_pools: Vec<vk::DescriptorPool>,
+    free_pools: Vec<vk::DescriptorPool>,
+}
+
+impl DescriptorAllocator {
+    pub fn new(device: Arc<Device>) -> Self {
+        Self {
+            device,
+            sets_per_pool: INITIAL_SETS_PER_POOL,
+            current_pool: None,
+            used_pools: Vec::new(),
+            free_pools: Vec::new(),
+        }
+    }
+
+    pub unsafe fn destroy(&self) {
+        for &descriptor_pool in self.used_pools.iter().chain(&self.free_pools) {
+            self.device.handle().destroy_descriptor_pool(descriptor_pool, None);
+            log::debug!("dropped descriptor pool {:?}", descriptor_pool);
+        }
+    }
+
+    pub fn allocate(&mut self, descriptor_set_layout: vk::DescriptorSetLayout) -> Result<vk::DescriptorSet> {
+        let descriptor_pool = match self.current_pool {
+            Some(descriptor_pool) => descriptor_pool,
+            None => self.grab_pool()?,
+        };
+
+        match self.allocate_from(descriptor_pool, descriptor_set_layout) {
+            Ok(descriptor_set) => Ok(descriptor_set),
+            // the pool is exhausted, retry once with a fresh one
+            Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY) | Err(vk::Result::ERROR_FRAGMENTED_POOL) => {
+                let descriptor_pool = self.grab_pool()?;
+                Ok(self.allocate_from(descriptor_pool, descriptor_set_layout)?)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // returns all sets allocated from this allocator to their pools,
+    // must only be called when none of them are used by the GPU
+    pub fn reset(&mut self) -> Result<()> {
+        for &descriptor_pool in &self.used_pools {
+            unsafe {
+                self.device
+                    .handle()
+                    .reset_descriptor_pool(descriptor_pool, vk::DescriptorPoolResetFlags::empty())?;
+            }
+        }
+
+        self.free_pools.append(&mut self.used_pools);
+        self.current_pool = None;
+        Ok(())
+    }
+
+    fn allocate_from(
+        &self,
+        descriptor_pool: vk::DescriptorPool,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+    ) -> VkResult<vk::DescriptorSet> {
+        let layouts = [descriptor_set_layout];
+        let descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&layouts);
+
+        unsafe {
+            self.device
+                .handle()
+                .allocate_descriptor_sets(&descriptor_set_allocate_info)
+                .map(|descriptor_sets| descriptor_sets[0])
+        }
+    }
+
+    fn grab_pool(&mut self) -> Result<vk::DescriptorPool> {
+        let descriptor_pool = match self.free_pools.pop() {
+            Some(descriptor_pool) => descriptor_pool,
+            None => {
+                let descriptor_pool = create_pool(&self.device, self.sets_per_pool)?;
+                // each new pool is bigger than the previous one to reduce the number of pools
+                self.sets_per_pool = (self.sets_per_pool * 2).min(MAX_SETS_PER_POOL);
+                descriptor_pool
+            }
+        };
+
+        self.used_pools.push(descriptor_pool);
+        self.current_pool = Some(descriptor_pool);
+        Ok(descriptor_pool)
+    }
+}
+
+fn create_pool(device: &Device, max_sets: u32) -> Result<vk::DescriptorPool> {
+    let pool_sizes = POOL_SIZE_RATIOS
+        .iter()
+        .map(|&(ty, ratio)| vk::DescriptorPoolSize {
+            ty,
+            descriptor_count: ((max_sets as f32 * ratio) as u32).max(1),
+        })
+        .collect::<Vec<_>>();
+
+    let descriptor_pool_create_info = vk::DescriptorPoolCreateInfo::builder()
+        .max_sets(max_sets)
+        .pool_sizes(&pool_sizes);
+
+    let descriptor_pool = unsafe {
+        device
+            .handle()
+            .create_descriptor_pool(&descriptor_pool_create_info, None)?
+    };
+    log::debug!("created descriptor pool {:?} for {} sets", descriptor_pool, max_sets);
+
+    Ok(descriptor_pool)
+}
+
+// binding description without pointers, used to find identical set layouts
+type DescriptorSetLayoutKey = Vec<(
+    u32,
+    vk::DescriptorType,
+    u32,
+    vk::ShaderStageFlags,
+    vk::DescriptorBindingFlags,
+)>;
+
+// shares descriptor set layouts between all pipeline layouts with identical bindings
+pub struct DescriptorLayoutCache {
+    device: Arc<Device>,
+    layouts: Mutex<HashMap<DescriptorSetLayoutKey, vk::DescriptorSetLayout>>,
+}
+
+impl DescriptorLayoutCache {
+    pub fn new(device: Arc<Device>) -> Self {
+        Self {
+            device,
+            layouts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub unsafe fn destroy(&self) {
+        let layouts = self.layouts.lock().unwrap();
+        for &descriptor_set_layout in layouts.values() {
+            self.device
+                .handle()
+                .destroy_descriptor_set_layout(descriptor_set_layout, None);
+            log::debug!("dropped descriptor set layout {:?}", descriptor_set_layout);
+        }
+    }
+
+    // immutable samplers are not supported, binding flags are either empty or specified for each binding
+    pub fn get(
+        &self,
+        bindings: &[vk::DescriptorSetLayoutBinding],
+        binding_flags: &[vk::DescriptorBindingFlags],
+    ) -> Result<vk::DescriptorSetLayout> {
+        let flags_of = |index: usize| {
+            binding_flags
+                .get(index)
+                .copied()
+                .unwrap_or_else(vk::DescriptorBindingFlags::empty)
+        };
+
+        let mut key = bindings
+            .iter()
+            .enumerate()
+            .map(|(index, binding)| {
+                (
+                    binding.binding,
+                    binding.descriptor_type,
+                    binding.descriptor_count,
+                    binding.stage_flags,
+                    flags_of(index),
+                )
+            })
+            .collect::<DescriptorSetLayoutKey>();
+        key.sort_by_key(|&(binding, ..)| binding);
+
+        let mut layouts = self.layouts.lock().unwrap();
+        if let Some(&descriptor_set_layout) = layouts.get(&key) {
+            return Ok(descriptor_set_layout);
+        }
+
+        // sets with update after bind bindings must be allocated from special pools
+        let flags = if binding_flags
+            .iter()
+            .any(|flags| flags.contains(vk::DescriptorBindingFlags::UPDATE_AFTER_BIND))
+        {
+            vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL
+        } else {
+            vk::DescriptorSetLayoutCreateFlags::empty()
+        };
+
+        let mut binding_flags_create_info =
+            vk::DescriptorSetLayoutBindingFlagsCreateInfo::builder().binding_flags(binding_flags);
+
+        let mut descriptor_set_layout_create_info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .flags(flags)
+            .bindings(bindings);
+        if !binding_flags.is_empty() {
+            descriptor_set_layout_create_info =
+                descriptor_set_layout_create_info.push_next(&mut binding_flags_create_info);
+        }
+
+        let descriptor_set_layout = unsafe {
+            self.device
+                .handle()
+                .create_descriptor_set_layout(&descriptor_set_layout_create_info, None)?
+        };
+        log::debug!("created descriptor set layout {:?}", descriptor_set_layout);
+
+        layouts.insert(key, descriptor_set_layout);
+        Ok(descriptor_set_layout)
+    }
+}
+
+// single descriptor set with a variable sized array in its last binding, which can be updated while in use
+pub struct BindlessDescriptorSet {
+    device: Arc<Device>,
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_set: vk::DescriptorSet,
+    capacity: u32,
+}
+
+impl BindlessDescriptorSet {
+    pub fn new(
+        device: Arc<Device>,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+        pool_sizes: &[vk::DescriptorPoolSize],
+        capacity: u32,
+    ) -> Result<Self> {
+        let descriptor_pool_create_info = vk::DescriptorPoolCreateInfo::builder()
+            .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
+            .max_sets(1)
+            .pool_sizes(pool_sizes);
+
+        let descriptor_pool = unsafe {
+            device
+                .handle()
+                .create_descriptor_pool(&descriptor_pool_create_info, None)?
+        };
+        log::debug!("created descriptor pool {:?} for bindless set", descriptor_pool);
+
+        let layouts = [descriptor_set_layout];
+        let counts = [capacity];
+        let mut variable_count_allocate_info =
+            vk::DescriptorSetVariableDescriptorCountAllocateInfo::builder().descriptor_counts(&counts);
+        let descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&layouts)
+            .push_next(&mut variable_count_allocate_info);
+
+        let descriptor_set = match unsafe { device.handle().allocate_descriptor_sets(&descriptor_set_allocate_info) } {
+            Ok(descriptor_sets) => descriptor_sets[0],
+            Err(e) => {
+                unsafe { device.handle().destroy_descriptor_pool(descriptor_pool, None) };
+                return Err(e.into());
+            }
+        };
+
+        Ok(Self {
+            device,
+            descriptor_pool,
+            descriptor_set,
+            capacity,
+        })
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.device.handle().destroy_descriptor_pool(self.descriptor_pool, None);
+        log::debug!("dropped descriptor pool {:?}", self.descriptor_pool);
+    }
+
+    #[inline]
+    pub fn handle(&self) -> vk::DescriptorSet {
+        self.descriptor_set
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> u32 {
+        self.capacity
+    }
+}
+
+pub fn write_buffer(
+    device: &Device,
+    descriptor_set: vk::DescriptorSet,
+    binding: u32,
+    descriptor_type: vk::DescriptorType,
+    buffer: &Buffer,
+) {
+    let descriptor_buffer_info = [vk::DescriptorBufferInfo {
+        buffer: buffer.handle(),
+        offset: 0,
+        range: vk::WHOLE_SIZE,
+    }];
+
+    let descriptor_write_sets = [vk::WriteDescriptorSet::builder()
+        .dst_set(descriptor_set)
+        .dst_binding(binding)
+        .dst_array_element(0)
+        .descriptor_type(descriptor_type)
+        .buffer_info(&descriptor_buffer_info)
+        .build()];
+
+    unsafe {
+        device.handle().update_descriptor_sets(&descriptor_write_sets, &[]);
+    }
+}
+
+pub fn write_storage_buffer(device: &Device, descriptor_set: vk::DescriptorSet, binding: u32, buffer: &Buffer) {
+    write_buffer(
+        device,
+        descriptor_set,
+        binding,
+        vk::DescriptorType::STORAGE_BUFFER,
+        buffer,
+    );
+}
+
+// storage images are expected to be in the GENERAL layout while bound
+pub fn write_storage_image(device: &Device, descriptor_set: vk::DescriptorSet, binding: u32, image_view: &ImageView) {
+    let descriptor_image_info = [vk::DescriptorImageInfo {
+        sampler: vk::Sampler::null(),
+        image_view: image_view.handle(),
+        image_layout: vk::ImageLayout::GENERAL,
+    }];
+
+    let descriptor_write_sets = [vk::WriteDescriptorSet::builder()
+        .dst_set(descriptor_set)
+        .dst_binding(binding)
+        .dst_array_element(0)
+        .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+        .image_info(&descriptor_image_info)
+        .build()];
+
+    unsafe {
+        device.handle().update_descriptor_sets(&descriptor_write_sets, &[]);
+    }
+}
+
+pub fn write_sampler(device: &Device, descriptor_set: vk::DescriptorSet, binding: u32, sampler: vk::Sampler) {
+    let descriptor_image_info = [vk::DescriptorImageInfo {
+        sampler,
+        image_view: vk::ImageView::null(),
+        image_layout: vk::ImageLayout::UNDEFINED,
+    }];
+
+    let descriptor_write_sets = [vk::WriteDescriptorSet::builder()
+        .dst_set(descriptor_set)
+        .dst_binding(binding)
+        .dst_array_element(0)
+        .descriptor_type(vk::DescriptorType::SAMPLER)
+        .image_info(&descriptor_image_info)
+        .build()];
+
+    unsafe {
+        device.handle().update_descriptor_sets(&descriptor_write_sets, &[]);
+    }
+}
+
+// writes consecutive array elements starting from the first one, images must be in the SHADER_READ_ONLY layout
+pub fn write_sampled_images(
+    device: &Device,
+    descriptor_set: vk::DescriptorSet,
+    binding: u32,
+    first_element: u32,
+    image_views: &[vk::ImageView],
+) {
+    if image_views.is_empty() {
+        return;
+    }
+
+    let descriptor_image_infos = image_views
+        .iter()
+        .map(|&image_view| vk::DescriptorImageInfo {
+            sampler: vk::Sampler::null(),
+            image_view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        })
+        .collect::<Vec<_>>();
+
+    let descriptor_write_sets = [vk::WriteDescriptorSet::builder()
+        .dst_set(descriptor_set)
+        .dst_binding(binding)
+        .dst_array_element(first_element)
+        .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+        .image_info(&descriptor_image_infos)
+        .build()];
+
+    unsafe {
+        device.handle().update_descriptor_sets(&descriptor_write_sets, &[]);
+    }
+}
+
+// single image in the specified layout, e.g. GENERAL when it is also written as a storage image
+pub fn write_sampled_image(
+    device: &Device,
+    descriptor_set: vk::DescriptorSet,
+    binding: u32,
+    image_view: &ImageView,
+    image_layout: vk::ImageLayout,
+) {
+    let descriptor_image_info = [vk::DescriptorImageInfo {
+        sampler: vk::Sampler::null(),
+        image_view: image_view.handle(),
+        image_layout,
+    }];
+
+    let descriptor_write_sets = [vk::WriteDescriptorSet::builder()
+        .dst_set(descriptor_set)
+        .dst_binding(binding)
+        .dst_array_element(0)
+        .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+        .image_info(&descriptor_image_info)
+        .build()];
+
+    unsafe {
+        device.handle().update_descriptor_sets(&descriptor_write_sets, &[]);
+    }
+}
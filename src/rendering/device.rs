@@ -1,17 +1,27 @@
 use super::prelude::*;
-use super::{utils, validation, Instance, Surface};
+use super::{utils, validation, DeviceCapabilities, Instance, Surface};
 
 pub struct Device {
     instance: Arc<Instance>,
     device: ash::Device,
     physical_device: vk::PhysicalDevice,
+    properties: vk::PhysicalDeviceProperties,
     memory_properties: vk::PhysicalDeviceMemoryProperties,
+    capabilities: DeviceCapabilities,
+    draw_indirect_count_fn: Option<vk::KhrDrawIndirectCountFn>,
     queues: Queues,
 }
 
 impl Device {
-    pub fn new(instance: Arc<Instance>, surface: &Surface, is_validation_enabled: bool) -> Result<Self> {
-        let (physical_device, queue_indices) = pick_physical_device(instance.handle(), surface)?;
+    pub fn new(
+        instance: Arc<Instance>,
+        surface: &Surface,
+        is_validation_enabled: bool,
+        selector: Option<&DeviceSelector>,
+    ) -> Result<Self> {
+        let (physical_device, queue_indices, capabilities) =
+            pick_physical_device(instance.handle(), surface, selector)?;
+        let properties = unsafe { instance.handle().get_physical_device_properties(physical_device) };
         let memory_properties = unsafe { instance.handle().get_physical_device_memory_properties(physical_device) };
 
         let unique_queue_families = queue_indices.unique_families();
@@ -29,10 +39,13 @@ impl Device {
         }
 
         //
-        let required_extensions = vec![
-            ash::extensions::khr::Swapchain::name().as_ptr(),
-            ash::extensions::nv::RayTracing::name().as_ptr(),
-        ];
+        capabilities.log();
+
+        let enabled_extensions = capabilities
+            .enabled_extensions()
+            .iter()
+            .map(|name| name.as_ptr())
+            .collect::<Vec<_>>();
 
         //
         let required_layers = if is_validation_enabled {
@@ -41,13 +54,19 @@ impl Device {
             &[]
         };
 
-        let required_layers = utils::as_ptr_vec(&required_layers);
+        let required_layers = utils::as_ptr_vec(required_layers);
 
         //
-        let device_create_info = vk::DeviceCreateInfo::builder()
+        let mut descriptor_indexing_features = capabilities.descriptor_indexing_features();
+
+        let mut device_create_info = vk::DeviceCreateInfo::builder()
             .queue_create_infos(&queue_create_infos)
-            .enabled_extension_names(&required_extensions)
-            .enabled_layer_names(&required_layers);
+            .enabled_extension_names(&enabled_extensions)
+            .enabled_layer_names(&required_layers)
+            .enabled_features(&capabilities.features);
+        if capabilities.descriptor_indexing {
+            device_create_info = device_create_info.push_next(&mut descriptor_indexing_features);
+        }
 
         //
         let device = unsafe {
@@ -55,14 +74,31 @@ impl Device {
                 .handle()
                 .create_device(physical_device, &device_create_info, None)?
         };
+        let draw_indirect_count_fn = if capabilities.draw_indirect_count {
+            Some(vk::KhrDrawIndirectCountFn::load(|name| unsafe {
+                std::mem::transmute(instance.handle().get_device_proc_addr(device.handle(), name.as_ptr()))
+            }))
+        } else {
+            None
+        };
+
         let queues = Queues::new(&device, queue_indices)?;
         log::debug!("created logical device");
+        log::debug!(
+            "queue families: graphics {}, present {}, transfer {}",
+            queues.graphics_queue_family,
+            queues.present_queue_family,
+            queues.transfer_queue_family
+        );
 
         Ok(Self {
             instance,
             device,
             physical_device,
+            properties,
             memory_properties,
+            capabilities,
+            draw_indirect_count_fn,
             queues,
         })
     }
@@ -115,11 +151,27 @@ impl Device {
         &self.device
     }
 
+    #[inline]
+    pub fn properties(&self) -> &vk::PhysicalDeviceProperties {
+        &self.properties
+    }
+
     #[inline]
     pub fn memory_properties(&self) -> &vk::PhysicalDeviceMemoryProperties {
         &self.memory_properties
     }
 
+    #[inline]
+    pub fn capabilities(&self) -> &DeviceCapabilities {
+        &self.capabilities
+    }
+
+    // only loaded when the extension is enabled
+    #[inline]
+    pub fn draw_indirect_count_fn(&self) -> Option<&vk::KhrDrawIndirectCountFn> {
+        self.draw_indirect_count_fn.as_ref()
+    }
+
     #[inline]
     pub fn queues(&self) -> &Queues {
         &self.queues
@@ -137,6 +189,7 @@ pub struct SwapchainSupportInfo {
 struct QueueFamilyIndices {
     graphics_family: Option<u32>,
     present_family: Option<u32>,
+    transfer_family: Option<u32>,
 }
 
 impl QueueFamilyIndices {
@@ -148,6 +201,7 @@ impl QueueFamilyIndices {
         let mut result = HashSet::new();
         self.graphics_family.map(|idx| result.insert(idx));
         self.present_family.map(|idx| result.insert(idx));
+        self.transfer_family.map(|idx| result.insert(idx));
         result
     }
 }
@@ -158,6 +212,8 @@ pub struct Queues {
     pub graphics_queue_family: u32,
     pub present_queue: vk::Queue,
     pub present_queue_family: u32,
+    pub transfer_queue: vk::Queue,
+    pub transfer_queue_family: u32,
 }
 
 impl Queues {
@@ -174,60 +230,216 @@ impl Queues {
 
         let present_queue = unsafe { device.get_device_queue(present_queue_family, 0) };
 
+        let transfer_queue_family = indices.transfer_family.unwrap_or(graphics_queue_family);
+
+        let transfer_queue = unsafe { device.get_device_queue(transfer_queue_family, 0) };
+
         Ok(Self {
             graphics_queue_family,
             graphics_queue,
             present_queue_family,
             present_queue,
+            transfer_queue_family,
+            transfer_queue,
         })
     }
+
+    #[inline]
+    pub fn has_dedicated_transfer(&self) -> bool {
+        self.transfer_queue_family != self.graphics_queue_family
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceSelector {
+    Index(usize),
+    Name(String),
+}
+
+impl DeviceSelector {
+    fn matches(&self, index: usize, name: &str) -> bool {
+        match self {
+            DeviceSelector::Index(selected) => *selected == index,
+            DeviceSelector::Name(selected) => name.to_lowercase().contains(&selected.to_lowercase()),
+        }
+    }
+}
+
+impl std::str::FromStr for DeviceSelector {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s.is_empty() {
+            return Err(Error::msg("empty device selector"));
+        }
+
+        Ok(match s.parse::<usize>() {
+            Ok(index) => DeviceSelector::Index(index),
+            Err(_) => DeviceSelector::Name(s.to_owned()),
+        })
+    }
+}
+
+struct PhysicalDeviceCandidate {
+    physical_device: vk::PhysicalDevice,
+    name: String,
+    device_type: vk::PhysicalDeviceType,
+    local_memory: vk::DeviceSize,
+    score: u64,
+    support: Result<(QueueFamilyIndices, DeviceCapabilities), String>,
 }
 
 fn pick_physical_device(
     instance: &ash::Instance,
     surface: &Surface,
-) -> Result<(vk::PhysicalDevice, QueueFamilyIndices)> {
+    selector: Option<&DeviceSelector>,
+) -> Result<(vk::PhysicalDevice, QueueFamilyIndices, DeviceCapabilities)> {
     let physical_devices = unsafe { instance.enumerate_physical_devices()? };
 
-    let mut result = None;
-    for &physical_device in physical_devices.iter() {
-        let indices = check_physical_device(instance, surface, physical_device)?;
+    let mut candidates = physical_devices
+        .iter()
+        .map(|&physical_device| check_physical_device(instance, surface, physical_device))
+        .collect::<Result<Vec<_>>>()?;
+
+    let selected = match selector {
+        // several devices can match a name, the first suitable one is used
+        Some(selector) => {
+            let matches = (0..candidates.len())
+                .filter(|&index| selector.matches(index, &candidates[index].name))
+                .collect::<Vec<_>>();
+            if matches.is_empty() {
+                log_candidates(&candidates, None);
+                return Err(Error::msg(format!("no physical device matches {:?}", selector)));
+            }
+
+            match matches.iter().find(|&&index| candidates[index].support.is_ok()) {
+                Some(&index) => Some(index),
+                None => {
+                    log_candidates(&candidates, None);
+                    let reasons = matches
+                        .iter()
+                        .filter_map(|&index| {
+                            let candidate = &candidates[index];
+                            let reason = candidate.support.as_ref().err()?;
+                            Some(format!("{}: {}", candidate.name, reason))
+                        })
+                        .collect::<Vec<_>>();
+                    return Err(Error::msg(format!(
+                        "no device matching {:?} is suitable ({})",
+                        selector,
+                        reasons.join(", ")
+                    )));
+                }
+            }
+        }
+        None => candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| candidate.support.is_ok())
+            .max_by_key(|(index, candidate)| (candidate.score, std::cmp::Reverse(*index)))
+            .map(|(index, _)| index),
+    };
 
-        if indices.is_complete() && result.is_none() {
-            result = Some((physical_device, indices));
+    log_candidates(&candidates, selected);
+
+    match selected.map(|index| candidates.swap_remove(index)) {
+        Some(PhysicalDeviceCandidate {
+            physical_device,
+            name,
+            support: Ok((queue_indices, capabilities)),
+            ..
+        }) => {
+            log::info!("using device: {}", name);
+            Ok((physical_device, queue_indices, capabilities))
         }
+        _ => Err(Error::msg("no suitable physical device found")),
     }
+}
+
+fn log_candidates(candidates: &[PhysicalDeviceCandidate], selected: Option<usize>) {
+    log::info!(
+        "{:>3}  {:<40} {:<16} {:>10} {:>8}  status",
+        "#",
+        "name",
+        "type",
+        "memory",
+        "score"
+    );
+    for (index, candidate) in candidates.iter().enumerate() {
+        let status = match &candidate.support {
+            _ if selected == Some(index) => "selected".to_owned(),
+            Ok(_) => "suitable".to_owned(),
+            Err(reason) => format!("rejected: {}", reason),
+        };
 
-    match result {
-        Some((device, indices)) => Ok((device, indices)),
-        None => Err(Error::msg("no suitable physical device found")),
+        log::info!(
+            "{:>3}  {:<40} {:<16} {:>6} MiB {:>8}  {}",
+            index,
+            candidate.name,
+            device_type_name(candidate.device_type),
+            candidate.local_memory / (1024 * 1024),
+            candidate.score,
+            status
+        );
+    }
+}
+
+fn device_type_name(device_type: vk::PhysicalDeviceType) -> &'static str {
+    match device_type {
+        vk::PhysicalDeviceType::CPU => "cpu",
+        vk::PhysicalDeviceType::INTEGRATED_GPU => "integrated GPU",
+        vk::PhysicalDeviceType::DISCRETE_GPU => "discrete GPU",
+        vk::PhysicalDeviceType::VIRTUAL_GPU => "virtual GPU",
+        _ => "unknown",
     }
 }
 
+fn score_physical_device(
+    properties: &vk::PhysicalDeviceProperties,
+    memory_properties: &vk::PhysicalDeviceMemoryProperties,
+) -> (u64, vk::DeviceSize) {
+    // device type always dominates, memory size and limits only break ties between devices of the same kind
+    let type_score = match properties.device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+        vk::PhysicalDeviceType::CPU => 1,
+        _ => 0,
+    };
+
+    let local_memory = memory_properties.memory_heaps[..memory_properties.memory_heap_count as usize]
+        .iter()
+        .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+        .map(|heap| heap.size)
+        .sum::<vk::DeviceSize>();
+
+    let limits = &properties.limits;
+    let limits_score = u64::from(limits.max_image_dimension2_d / 1024)
+        + u64::from(limits.max_bound_descriptor_sets)
+        + u64::from(limits.max_push_constants_size / 128);
+
+    let score = type_score * 1_000_000 + local_memory / (1024 * 1024) + limits_score;
+
+    (score, local_memory)
+}
+
 fn check_physical_device(
     instance: &ash::Instance,
     surface: &Surface,
     physical_device: vk::PhysicalDevice,
-) -> Result<QueueFamilyIndices> {
+) -> Result<PhysicalDeviceCandidate> {
     // check device properties
     let device_properties = unsafe { instance.get_physical_device_properties(physical_device) };
+    let memory_properties = unsafe { instance.get_physical_device_memory_properties(physical_device) };
 
     let device_name = utils::from_vk_string(&device_properties.device_name);
 
-    let device_type = match device_properties.device_type {
-        vk::PhysicalDeviceType::CPU => "cpu",
-        vk::PhysicalDeviceType::INTEGRATED_GPU => "integrated GPU",
-        vk::PhysicalDeviceType::DISCRETE_GPU => "discrete GPU",
-        vk::PhysicalDeviceType::VIRTUAL_GPU => "virtual GPU",
-        vk::PhysicalDeviceType::OTHER => "unknown",
-        _ => unreachable!(),
-    };
-
     log::debug!(
         "found device: {}, id: {}, type: {}",
         device_name,
         device_properties.device_id,
-        device_type
+        device_type_name(device_properties.device_type)
     );
 
     let major_version = vk::version_major(device_properties.api_version);
@@ -241,35 +453,45 @@ fn check_physical_device(
         patch_version
     );
 
-    // check device extension support
-    let device_extensions = unsafe { instance.enumerate_device_extension_properties(physical_device)? };
+    let (score, local_memory) = score_physical_device(&device_properties, &memory_properties);
 
-    let mut required_extensions = HashSet::new();
-    required_extensions.insert(ash::extensions::khr::Swapchain::name());
-    required_extensions.insert(ash::extensions::nv::RayTracing::name());
+    let support = check_physical_device_support(instance, surface, physical_device)?;
 
-    for item in device_extensions {
-        let extension_name = utils::from_vk_string_raw(&item.extension_name);
-        required_extensions.remove(extension_name);
-    }
+    Ok(PhysicalDeviceCandidate {
+        physical_device,
+        name: device_name,
+        device_type: device_properties.device_type,
+        local_memory,
+        score,
+        support,
+    })
+}
 
-    if !required_extensions.is_empty() {
-        for item in required_extensions.into_iter() {
-            log::debug!("extension {:?} is not supported by device", item);
-        }
-        return Ok(Default::default());
-    }
+fn check_physical_device_support(
+    instance: &ash::Instance,
+    surface: &Surface,
+    physical_device: vk::PhysicalDevice,
+) -> Result<Result<(QueueFamilyIndices, DeviceCapabilities), String>> {
+    // check device extension support
+    let capabilities = match DeviceCapabilities::query(instance, physical_device)? {
+        Ok(capabilities) => capabilities,
+        Err(reason) => return Ok(Err(reason)),
+    };
 
     // check swapchain support
     let swapchain_support = query_swapchain_support(surface, physical_device)?;
-    if swapchain_support.available_formats.is_empty() || swapchain_support.available_present_modes.is_empty() {
-        return Ok(Default::default());
+    if swapchain_support.available_formats.is_empty() {
+        return Ok(Err("no surface formats".to_owned()));
+    }
+    if swapchain_support.available_present_modes.is_empty() {
+        return Ok(Err("no present modes".to_owned()));
     }
 
     // find supported families
     let mut queue_family_indices = QueueFamilyIndices {
         graphics_family: None,
         present_family: None,
+        transfer_family: None,
     };
 
     let device_queue_families = unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
@@ -298,8 +520,34 @@ fn check_physical_device(
         }
     }
 
+    if queue_family_indices.graphics_family.is_none() {
+        return Ok(Err("no graphics queue".to_owned()));
+    }
+    if queue_family_indices.present_family.is_none() {
+        return Ok(Err("no present queue".to_owned()));
+    }
+
+    // prefer DMA-only families for uploads, then any other family without graphics
+    let transfer_families = device_queue_families
+        .iter()
+        .enumerate()
+        .filter(|(_, queue_family)| {
+            queue_family.queue_count > 0
+                && !queue_family.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                && queue_family
+                    .queue_flags
+                    .intersects(vk::QueueFlags::TRANSFER | vk::QueueFlags::COMPUTE)
+        })
+        .collect::<Vec<_>>();
+
+    queue_family_indices.transfer_family = transfer_families
+        .iter()
+        .find(|(_, queue_family)| !queue_family.queue_flags.contains(vk::QueueFlags::COMPUTE))
+        .or_else(|| transfer_families.first())
+        .map(|&(index, _)| index as u32);
+
     // done
-    Ok(queue_family_indices)
+    Ok(Ok((queue_family_indices, capabilities)))
 }
 
 fn query_swapchain_support(surface: &Surface, physical_device: vk::PhysicalDevice) -> Result<SwapchainSupportInfo> {
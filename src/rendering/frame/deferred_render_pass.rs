@@ -7,7 +7,13 @@ pub struct DeferredRenderPass {
 }
 
 impl DeferredRenderPass {
-    pub fn new(device: Arc<Device>, surface_format: vk::Format, depth_format: vk::Format) -> Result<Self> {
+    // depth is stored only when it is read after the render pass
+    pub fn new(
+        device: Arc<Device>,
+        surface_format: vk::Format,
+        depth_format: vk::Format,
+        store_depth: bool,
+    ) -> Result<Self> {
         // render pass
         let color_attachment = vk::AttachmentDescription::builder()
             .format(surface_format)
@@ -24,7 +30,11 @@ impl DeferredRenderPass {
             .format(depth_format)
             .samples(vk::SampleCountFlags::TYPE_1)
             .load_op(vk::AttachmentLoadOp::CLEAR)
-            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .store_op(if store_depth {
+                vk::AttachmentStoreOp::STORE
+            } else {
+                vk::AttachmentStoreOp::DONT_CARE
+            })
             .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
             .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
             .initial_layout(vk::ImageLayout::UNDEFINED)
@@ -0,0 +1,428 @@
+use std::path::PathBuf;
+
+use crate::rendering::descriptors;
+use crate::rendering::prelude::*;
+use crate::rendering::{
+    ComputePipeline, DescriptorAllocator, DescriptorLayoutCache, Device, Image, ImageView, PipelineCache,
+    PipelineLayout, Sampler, ShaderCompiler,
+};
+
+const DEPTH_PYRAMID_SHADER: &str = "depth_pyramid.comp";
+const PYRAMID_FORMAT: vk::Format = vk::Format::R32_SFLOAT;
+
+const SOURCE_BINDING: u32 = 0;
+const SAMPLER_BINDING: u32 = 1;
+const DESTINATION_BINDING: u32 = 2;
+
+// must match the push constant block of the depth pyramid shader
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct PyramidLevel {
+    source_size: [u32; 2],
+    destination_size: [u32; 2],
+}
+
+unsafe impl bytemuck::Pod for PyramidLevel {}
+unsafe impl bytemuck::Zeroable for PyramidLevel {}
+
+// farthest depth of the previous frame at decreasing resolutions, a texel of level n covers 2^(n+1) pixels.
+// The whole pyramid stays in the GENERAL layout, so levels can be written and sampled without transitions
+pub struct DepthPyramid {
+    device: Arc<Device>,
+    pipeline_layout: PipelineLayout,
+    pipeline: ComputePipeline,
+    sampler: Sampler,
+    descriptor_allocator: DescriptorAllocator,
+    resources: Option<PyramidResources>,
+    is_built: bool,
+}
+
+struct PyramidResources {
+    image: Image,
+    image_view: ImageView,
+    level_views: Vec<ImageView>,
+    level_sizes: Vec<[u32; 2]>,
+    depth_extent: vk::Extent2D,
+    // the first level is built from the depth image of each framebuffer
+    source_descriptor_sets: Vec<vk::DescriptorSet>,
+    level_descriptor_sets: Vec<vk::DescriptorSet>,
+}
+
+impl PyramidResources {
+    unsafe fn destroy(&self) {
+        self.level_views.iter().for_each(|view| view.destroy());
+        self.image_view.destroy();
+        self.image.destroy();
+    }
+}
+
+impl DepthPyramid {
+    pub fn new(
+        device: Arc<Device>,
+        pipeline_cache: &PipelineCache,
+        layout_cache: &DescriptorLayoutCache,
+        shader_compiler: &ShaderCompiler,
+    ) -> Result<Self> {
+        let (pipeline_layout, pipeline) = ComputePipeline::compile(
+            device.clone(),
+            pipeline_cache,
+            layout_cache,
+            shader_compiler,
+            DEPTH_PYRAMID_SHADER,
+        )?;
+
+        let sampler = match Sampler::nearest(device.clone()) {
+            Ok(sampler) => sampler,
+            Err(e) => {
+                unsafe {
+                    pipeline.destroy();
+                    pipeline_layout.destroy();
+                }
+                return Err(e);
+            }
+        };
+
+        Ok(Self {
+            descriptor_allocator: DescriptorAllocator::new(device.clone()),
+            device,
+            pipeline_layout,
+            pipeline,
+            sampler,
+            resources: None,
+            is_built: false,
+        })
+    }
+
+    pub unsafe fn destroy(&self) {
+        if let Some(resources) = &self.resources {
+            resources.destroy();
+        }
+        self.descriptor_allocator.destroy();
+        self.sampler.destroy();
+        self.pipeline.destroy();
+        self.pipeline_layout.destroy();
+    }
+
+    pub fn reload_shaders(
+        &mut self,
+        pipeline_cache: &PipelineCache,
+        shader_compiler: &ShaderCompiler,
+        changed: &[PathBuf],
+    ) -> Result<()> {
+        self.pipeline.reload(
+            pipeline_cache,
+            &self.pipeline_layout,
+            shader_compiler,
+            DEPTH_PYRAMID_SHADER,
+            changed,
+        )
+    }
+
+    // depth images must be sampled with the depth aspect, the device must be idle
+    pub fn recreate(&mut self, depth_views: &[&ImageView], depth_extent: vk::Extent2D) -> Result<()> {
+        if let Some(resources) = self.resources.take() {
+            unsafe { resources.destroy() };
+        }
+        self.descriptor_allocator.reset()?;
+        self.is_built = false;
+
+        // each level halves the previous one, rounding up so that every source texel is covered
+        let mut level_sizes = vec![[
+            depth_extent.width.div_ceil(2).max(1),
+            depth_extent.height.div_ceil(2).max(1),
+        ]];
+        while let Some(&[width, height]) = level_sizes.last() {
+            if width <= 1 && height <= 1 {
+                break;
+            }
+            level_sizes.push([width.div_ceil(2).max(1), height.div_ceil(2).max(1)]);
+        }
+        let level_count = level_sizes.len() as u32;
+
+        let image = Image::new(
+            self.device.clone(),
+            level_sizes[0],
+            level_count,
+            vk::SampleCountFlags::TYPE_1,
+            PYRAMID_FORMAT,
+            vk::ImageTiling::OPTIMAL,
+            vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+        let image_view = ImageView::new(
+            self.device.clone(),
+            &image,
+            PYRAMID_FORMAT,
+            vk::ImageAspectFlags::COLOR,
+            level_count,
+        )?;
+        let level_views = (0..level_count)
+            .map(|level| {
+                ImageView::for_mip_level(
+                    self.device.clone(),
+                    &image,
+                    PYRAMID_FORMAT,
+                    vk::ImageAspectFlags::COLOR,
+                    level,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let descriptor_set_layout = self.pipeline_layout.descriptor_set_layout(0);
+
+        let source_descriptor_sets = depth_views
+            .iter()
+            .map(|depth_view| {
+                let descriptor_set = self.descriptor_allocator.allocate(descriptor_set_layout)?;
+                descriptors::write_sampled_image(
+                    &self.device,
+                    descriptor_set,
+                    SOURCE_BINDING,
+                    depth_view,
+                    vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                );
+                descriptors::write_sampler(&self.device, descriptor_set, SAMPLER_BINDING, self.sampler.handle());
+                descriptors::write_storage_image(&self.device, descriptor_set, DESTINATION_BINDING, &level_views[0]);
+                Ok(descriptor_set)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let level_descriptor_sets = level_views
+            .windows(2)
+            .map(|views| {
+                let descriptor_set = self.descriptor_allocator.allocate(descriptor_set_layout)?;
+                descriptors::write_sampled_image(
+                    &self.device,
+                    descriptor_set,
+                    SOURCE_BINDING,
+                    &views[0],
+                    vk::ImageLayout::GENERAL,
+                );
+                descriptors::write_sampler(&self.device, descriptor_set, SAMPLER_BINDING, self.sampler.handle());
+                descriptors::write_storage_image(&self.device, descriptor_set, DESTINATION_BINDING, &views[1]);
+                Ok(descriptor_set)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        log::debug!(
+            "created depth pyramid {}x{} with {} levels",
+            level_sizes[0][0],
+            level_sizes[0][1],
+            level_count
+        );
+
+        self.resources = Some(PyramidResources {
+            image,
+            image_view,
+            level_views,
+            level_sizes,
+            depth_extent,
+            source_descriptor_sets,
+            level_descriptor_sets,
+        });
+
+        Ok(())
+    }
+
+    // moves the pyramid into its layout before it is sampled for the first time
+    pub fn record_initial_layout(&self, command_buffer: vk::CommandBuffer) {
+        let resources = match &self.resources {
+            Some(resources) => resources,
+            None => return,
+        };
+
+        let barriers = [pyramid_barrier(resources, None)
+            .old_layout(vk::ImageLayout::UNDEFINED)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .build()];
+
+        unsafe {
+            self.device.handle().cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            );
+        }
+    }
+
+    // must be recorded after the render pass which has written the depth image
+    pub fn record_build(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        depth_image: vk::Image,
+        depth_aspect: vk::ImageAspectFlags,
+        framebuffer_index: usize,
+    ) {
+        let resources = match &self.resources {
+            Some(resources) => resources,
+            None => return,
+        };
+        let device = self.device.handle();
+
+        // previous contents are discarded, they are only read by the culling earlier in this frame
+        let barriers = [
+            vk::ImageMemoryBarrier::builder()
+                .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .old_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+                .new_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)
+                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                .image(depth_image)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: depth_aspect,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                })
+                .build(),
+            pyramid_barrier(resources, None)
+                .old_layout(vk::ImageLayout::UNDEFINED)
+                .dst_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .build(),
+        ];
+
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::LATE_FRAGMENT_TESTS | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &barriers,
+            );
+        }
+
+        self.pipeline.bind(command_buffer);
+
+        for (level, &destination_size) in resources.level_sizes.iter().enumerate() {
+            let (descriptor_set, source_size) = match level {
+                0 => (
+                    resources.source_descriptor_sets[framebuffer_index],
+                    [resources.depth_extent.width, resources.depth_extent.height],
+                ),
+                _ => (
+                    resources.level_descriptor_sets[level - 1],
+                    resources.level_sizes[level - 1],
+                ),
+            };
+            let push_constants = PyramidLevel {
+                source_size,
+                destination_size,
+            };
+
+            let barriers = [pyramid_barrier(resources, Some(level as u32))
+                .old_layout(vk::ImageLayout::GENERAL)
+                .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+                .dst_access_mask(vk::AccessFlags::SHADER_READ)
+                .build()];
+
+            unsafe {
+                device.cmd_bind_descriptor_sets(
+                    command_buffer,
+                    vk::PipelineBindPoint::COMPUTE,
+                    self.pipeline_layout.handle(),
+                    0,
+                    &[descriptor_set],
+                    &[],
+                );
+                device.cmd_push_constants(
+                    command_buffer,
+                    self.pipeline_layout.handle(),
+                    vk::ShaderStageFlags::COMPUTE,
+                    0,
+                    bytemuck::bytes_of(&push_constants),
+                );
+            }
+
+            self.pipeline
+                .dispatch_invocations(command_buffer, [destination_size[0], destination_size[1], 1]);
+
+            unsafe {
+                device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::PipelineStageFlags::COMPUTE_SHADER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &barriers,
+                );
+            }
+        }
+
+        // the depth image is written again by the next frame which uses the same framebuffer
+        let memory_barriers = [vk::MemoryBarrier::builder().build()];
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                vk::DependencyFlags::empty(),
+                &memory_barriers,
+                &[],
+                &[],
+            );
+        }
+
+        self.is_built = true;
+    }
+
+    #[inline]
+    pub fn is_built(&self) -> bool {
+        self.is_built
+    }
+
+    #[inline]
+    pub fn image_view(&self) -> Option<&ImageView> {
+        self.resources.as_ref().map(|resources| &resources.image_view)
+    }
+
+    #[inline]
+    pub fn sampler(&self) -> &Sampler {
+        &self.sampler
+    }
+
+    #[inline]
+    pub fn level_count(&self) -> u32 {
+        self.resources
+            .as_ref()
+            .map(|resources| resources.level_sizes.len() as u32)
+            .unwrap_or_default()
+    }
+
+    #[inline]
+    pub fn depth_extent(&self) -> vk::Extent2D {
+        self.resources
+            .as_ref()
+            .map(|resources| resources.depth_extent)
+            .unwrap_or_default()
+    }
+}
+
+// barrier for one level or the whole pyramid, which ends in the GENERAL layout
+fn pyramid_barrier<'a>(resources: &PyramidResources, level: Option<u32>) -> vk::ImageMemoryBarrierBuilder<'a> {
+    let (base_mip_level, level_count) = match level {
+        Some(level) => (level, 1),
+        None => (0, resources.level_sizes.len() as u32),
+    };
+
+    vk::ImageMemoryBarrier::builder()
+        .new_layout(vk::ImageLayout::GENERAL)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(resources.image.handle())
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level,
+            level_count,
+            base_array_layer: 0,
+            layer_count: 1,
+        })
+}
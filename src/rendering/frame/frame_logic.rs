@@ -1,179 +1,189 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
 use super::deferred_render_pass::DeferredRenderPass;
+use super::gpu_culling::GpuCulling;
 use super::graphics_pipeline_layout::GraphicsPipelineLayout;
+use super::indirect_draws::{CullStats, DrawData, DrawListBuilder, DrawUpdate, IndirectDraws};
+use super::material_bindings::{MaterialBindings, MATERIAL_SET};
+use super::mesh_pipelines::{MeshPipelines, PipelineKey};
+use super::per_frame_buffers::PerFrameBuffers;
+use super::FrameOptions;
 use crate::rendering::prelude::*;
-use crate::rendering::{shader, utils};
+use crate::rendering::staging::{StagingAllocation, StagingRing};
+use crate::rendering::utils;
 use crate::rendering::{
-    CommandPool, Device, Framebuffer, Image, ImageView, Mesh, PipelineCache, ShaderModule, Swapchain, Vertex,
+    BlendMode, CommandPool, DescriptorAllocator, DescriptorLayoutCache, Device, Framebuffer, Frustum, GeometryBuffers,
+    Image, ImageView, LodSelector, Material, Mesh, MeshInstance, PipelineCache, ShaderCompiler, ShaderFeatures,
+    Swapchain, Texture, UploadManager, VertexAttribute, VertexBindings, VertexLayout, VertexStorage,
 };
 
+// joint matrices and morph weights of the current frame, scenes without them stage nothing
+#[derive(Debug, Copy, Clone, Default)]
+pub struct AnimationUpdate {
+    joint_matrices: Option<StagingAllocation>,
+    morph_weights: Option<StagingAllocation>,
+}
+
 pub struct FrameLogic {
     device: Arc<Device>,
     command_pool: Arc<CommandPool>,
 
     deferred_render_pass: DeferredRenderPass,
     pipeline_layout: GraphicsPipelineLayout,
-    vertex_shader_module: ShaderModule,
-    fragment_shader_module: ShaderModule,
-    graphics_pipeline: vk::Pipeline,
+    mesh_pipelines: MeshPipelines,
+    material_bindings: MaterialBindings,
+    transient_descriptors: Vec<DescriptorAllocator>,
     command_buffers: Vec<vk::CommandBuffer>,
     framebuffers: Vec<(Framebuffer, Image, ImageView)>,
+    extent: vk::Extent2D,
     depth_format: vk::Format,
 
-    meshes: Vec<(vk::Buffer, vk::Buffer, u64, u32)>,
+    indirect_draws: IndirectDraws,
+    gpu_culling: Option<GpuCulling>,
+    joint_buffers: PerFrameBuffers,
+    morph_weight_buffers: PerFrameBuffers,
+    vertex_bindings: HashMap<VertexLayout, VertexBindings>,
+    index_buffer: vk::Buffer,
 }
 
 impl FrameLogic {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         device: Arc<Device>,
         pipeline_cache: &PipelineCache,
+        layout_cache: &DescriptorLayoutCache,
+        shader_compiler: ShaderCompiler,
         command_pool: Arc<CommandPool>,
         swapchain: &Swapchain,
+        max_frames_in_flight: usize,
+        options: FrameOptions,
     ) -> Result<Self> {
-        let depth_format = device.find_supported_format(
-            &[
-                vk::Format::D32_SFLOAT,
-                vk::Format::D32_SFLOAT_S8_UINT,
-                vk::Format::D24_UNORM_S8_UINT,
-            ],
-            vk::ImageTiling::OPTIMAL,
-            vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
-        )?;
-
-        let deferred_render_pass = DeferredRenderPass::new(device.clone(), swapchain.format(), depth_format)?;
-        let pipeline_layout = GraphicsPipelineLayout::new(device.clone(), swapchain.image_views().len())?;
-        let vertex_shader_module = ShaderModule::from_file(device.clone(), "shaders/spv/mesh.vert.spv")?;
-        let fragment_shader_module = ShaderModule::from_file(device.clone(), "shaders/spv/mesh.frag.spv")?;
-
-        let main_function_name = shader::main_function_name();
-
-        // shader stages
-        let shader_stages = vec![
-            vk::PipelineShaderStageCreateInfo::builder()
-                .module(vertex_shader_module.handle())
-                .name(main_function_name)
-                .stage(vk::ShaderStageFlags::VERTEX)
-                .build(),
-            vk::PipelineShaderStageCreateInfo::builder()
-                .module(fragment_shader_module.handle())
-                .name(main_function_name)
-                .stage(vk::ShaderStageFlags::FRAGMENT)
-                .build(),
+        let depth_formats = [
+            vk::Format::D32_SFLOAT,
+            vk::Format::D32_SFLOAT_S8_UINT,
+            vk::Format::D24_UNORM_S8_UINT,
         ];
 
-        // vertex input state
-        let binding_descriptions = Vertex::get_binding_descriptions();
-        let attribute_descriptions = Vertex::get_attribute_descriptions();
-
-        let vertex_input_state_create_info = vk::PipelineVertexInputStateCreateInfo::builder()
-            .vertex_binding_descriptions(&binding_descriptions)
-            .vertex_attribute_descriptions(&attribute_descriptions);
-
-        let input_assembly_state_create_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
-            .primitive_restart_enable(false)
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
-
-        // viewports
-        let viewports = [vk::Viewport::builder().build()];
-        let scissors = [vk::Rect2D::builder().build()];
-
-        let viewport_state_create_info = vk::PipelineViewportStateCreateInfo::builder()
-            .scissors(&scissors)
-            .viewports(&viewports);
-
-        // rasterization state
-        let rasterization_state_create_info = vk::PipelineRasterizationStateCreateInfo::builder()
-            .cull_mode(vk::CullModeFlags::BACK)
-            .front_face(vk::FrontFace::CLOCKWISE)
-            .line_width(1.0)
-            .polygon_mode(vk::PolygonMode::FILL);
-
-        // multisample state
-        let multisample_state_create_info =
-            vk::PipelineMultisampleStateCreateInfo::builder().rasterization_samples(vk::SampleCountFlags::TYPE_1);
-
-        // depth state
-        let stencil_state = vk::StencilOpState::builder()
-            .fail_op(vk::StencilOp::KEEP)
-            .pass_op(vk::StencilOp::KEEP)
-            .depth_fail_op(vk::StencilOp::KEEP)
-            .compare_op(vk::CompareOp::LESS_OR_EQUAL)
-            .compare_mask(0)
-            .write_mask(0)
-            .reference(0)
-            .build();
-
-        let depth_stencil_state_create_info = vk::PipelineDepthStencilStateCreateInfo::builder()
-            .depth_test_enable(true)
-            .depth_write_enable(true)
-            .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL)
-            .depth_bounds_test_enable(false)
-            .stencil_test_enable(false)
-            .front(stencil_state)
-            .back(stencil_state);
-
-        // color blend state
-        let color_blend_attachment_states = [vk::PipelineColorBlendAttachmentState::builder()
-            .blend_enable(false)
-            .color_write_mask(vk::ColorComponentFlags::all())
-            .src_color_blend_factor(vk::BlendFactor::ONE)
-            .dst_color_blend_factor(vk::BlendFactor::ZERO)
-            .color_blend_op(vk::BlendOp::ADD)
-            .src_alpha_blend_factor(vk::BlendFactor::ONE)
-            .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
-            .alpha_blend_op(vk::BlendOp::ADD)
-            .build()];
-
-        let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
-            .logic_op_enable(false)
-            .logic_op(vk::LogicOp::COPY)
-            .attachments(&color_blend_attachment_states);
-
-        // dynamic state create info
-        let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
-        let dynamic_state_create_info = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dynamic_states);
-
-        // pipeline creation
-        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
-            .stages(&shader_stages)
-            .vertex_input_state(&vertex_input_state_create_info)
-            .input_assembly_state(&input_assembly_state_create_info)
-            .viewport_state(&viewport_state_create_info)
-            .rasterization_state(&rasterization_state_create_info)
-            .multisample_state(&multisample_state_create_info)
-            .depth_stencil_state(&depth_stencil_state_create_info)
-            .color_blend_state(&color_blend_state)
-            .layout(pipeline_layout.handle())
-            .render_pass(deferred_render_pass.handle())
-            .subpass(0)
-            .dynamic_state(&dynamic_state_create_info)
-            .base_pipeline_handle(vk::Pipeline::null())
-            .base_pipeline_index(-1)
-            .build()];
-
-        let graphics_pipelines = unsafe {
+        // the depth pyramid is built by sampling the depth images
+        let sampled_depth_format = if options.gpu_culling && GpuCulling::is_supported(&device) {
             device
-                .handle()
-                .create_graphics_pipelines(pipeline_cache.handle(), &graphics_pipeline_create_infos, None)
-                .map_err(|(_, e)| e)?
+                .find_supported_format(
+                    &depth_formats,
+                    vk::ImageTiling::OPTIMAL,
+                    vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT | vk::FormatFeatureFlags::SAMPLED_IMAGE,
+                )
+                .ok()
+        } else {
+            None
         };
-        let graphics_pipeline = graphics_pipelines[0];
+        let depth_format = match sampled_depth_format {
+            Some(format) => format,
+            None => device.find_supported_format(
+                &depth_formats,
+                vk::ImageTiling::OPTIMAL,
+                vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT,
+            )?,
+        };
+
+        let gpu_culling = match sampled_depth_format {
+            Some(_) => Some(GpuCulling::new(
+                device.clone(),
+                pipeline_cache,
+                layout_cache,
+                &shader_compiler,
+            )?),
+            None => None,
+        };
+        log::info!("gpu culling: {}", gpu_culling.is_some());
+
+        let deferred_render_pass =
+            DeferredRenderPass::new(device.clone(), swapchain.format(), depth_format, gpu_culling.is_some())?;
+
+        // textures are either indexed from one global array or bound per material
+        let global_defines = if options.bindless_textures {
+            vec![("BINDLESS_TEXTURES", "1")]
+        } else {
+            Vec::new()
+        };
+
+        // the layout is reflected from the base permutation, all other permutations must match it,
+        // vertex inputs don't affect it so the base permutation only reads positions
+        let mut mesh_pipelines = MeshPipelines::new(device.clone(), shader_compiler, global_defines);
+        let base_vertex_layout = VertexLayout::new(&[VertexAttribute::Position], VertexStorage::default());
+        let base_shaders = mesh_pipelines.compile_shaders(ShaderFeatures::empty(), base_vertex_layout)?;
+        let pipeline_layout = GraphicsPipelineLayout::new(
+            device.clone(),
+            layout_cache,
+            &base_shaders.modules(),
+            max_frames_in_flight,
+        )?;
+
+        let base_key = PipelineKey {
+            features: ShaderFeatures::empty(),
+            vertex_layout: base_vertex_layout,
+            render_pass: deferred_render_pass.handle(),
+            double_sided: false,
+            blend_mode: BlendMode::Opaque,
+        };
+        mesh_pipelines.insert(pipeline_cache, &pipeline_layout, base_key, base_shaders)?;
+
+        let material_bindings = MaterialBindings::new(device.clone(), &pipeline_layout)?;
+
+        // sets allocated from these live only until the frame is rendered
+        let transient_descriptors = (0..max_frames_in_flight)
+            .map(|_| DescriptorAllocator::new(device.clone()))
+            .collect();
+
+        // command buffers are recorded every frame
+        let command_buffer_create_info = vk::CommandBufferAllocateInfo::builder()
+            .command_pool(command_pool.handle())
+            .command_buffer_count(max_frames_in_flight as u32)
+            .level(vk::CommandBufferLevel::PRIMARY);
+
+        let command_buffers = unsafe { device.handle().allocate_command_buffers(&command_buffer_create_info)? };
+
+        let indirect_draws = IndirectDraws::new(device.clone());
+        let joint_buffers = PerFrameBuffers::new(
+            device.clone(),
+            "joint matrix",
+            std::mem::size_of::<glm::Mat4>(),
+            max_frames_in_flight,
+        )?;
+        let morph_weight_buffers = PerFrameBuffers::new(
+            device.clone(),
+            "morph weight",
+            std::mem::size_of::<f32>(),
+            max_frames_in_flight,
+        )?;
+        pipeline_layout
+            .uniform_buffers()
+            .write_joint_matrices(joint_buffers.buffers());
+        pipeline_layout
+            .uniform_buffers()
+            .write_morph_weights(morph_weight_buffers.buffers());
 
         let mut result = Self {
             device,
             command_pool,
             deferred_render_pass,
             pipeline_layout,
-            vertex_shader_module,
-            fragment_shader_module,
-            graphics_pipeline,
-            command_buffers: Vec::new(),
+            mesh_pipelines,
+            material_bindings,
+            transient_descriptors,
+            command_buffers,
             framebuffers: Vec::new(),
+            extent: swapchain.extent(),
             depth_format,
-            meshes: Vec::new(),
+            indirect_draws,
+            gpu_culling,
+            joint_buffers,
+            morph_weight_buffers,
+            vertex_bindings: HashMap::new(),
+            index_buffer: vk::Buffer::null(),
         };
 
         result.recreate_frame_buffers(swapchain)?;
-        result.recreate_command_buffers(swapchain)?;
 
         Ok(result)
     }
@@ -198,27 +208,178 @@ impl FrameLogic {
         self.free_command_buffers();
         self.destroy_framebuffers();
 
-        self.device.handle().destroy_pipeline(self.graphics_pipeline, None);
-        log::debug!("dropped pipeline {:?}", self.graphics_pipeline);
+        self.mesh_pipelines.destroy();
+        self.material_bindings.destroy();
+        self.indirect_draws.destroy();
+        self.joint_buffers.destroy();
+        self.morph_weight_buffers.destroy();
+        if let Some(gpu_culling) = &self.gpu_culling {
+            gpu_culling.destroy();
+        }
+        self.transient_descriptors
+            .iter()
+            .for_each(|allocator| allocator.destroy());
 
         self.deferred_render_pass.destroy();
         self.pipeline_layout.destroy();
-        self.vertex_shader_module.destroy();
-        self.fragment_shader_module.destroy();
     }
 
-    pub fn update_meshes(&mut self, meshes: &[Mesh]) {
-        self.meshes = meshes
+    // must be called after the previous submission of this frame has finished
+    pub fn begin_frame(&mut self, current_frame: usize) -> Result<()> {
+        self.transient_descriptors[current_frame].reset()
+    }
+
+    pub fn reload_shaders(&mut self, pipeline_cache: &PipelineCache, changed: &[PathBuf]) -> Result<()> {
+        self.mesh_pipelines
+            .reload(pipeline_cache, &self.pipeline_layout, changed)?;
+        if let Some(gpu_culling) = &mut self.gpu_culling {
+            gpu_culling.reload_shaders(pipeline_cache, self.mesh_pipelines.shader_compiler(), changed)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn update_materials(
+        &mut self,
+        uploader: &mut UploadManager,
+        materials: &[Material],
+        textures: &[Texture],
+    ) -> Result<()> {
+        self.material_bindings.update(uploader, materials, textures)
+    }
+
+    // materials must be updated first, draws are batched by their descriptor sets
+    pub fn update_meshes(
+        &mut self,
+        pipeline_cache: &PipelineCache,
+        uploader: &mut UploadManager,
+        geometry: &GeometryBuffers,
+        meshes: &[Mesh],
+        materials: &[Material],
+        instances: &[MeshInstance],
+    ) -> Result<()> {
+        let render_pass = self.deferred_render_pass.handle();
+
+        let mut draws = instances
             .iter()
-            .map(|mesh| {
-                (
-                    mesh.vertex_buffer().handle(),
-                    mesh.index_buffer().handle(),
-                    0,
-                    mesh.index_count(),
-                )
+            .enumerate()
+            .map(|(object_id, instance)| {
+                let mesh = &meshes[instance.mesh];
+                let material = &materials[mesh.material()];
+
+                // meshes with joints are drawn rigidly by instances without a skin
+                let mut features = material.features() | mesh.features();
+                if instance.joints.is_none() {
+                    features.set(ShaderFeatures::SKINNED, false);
+                }
+                // normal maps are given in tangent space, meshes without a tangent frame ignore them
+                let vertex_layout = mesh.vertex_layout();
+                if !vertex_layout.contains(VertexAttribute::Normal) || !vertex_layout.contains(VertexAttribute::Tangent)
+                {
+                    features.set(ShaderFeatures::NORMAL_MAP, false);
+                }
+
+                let key = PipelineKey {
+                    features,
+                    vertex_layout,
+                    render_pass,
+                    double_sided: material.double_sided,
+                    blend_mode: material.blend_mode(),
+                };
+                self.mesh_pipelines
+                    .get_or_create(pipeline_cache, &self.pipeline_layout, key)?;
+
+                Ok((key, object_id, instance))
             })
-            .collect();
+            .collect::<Result<Vec<_>>>()?;
+
+        // blended draws go over all opaque ones, they aren't sorted by depth.
+        // Otherwise group draws by vertex buffer, pipeline and material to minimize state changes,
+        // and by mesh to draw them instanced
+        draws.sort_by_key(|(key, _, instance)| {
+            (
+                key.blend_mode != BlendMode::Opaque,
+                key.vertex_layout,
+                key.features.bits(),
+                meshes[instance.mesh].material(),
+                instance.mesh,
+            )
+        });
+
+        let mut builder = DrawListBuilder::new();
+        for (key, object_id, instance) in draws {
+            let mesh = &meshes[instance.mesh];
+
+            let mut model = [0.0; 16];
+            model.copy_from_slice(instance.transform.as_slice());
+
+            builder.push(
+                key,
+                self.material_bindings.descriptor_set(mesh.material()),
+                mesh,
+                instance,
+                DrawData {
+                    model,
+                    material_index: mesh.material() as u32,
+                    object_id: object_id as u32,
+                    joint_offset: instance.joints.map_or(0, |joints| joints.offset),
+                    morph_weight_offset: instance.morph_weight_offset.unwrap_or(0),
+                    morph_target_count: mesh.morph_target_count(),
+                    // the vertex index of the shader already includes the vertex offset
+                    morph_delta_offset: mesh.morph_delta_offset() as i32
+                        - mesh.vertex_offset() * mesh.morph_target_count() as i32,
+                    padding: [0; 2],
+                },
+            );
+        }
+
+        self.indirect_draws.update(uploader, builder)?;
+        if let Some(draw_data_buffer) = self.indirect_draws.draw_data_buffer() {
+            self.pipeline_layout.uniform_buffers().write_draw_data(draw_data_buffer);
+        }
+        if let Some(gpu_culling) = &mut self.gpu_culling {
+            gpu_culling.update(&self.indirect_draws)?;
+        }
+
+        // the draws were replaced after waiting for the device, so no frame uses the joint buffers
+        let joint_count = instances
+            .iter()
+            .filter_map(|instance| instance.joints)
+            .map(|joints| joints.end() as usize)
+            .max()
+            .unwrap_or(0);
+        if self.joint_buffers.resize(joint_count)? {
+            self.pipeline_layout
+                .uniform_buffers()
+                .write_joint_matrices(self.joint_buffers.buffers());
+        }
+
+        let morph_weight_count = instances
+            .iter()
+            .filter_map(|instance| {
+                let offset = instance.morph_weight_offset?;
+                Some((offset + meshes[instance.mesh].morph_target_count()) as usize)
+            })
+            .max()
+            .unwrap_or(0);
+        if self.morph_weight_buffers.resize(morph_weight_count)? {
+            self.pipeline_layout
+                .uniform_buffers()
+                .write_morph_weights(self.morph_weight_buffers.buffers());
+        }
+        self.pipeline_layout
+            .uniform_buffers()
+            .write_morph_deltas(geometry.morph_delta_buffer());
+
+        self.vertex_bindings = geometry.vertex_bindings();
+        self.index_buffer = geometry.index_buffer().handle();
+
+        Ok(())
+    }
+
+    // instances must be the ones the meshes were updated with, only their transforms may change
+    pub fn update_instances(&mut self, instances: &[MeshInstance]) {
+        self.indirect_draws.update_instances(instances);
     }
 
     pub fn recreate_frame_buffers(&mut self, swapchain: &Swapchain) -> Result<()> {
@@ -228,6 +389,11 @@ impl FrameLogic {
         };
 
         // create framebuffers
+        self.extent = swapchain.extent();
+        let depth_usage = match self.gpu_culling {
+            Some(_) => vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED,
+            None => vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+        };
         self.framebuffers = swapchain
             .image_views()
             .iter()
@@ -241,7 +407,7 @@ impl FrameLogic {
                     vk::SampleCountFlags::TYPE_1,
                     self.depth_format,
                     vk::ImageTiling::OPTIMAL,
-                    vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                    depth_usage,
                     vk::MemoryPropertyFlags::DEVICE_LOCAL,
                 )?;
 
@@ -264,101 +430,226 @@ impl FrameLogic {
             })
             .collect::<Result<_>>()?;
 
+        if let Some(gpu_culling) = &mut self.gpu_culling {
+            let depth_views = self.framebuffers.iter().map(|(_, _, view)| view).collect::<Vec<_>>();
+            gpu_culling.recreate_depth_pyramid(&depth_views, self.extent)?;
+        }
+
         // done
         Ok(())
     }
 
-    pub fn recreate_command_buffers(&mut self, swapchain: &Swapchain) -> Result<()> {
-        // free command buffers
-        unsafe { self.free_command_buffers() };
+    // joint matrices and morph weights of this frame
+    pub fn stage_animation_data(
+        &self,
+        staging: &mut StagingRing,
+        joint_matrices: &[glm::Mat4],
+        morph_weights: &[f32],
+    ) -> Result<AnimationUpdate> {
+        let joint_data = joint_matrices
+            .iter()
+            .flat_map(|matrix| matrix.as_slice().iter().copied())
+            .collect::<Vec<f32>>();
 
-        let extent = swapchain.extent();
+        Ok(AnimationUpdate {
+            joint_matrices: self.joint_buffers.stage(staging, &joint_data)?,
+            morph_weights: self.morph_weight_buffers.stage(staging, morph_weights)?,
+        })
+    }
 
-        // create command buffers
-        let device = self.device.handle();
+    // builds the commands of this frame from the visible instances, gpu culling tests them again.
+    // Bounds of skinned instances follow their joints
+    pub fn prepare_draws(
+        &mut self,
+        staging: &mut StagingRing,
+        view: &glm::Mat4,
+        projection: &glm::Mat4,
+        joint_matrices: &[glm::Mat4],
+    ) -> Result<(CullStats, Option<DrawUpdate>)> {
+        let frustum = Frustum::from_view_projection(&(projection * view));
+        let lod_selector = LodSelector::new(view, projection);
+
+        let stats = self.indirect_draws.prepare(&frustum, &lod_selector, joint_matrices);
+        let update = self.indirect_draws.stage(staging)?;
+        Ok((stats, update))
+    }
 
-        let command_buffer_create_info = vk::CommandBufferAllocateInfo::builder()
-            .command_pool(self.command_pool.handle())
-            .command_buffer_count(swapchain.image_count())
-            .level(vk::CommandBufferLevel::PRIMARY);
+    // culling data is staged only when the draws are culled on the gpu
+    pub fn stage_cull_data(
+        &mut self,
+        staging: &mut StagingRing,
+        view: &glm::Mat4,
+        projection: &glm::Mat4,
+    ) -> Result<Option<StagingAllocation>> {
+        match &mut self.gpu_culling {
+            Some(gpu_culling) => gpu_culling.stage(staging, view, projection).map(Some),
+            None => Ok(None),
+        }
+    }
 
-        self.command_buffers = unsafe { device.allocate_command_buffers(&command_buffer_create_info)? };
+    pub fn record_command_buffer(
+        &mut self,
+        current_frame: usize,
+        image_index: usize,
+        world_data: &StagingAllocation,
+        animation_update: &AnimationUpdate,
+        draw_update: Option<&DrawUpdate>,
+        cull_data: Option<&StagingAllocation>,
+    ) -> Result<vk::CommandBuffer> {
+        let device = self.device.handle();
+        let command_buffer = self.command_buffers[current_frame];
+        let extent = self.extent;
 
         let viewports = [utils::viewport_flipped(extent, 0.0, 1.0)];
         let scissors = [utils::rect_2d([0, 0], extent)];
 
-        for (i, &command_buffer) in self.command_buffers.iter().enumerate() {
+        unsafe {
+            device.reset_command_buffer(command_buffer, vk::CommandBufferResetFlags::empty())?;
+
             let command_buffer_begin_info =
-                vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::SIMULTANEOUS_USE);
+                vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            device.begin_command_buffer(command_buffer, &command_buffer_begin_info)?;
+        }
 
-            unsafe { device.begin_command_buffer(command_buffer, &command_buffer_begin_info)? }
+        self.pipeline_layout
+            .uniform_buffers()
+            .record_world_data_update(command_buffer, current_frame, world_data);
+        if let Some(joint_matrices) = &animation_update.joint_matrices {
+            self.joint_buffers
+                .record_update(command_buffer, current_frame, joint_matrices);
+        }
+        if let Some(morph_weights) = &animation_update.morph_weights {
+            self.morph_weight_buffers
+                .record_update(command_buffer, current_frame, morph_weights);
+        }
+        if let Some(draw_update) = draw_update {
+            self.indirect_draws.record_update(command_buffer, draw_update);
+        }
 
-            let clear_values = [
-                vk::ClearValue {
-                    color: vk::ClearColorValue {
-                        float32: [0.0, 0.0, 0.0, 1.0],
-                    },
-                },
-                vk::ClearValue {
-                    depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 },
-                },
-            ];
+        let gpu_culling = match (&self.gpu_culling, cull_data) {
+            (Some(gpu_culling), Some(cull_data)) => {
+                gpu_culling.record_cull(
+                    command_buffer,
+                    &mut self.transient_descriptors[current_frame],
+                    &self.indirect_draws,
+                    cull_data,
+                )?;
+                Some(gpu_culling)
+            }
+            _ => None,
+        };
 
-            let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
-                .render_pass(self.deferred_render_pass.handle())
-                .framebuffer(self.framebuffers[i].0.handle())
-                .render_area(vk::Rect2D {
-                    offset: vk::Offset2D { x: 0, y: 0 },
-                    extent,
-                })
-                .clear_values(&clear_values);
+        let clear_values = [
+            vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: [0.0, 0.0, 0.0, 1.0],
+                },
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 },
+            },
+        ];
 
-            unsafe {
-                device.cmd_begin_render_pass(command_buffer, &render_pass_begin_info, vk::SubpassContents::INLINE);
-                device.cmd_set_viewport(command_buffer, 0, &viewports);
-                device.cmd_set_scissor(command_buffer, 0, &scissors);
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.deferred_render_pass.handle())
+            .framebuffer(self.framebuffers[image_index].0.handle())
+            .render_area(vk::Rect2D {
+                offset: vk::Offset2D { x: 0, y: 0 },
+                extent,
+            })
+            .clear_values(&clear_values);
 
-                device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.graphics_pipeline);
+        unsafe {
+            device.cmd_begin_render_pass(command_buffer, &render_pass_begin_info, vk::SubpassContents::INLINE);
+            device.cmd_set_viewport(command_buffer, 0, &viewports);
+            device.cmd_set_scissor(command_buffer, 0, &scissors);
+
+            let descriptor_sets = [self.pipeline_layout.uniform_buffers().descriptor_set(current_frame)];
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline_layout.handle(),
+                0,
+                &descriptor_sets,
+                &[],
+            );
+
+            if !self.indirect_draws.batches().is_empty() {
+                device.cmd_bind_index_buffer(command_buffer, self.index_buffer, 0, GeometryBuffers::INDEX_TYPE);
+            }
 
-                for &(vertex_buffer, index_buffer, offset, index_count) in &self.meshes {
-                    let vertex_buffers = [vertex_buffer];
-                    let offsets = [offset];
-                    let descriptor_sets = [self.pipeline_layout.uniform_buffers().descriptor_set(i)];
+            let mut bound_vertex_layout = None;
+            let mut bound_pipeline = None;
+            let mut bound_material_set = None;
+            for (batch_index, batch) in self.indirect_draws.batches().iter().enumerate() {
+                let (pipeline, vertex_bindings) = match (
+                    self.mesh_pipelines.get(&batch.key),
+                    self.vertex_bindings.get(&batch.key.vertex_layout),
+                ) {
+                    (Some(pipeline), Some(vertex_bindings)) => (pipeline, vertex_bindings),
+                    _ => continue,
+                };
+
+                // batches are sorted by layout, so each vertex buffer is bound at most once per blend mode
+                if bound_vertex_layout != Some(batch.key.vertex_layout) {
+                    device.cmd_bind_vertex_buffers(
+                        command_buffer,
+                        0,
+                        &vertex_bindings.buffers,
+                        &vertex_bindings.offsets,
+                    );
+                    bound_vertex_layout = Some(batch.key.vertex_layout);
+                }
+                if bound_pipeline != Some(pipeline) {
+                    device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline);
+                    bound_pipeline = Some(pipeline);
+                }
 
-                    device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
-                    device.cmd_bind_index_buffer(command_buffer, index_buffer, 0, vk::IndexType::UINT16);
+                // bindless textures use the same set for all materials, so it is bound only once
+                if bound_material_set != Some(batch.material_set) {
                     device.cmd_bind_descriptor_sets(
                         command_buffer,
                         vk::PipelineBindPoint::GRAPHICS,
                         self.pipeline_layout.handle(),
-                        0,
-                        &descriptor_sets,
+                        MATERIAL_SET as u32,
+                        &[batch.material_set],
                         &[],
                     );
-                    device.cmd_draw_indexed(command_buffer, index_count, 1, 0, 0, 0);
+                    bound_material_set = Some(batch.material_set);
                 }
 
-                device.cmd_end_render_pass(command_buffer);
-                device.end_command_buffer(command_buffer)?;
+                match gpu_culling {
+                    Some(gpu_culling) => gpu_culling.record_batch(command_buffer, batch_index, batch),
+                    None => self.indirect_draws.record_batch(command_buffer, batch),
+                }
             }
+
+            device.cmd_end_render_pass(command_buffer);
         }
 
-        Ok(())
-    }
+        // the next frame tests its draws against the depth of this one
+        if let (Some(gpu_culling), Some(_)) = (&mut self.gpu_culling, cull_data) {
+            let depth_aspect = match self.depth_format {
+                vk::Format::D32_SFLOAT_S8_UINT | vk::Format::D24_UNORM_S8_UINT => {
+                    vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+                }
+                _ => vk::ImageAspectFlags::DEPTH,
+            };
+            gpu_culling.record_depth_pyramid(
+                command_buffer,
+                self.framebuffers[image_index].1.handle(),
+                depth_aspect,
+                image_index,
+            );
+        }
 
-    #[inline]
-    pub fn command_buffer(&self, image_index: usize) -> vk::CommandBuffer {
-        self.command_buffers[image_index]
+        unsafe { device.end_command_buffer(command_buffer)? };
+
+        Ok(command_buffer)
     }
 
-    #[allow(unused)]
     #[inline]
     pub fn pipeline_layout(&self) -> &GraphicsPipelineLayout {
         &self.pipeline_layout
     }
-
-    #[inline]
-    pub fn pipeline_layout_mut(&mut self) -> &mut GraphicsPipelineLayout {
-        &mut self.pipeline_layout
-    }
 }
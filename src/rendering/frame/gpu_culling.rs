@@ -0,0 +1,406 @@
+use std::path::PathBuf;
+
+use super::depth_pyramid::DepthPyramid;
+use super::indirect_draws::{DrawBatch, IndirectDraws, COMMAND_STRIDE};
+use crate::rendering::descriptors;
+use crate::rendering::prelude::*;
+use crate::rendering::staging::{StagingAllocation, StagingRing};
+use crate::rendering::{
+    Buffer, ComputePipeline, DescriptorAllocator, DescriptorLayoutCache, Device, Frustum, ImageView, PipelineCache,
+    PipelineLayout, ShaderCompiler,
+};
+
+const CULL_SHADER: &str = "cull.comp";
+
+const CULL_DATA_BINDING: u32 = 0;
+const BOUNDS_BINDING: u32 = 1;
+const INPUT_COMMANDS_BINDING: u32 = 2;
+const OUTPUT_COMMANDS_BINDING: u32 = 3;
+const BATCH_OFFSETS_BINDING: u32 = 4;
+const DRAW_COUNTS_BINDING: u32 = 5;
+const DEPTH_PYRAMID_BINDING: u32 = 6;
+const PYRAMID_SAMPLER_BINDING: u32 = 7;
+
+// must match the cull data uniform block of the culling shader
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+struct CullData {
+    previous_view_projection: [f32; 16],
+    frustum_planes: [[f32; 4]; 6],
+    depth_size: [f32; 2],
+    draw_count: u32,
+    occlusion_enabled: u32,
+    pyramid_levels: u32,
+    padding: [u32; 3],
+}
+
+unsafe impl bytemuck::Pod for CullData {}
+unsafe impl bytemuck::Zeroable for CullData {}
+
+// writes the visible commands of each batch into a compacted range and counts them, the draws read the count
+// from the buffer. Occlusion is tested against the depth pyramid of the previous frame
+pub struct GpuCulling {
+    device: Arc<Device>,
+    pipeline_layout: PipelineLayout,
+    pipeline: ComputePipeline,
+    cull_data_buffer: Buffer,
+    output_commands: Option<Buffer>,
+    draw_counts: Option<Buffer>,
+    draw_count: u32,
+    previous_view_projection: Option<glm::Mat4>,
+    depth_pyramid: DepthPyramid,
+}
+
+impl GpuCulling {
+    // indirect count draws are an extension, batches must also be drawable with a single call
+    pub fn is_supported(device: &Device) -> bool {
+        let features = &device.capabilities().features;
+        device.capabilities().draw_indirect_count
+            && features.multi_draw_indirect == vk::TRUE
+            && features.draw_indirect_first_instance == vk::TRUE
+    }
+
+    pub fn new(
+        device: Arc<Device>,
+        pipeline_cache: &PipelineCache,
+        layout_cache: &DescriptorLayoutCache,
+        shader_compiler: &ShaderCompiler,
+    ) -> Result<Self> {
+        let depth_pyramid = DepthPyramid::new(device.clone(), pipeline_cache, layout_cache, shader_compiler)?;
+
+        let (pipeline_layout, pipeline) = match ComputePipeline::compile(
+            device.clone(),
+            pipeline_cache,
+            layout_cache,
+            shader_compiler,
+            CULL_SHADER,
+        ) {
+            Ok(result) => result,
+            Err(e) => {
+                unsafe { depth_pyramid.destroy() };
+                return Err(e);
+            }
+        };
+
+        let cull_data_buffer = match Buffer::new(
+            device.clone(),
+            std::mem::size_of::<CullData>() as vk::DeviceSize,
+            vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::UNIFORM_BUFFER,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                unsafe {
+                    pipeline.destroy();
+                    pipeline_layout.destroy();
+                    depth_pyramid.destroy();
+                }
+                return Err(e);
+            }
+        };
+
+        log::debug!("created gpu culling");
+
+        Ok(Self {
+            device,
+            pipeline_layout,
+            pipeline,
+            cull_data_buffer,
+            output_commands: None,
+            draw_counts: None,
+            draw_count: 0,
+            previous_view_projection: None,
+            depth_pyramid,
+        })
+    }
+
+    pub unsafe fn destroy(&self) {
+        if let Some(output_commands) = &self.output_commands {
+            output_commands.destroy();
+        }
+        if let Some(draw_counts) = &self.draw_counts {
+            draw_counts.destroy();
+        }
+        self.cull_data_buffer.destroy();
+        self.depth_pyramid.destroy();
+        self.pipeline.destroy();
+        self.pipeline_layout.destroy();
+    }
+
+    // descriptor sets stay compatible, because the pipelines keep their layouts
+    pub fn reload_shaders(
+        &mut self,
+        pipeline_cache: &PipelineCache,
+        shader_compiler: &ShaderCompiler,
+        changed: &[PathBuf],
+    ) -> Result<()> {
+        self.depth_pyramid
+            .reload_shaders(pipeline_cache, shader_compiler, changed)?;
+        self.pipeline.reload(
+            pipeline_cache,
+            &self.pipeline_layout,
+            shader_compiler,
+            CULL_SHADER,
+            changed,
+        )
+    }
+
+    // must be called after the draws are updated, while no frame is in flight
+    pub fn update(&mut self, indirect_draws: &IndirectDraws) -> Result<()> {
+        if let Some(output_commands) = self.output_commands.take() {
+            unsafe { output_commands.destroy() };
+        }
+        if let Some(draw_counts) = self.draw_counts.take() {
+            unsafe { draw_counts.destroy() };
+        }
+        self.draw_count = 0;
+
+        let input_commands = match indirect_draws.command_buffer() {
+            Some(input_commands) => input_commands,
+            None => return Ok(()),
+        };
+
+        let output_commands = Buffer::new(
+            self.device.clone(),
+            input_commands.size(),
+            vk::BufferUsageFlags::INDIRECT_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+        let draw_counts = match Buffer::new(
+            self.device.clone(),
+            (indirect_draws.batches().len().max(1) * std::mem::size_of::<u32>()) as vk::DeviceSize,
+            vk::BufferUsageFlags::INDIRECT_BUFFER
+                | vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_DST,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) {
+            Ok(draw_counts) => draw_counts,
+            Err(e) => {
+                unsafe { output_commands.destroy() };
+                return Err(e);
+            }
+        };
+
+        self.output_commands = Some(output_commands);
+        self.draw_counts = Some(draw_counts);
+        self.draw_count = indirect_draws.command_count();
+
+        Ok(())
+    }
+
+    // the pyramid follows the size of the depth images, the device must be idle
+    pub fn recreate_depth_pyramid(&mut self, depth_views: &[&ImageView], depth_extent: vk::Extent2D) -> Result<()> {
+        self.depth_pyramid.recreate(depth_views, depth_extent)?;
+
+        // the old pyramid doesn't match the new projection
+        self.previous_view_projection = None;
+
+        Ok(())
+    }
+
+    pub fn stage(
+        &mut self,
+        staging: &mut StagingRing,
+        view: &glm::Mat4,
+        projection: &glm::Mat4,
+    ) -> Result<StagingAllocation> {
+        let view_projection = projection * view;
+        let occlusion_view_projection = match self.previous_view_projection {
+            Some(previous) if self.depth_pyramid.is_built() => Some(previous),
+            _ => None,
+        };
+
+        let mut previous_view_projection = [0.0; 16];
+        previous_view_projection.copy_from_slice(occlusion_view_projection.unwrap_or(view_projection).as_slice());
+        let depth_extent = self.depth_pyramid.depth_extent();
+
+        let cull_data = CullData {
+            previous_view_projection,
+            frustum_planes: Frustum::from_view_projection(&view_projection).planes_data(),
+            depth_size: [depth_extent.width as f32, depth_extent.height as f32],
+            draw_count: self.draw_count,
+            occlusion_enabled: occlusion_view_projection.is_some() as u32,
+            pyramid_levels: self.depth_pyramid.level_count(),
+            padding: [0; 3],
+        };
+
+        // the pyramid built in this frame is tested in the next one
+        self.previous_view_projection = Some(view_projection);
+
+        let alignment = self.device.properties().limits.optimal_buffer_copy_offset_alignment;
+        staging.write(bytemuck::bytes_of(&cull_data), alignment)
+    }
+
+    // must be recorded outside of a render pass, before the draws. The set is written every frame,
+    // so it is allocated from the transient allocator of the frame
+    pub fn record_cull(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        descriptor_allocator: &mut DescriptorAllocator,
+        indirect_draws: &IndirectDraws,
+        cull_data: &StagingAllocation,
+    ) -> Result<()> {
+        let (output_commands, draw_counts, depth_pyramid) = match (
+            &self.output_commands,
+            &self.draw_counts,
+            self.depth_pyramid.image_view(),
+        ) {
+            (Some(output_commands), Some(draw_counts), Some(depth_pyramid)) => {
+                (output_commands, draw_counts, depth_pyramid)
+            }
+            _ => return Ok(()),
+        };
+        let (bounds, input_commands, batch_offsets) = match (
+            indirect_draws.bounds_buffer(),
+            indirect_draws.command_buffer(),
+            indirect_draws.batch_offsets_buffer(),
+        ) {
+            (Some(bounds), Some(input_commands), Some(batch_offsets)) => (bounds, input_commands, batch_offsets),
+            _ => return Ok(()),
+        };
+        let device = self.device.handle();
+
+        let descriptor_set = descriptor_allocator.allocate(self.pipeline_layout.descriptor_set_layout(0))?;
+        descriptors::write_buffer(
+            &self.device,
+            descriptor_set,
+            CULL_DATA_BINDING,
+            vk::DescriptorType::UNIFORM_BUFFER,
+            &self.cull_data_buffer,
+        );
+        descriptors::write_storage_buffer(&self.device, descriptor_set, BOUNDS_BINDING, bounds);
+        descriptors::write_storage_buffer(&self.device, descriptor_set, INPUT_COMMANDS_BINDING, input_commands);
+        descriptors::write_storage_buffer(&self.device, descriptor_set, OUTPUT_COMMANDS_BINDING, output_commands);
+        descriptors::write_storage_buffer(&self.device, descriptor_set, BATCH_OFFSETS_BINDING, batch_offsets);
+        descriptors::write_storage_buffer(&self.device, descriptor_set, DRAW_COUNTS_BINDING, draw_counts);
+        descriptors::write_sampled_image(
+            &self.device,
+            descriptor_set,
+            DEPTH_PYRAMID_BINDING,
+            depth_pyramid,
+            vk::ImageLayout::GENERAL,
+        );
+        descriptors::write_sampler(
+            &self.device,
+            descriptor_set,
+            PYRAMID_SAMPLER_BINDING,
+            self.depth_pyramid.sampler().handle(),
+        );
+
+        if !self.depth_pyramid.is_built() {
+            self.depth_pyramid.record_initial_layout(command_buffer);
+        }
+
+        // buffers are still read by the previous frame
+        let copy_regions = [vk::BufferCopy {
+            src_offset: cull_data.offset,
+            dst_offset: 0,
+            size: cull_data.size,
+        }];
+        let transfer_barriers = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(
+                vk::AccessFlags::UNIFORM_READ | vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            )
+            .build()];
+        let cull_barriers = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::SHADER_WRITE)
+            .dst_access_mask(vk::AccessFlags::INDIRECT_COMMAND_READ)
+            .build()];
+
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::DRAW_INDIRECT | vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[],
+            );
+
+            device.cmd_copy_buffer(
+                command_buffer,
+                cull_data.buffer,
+                self.cull_data_buffer.handle(),
+                &copy_regions,
+            );
+            device.cmd_fill_buffer(command_buffer, draw_counts.handle(), 0, vk::WHOLE_SIZE, 0);
+
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::DependencyFlags::empty(),
+                &transfer_barriers,
+                &[],
+                &[],
+            );
+
+            self.pipeline.bind(command_buffer);
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.pipeline_layout.handle(),
+                0,
+                &[descriptor_set],
+                &[],
+            );
+        }
+
+        self.pipeline
+            .dispatch_invocations(command_buffer, [self.draw_count, 1, 1]);
+
+        unsafe {
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::PipelineStageFlags::DRAW_INDIRECT,
+                vk::DependencyFlags::empty(),
+                &cull_barriers,
+                &[],
+                &[],
+            );
+        }
+
+        Ok(())
+    }
+
+    // draws the visible commands of a batch, the batch index selects its count
+    pub fn record_batch(&self, command_buffer: vk::CommandBuffer, batch_index: usize, batch: &DrawBatch) {
+        let (output_commands, draw_counts) = match (&self.output_commands, &self.draw_counts) {
+            (Some(output_commands), Some(draw_counts)) => (output_commands, draw_counts),
+            _ => return,
+        };
+
+        unsafe {
+            self.device
+                .draw_indirect_count_fn()
+                .expect("gpu culling requires draw indirect count")
+                .cmd_draw_indexed_indirect_count_khr(
+                    command_buffer,
+                    output_commands.handle(),
+                    (batch.first_command * COMMAND_STRIDE) as vk::DeviceSize,
+                    draw_counts.handle(),
+                    (batch_index * std::mem::size_of::<u32>()) as vk::DeviceSize,
+                    batch.command_count,
+                    COMMAND_STRIDE,
+                );
+        }
+    }
+
+    // must be recorded after the render pass which has written the depth image
+    pub fn record_depth_pyramid(
+        &mut self,
+        command_buffer: vk::CommandBuffer,
+        depth_image: vk::Image,
+        depth_aspect: vk::ImageAspectFlags,
+        framebuffer_index: usize,
+    ) {
+        if self.draw_counts.is_none() {
+            return;
+        }
+        self.depth_pyramid
+            .record_build(command_buffer, depth_image, depth_aspect, framebuffer_index);
+    }
+}
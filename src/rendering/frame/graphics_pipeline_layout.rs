@@ -1,87 +1,137 @@
+use crate::rendering::descriptors;
+use crate::rendering::pipeline_layout::PipelineLayoutDescription;
 use crate::rendering::prelude::*;
-use crate::rendering::{Buffer, Device};
+use crate::rendering::staging::StagingAllocation;
+use crate::rendering::{
+    Buffer, DescriptorAllocator, DescriptorLayoutCache, Device, PipelineLayout, ShaderModule, StagingRing,
+};
+
+const WORLD_DATA_SET: usize = 0;
+const WORLD_DATA_BINDING: u32 = 0;
+const DRAW_DATA_BINDING: u32 = 1;
+const JOINT_MATRICES_BINDING: u32 = 2;
+const MORPH_WEIGHTS_BINDING: u32 = 3;
+const MORPH_DELTAS_BINDING: u32 = 4;
+
+// buffers of the world data set which every mesh permutation must declare
+const WORLD_DATA_BINDINGS: [(u32, vk::DescriptorType, &str); 5] = [
+    (
+        WORLD_DATA_BINDING,
+        vk::DescriptorType::UNIFORM_BUFFER,
+        "world data uniform buffer",
+    ),
+    (
+        DRAW_DATA_BINDING,
+        vk::DescriptorType::STORAGE_BUFFER,
+        "draw data storage buffer",
+    ),
+    (
+        JOINT_MATRICES_BINDING,
+        vk::DescriptorType::STORAGE_BUFFER,
+        "joint matrices storage buffer",
+    ),
+    (
+        MORPH_WEIGHTS_BINDING,
+        vk::DescriptorType::STORAGE_BUFFER,
+        "morph weights storage buffer",
+    ),
+    (
+        MORPH_DELTAS_BINDING,
+        vk::DescriptorType::STORAGE_BUFFER,
+        "morph deltas storage buffer",
+    ),
+];
 
 pub struct GraphicsPipelineLayout {
-    device: Arc<Device>,
-    descriptor_pool: Arc<DescriptorPool>,
-    pipeline_layout: vk::PipelineLayout,
+    descriptor_allocator: DescriptorAllocator,
+    pipeline_layout: PipelineLayout,
     uniform_buffers: UniformBuffers,
 }
 
 impl GraphicsPipelineLayout {
-    pub fn new(device: Arc<Device>, max_frames_in_flight: usize) -> Result<Self> {
-        let descriptor_pool = Arc::new(DescriptorPool::new(device.clone(), max_frames_in_flight)?);
-        let uniform_buffers = UniformBuffers::new(device.clone(), descriptor_pool.clone(), max_frames_in_flight)?;
-
-        let descriptor_set_layouts = [uniform_buffers.layout()];
-        let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::builder().set_layouts(&descriptor_set_layouts);
+    pub fn new(
+        device: Arc<Device>,
+        layout_cache: &DescriptorLayoutCache,
+        shaders: &[&ShaderModule],
+        max_frames_in_flight: usize,
+    ) -> Result<Self> {
+        let pipeline_layout = PipelineLayout::from_shaders(device.clone(), layout_cache, shaders)?;
+
+        for &(binding, descriptor_type, name) in &WORLD_DATA_BINDINGS {
+            let declared = pipeline_layout.description().binding(WORLD_DATA_SET, binding);
+            if declared.map(|binding| binding.descriptor_type) != Some(descriptor_type) {
+                unsafe { pipeline_layout.destroy() };
+                return Err(Error::msg(format!(
+                    "shaders must declare {} at set {} binding {}",
+                    name, WORLD_DATA_SET, binding
+                )));
+            }
+        }
 
-        let pipeline_layout = unsafe {
-            device
-                .handle()
-                .create_pipeline_layout(&pipeline_layout_create_info, None)?
+        let mut descriptor_allocator = DescriptorAllocator::new(device.clone());
+        let uniform_buffers = match UniformBuffers::new(
+            device,
+            &mut descriptor_allocator,
+            pipeline_layout.descriptor_set_layout(WORLD_DATA_SET),
+            max_frames_in_flight,
+        ) {
+            Ok(uniform_buffers) => uniform_buffers,
+            Err(e) => {
+                unsafe {
+                    descriptor_allocator.destroy();
+                    pipeline_layout.destroy();
+                }
+                return Err(e);
+            }
         };
-        log::debug!("created pipeline layout {:?}", pipeline_layout);
 
         Ok(Self {
-            device,
-            descriptor_pool,
+            descriptor_allocator,
             pipeline_layout,
             uniform_buffers,
         })
     }
 
     pub unsafe fn destroy(&self) {
-        self.device.handle().destroy_pipeline_layout(self.pipeline_layout, None);
-        log::debug!("dropped pipeline layout {:?}", self.pipeline_layout);
-
+        self.pipeline_layout.destroy();
         self.uniform_buffers.destroy();
-        self.descriptor_pool.destroy();
+        self.descriptor_allocator.destroy();
     }
 
     #[inline]
     pub fn handle(&self) -> vk::PipelineLayout {
-        self.pipeline_layout
+        self.pipeline_layout.handle()
     }
 
     #[inline]
-    pub fn uniform_buffers(&self) -> &UniformBuffers {
-        &self.uniform_buffers
+    pub fn descriptor_set_layout(&self, set: usize) -> vk::DescriptorSetLayout {
+        self.pipeline_layout.descriptor_set_layout(set)
     }
 
     #[inline]
-    pub fn uniform_buffers_mut(&mut self) -> &mut UniformBuffers {
-        &mut self.uniform_buffers
+    pub fn description(&self) -> &PipelineLayoutDescription {
+        self.pipeline_layout.description()
+    }
+
+    #[inline]
+    pub fn uniform_buffers(&self) -> &UniformBuffers {
+        &self.uniform_buffers
     }
 }
 
 pub struct UniformBuffers {
     device: Arc<Device>,
-    descriptor_pool: Arc<DescriptorPool>,
-    descriptor_set_layout: vk::DescriptorSetLayout,
     world_data_buffers: Vec<Buffer>,
     descriptor_sets: Vec<vk::DescriptorSet>,
 }
 
 impl UniformBuffers {
-    pub fn new(device: Arc<Device>, descriptor_pool: Arc<DescriptorPool>, max_frames_in_flight: usize) -> Result<Self> {
-        // create descriptor set layout
-        let ubo_layout_bindings = [vk::DescriptorSetLayoutBinding::builder()
-            .binding(0)
-            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
-            .descriptor_count(1)
-            .stage_flags(vk::ShaderStageFlags::VERTEX)
-            .build()];
-
-        let ubo_layout_create_info = vk::DescriptorSetLayoutCreateInfo::builder().bindings(&ubo_layout_bindings);
-
-        let descriptor_set_layout = unsafe {
-            device
-                .handle()
-                .create_descriptor_set_layout(&ubo_layout_create_info, None)?
-        };
-        log::debug!("created descriptor set layout {:?}", descriptor_set_layout);
-
+    pub fn new(
+        device: Arc<Device>,
+        descriptor_allocator: &mut DescriptorAllocator,
+        descriptor_set_layout: vk::DescriptorSetLayout,
+        max_frames_in_flight: usize,
+    ) -> Result<Self> {
         // create buffers
         let buffer_size = (std::mem::size_of::<glm::Mat4>() * 2) as vk::DeviceSize;
 
@@ -90,8 +140,8 @@ impl UniformBuffers {
                 Buffer::new(
                     device.clone(),
                     buffer_size,
-                    vk::BufferUsageFlags::UNIFORM_BUFFER,
-                    vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                    vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::UNIFORM_BUFFER,
+                    vk::MemoryPropertyFlags::DEVICE_LOCAL,
                 )
                 .map(|buffer| {
                     buffers.push(buffer);
@@ -100,18 +150,9 @@ impl UniformBuffers {
             })?;
 
         // create descriptor sets
-        let layouts = std::iter::repeat(descriptor_set_layout)
-            .take(max_frames_in_flight)
-            .collect::<Vec<_>>();
-
-        let descriptor_set_allocate_info = vk::DescriptorSetAllocateInfo::builder()
-            .descriptor_pool(descriptor_pool.handle())
-            .set_layouts(&layouts);
-        let descriptor_sets = unsafe {
-            device
-                .handle()
-                .allocate_descriptor_sets(&descriptor_set_allocate_info)?
-        };
+        let descriptor_sets = (0..max_frames_in_flight)
+            .map(|_| descriptor_allocator.allocate(descriptor_set_layout))
+            .collect::<Result<Vec<_>>>()?;
 
         // bind descriptor sets to buffers
         for (i, &descriptor_set) in descriptor_sets.iter().enumerate() {
@@ -123,7 +164,7 @@ impl UniformBuffers {
 
             let descriptor_write_sets = [vk::WriteDescriptorSet::builder()
                 .dst_set(descriptor_set)
-                .dst_binding(0)
+                .dst_binding(WORLD_DATA_BINDING)
                 .dst_array_element(0)
                 .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
                 .buffer_info(&descriptor_buffer_info)
@@ -137,8 +178,6 @@ impl UniformBuffers {
         // done
         Ok(Self {
             device,
-            descriptor_pool,
-            descriptor_set_layout,
             world_data_buffers,
             descriptor_sets,
         })
@@ -146,82 +185,91 @@ impl UniformBuffers {
 
     pub unsafe fn destroy(&self) {
         self.world_data_buffers.iter().for_each(|buffer| buffer.destroy());
+    }
 
-        let device = self.device.handle();
-
-        device.free_descriptor_sets(self.descriptor_pool.handle(), &self.descriptor_sets);
-
-        device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
-        log::debug!("dropped descriptor set layout {:?}", self.descriptor_set_layout);
+    pub fn stage_world_data(
+        &self,
+        staging: &mut StagingRing,
+        view: &glm::Mat4,
+        projection: &glm::Mat4,
+    ) -> Result<StagingAllocation> {
+        let mut buffer_data = [0f32; 16 * 2];
+        buffer_data[..16].copy_from_slice(view.as_slice());
+        buffer_data[16..].copy_from_slice(projection.as_slice());
+
+        let alignment = self.device.properties().limits.optimal_buffer_copy_offset_alignment;
+        staging.write(bytemuck::cast_slice(&buffer_data), alignment)
     }
 
-    pub fn update_world_data(&mut self, current_frame: usize, view: &glm::Mat4, projection: &glm::Mat4) -> Result<()> {
+    pub fn record_world_data_update(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        current_frame: usize,
+        world_data: &StagingAllocation,
+    ) {
+        let device = self.device.handle();
         let buffer = &self.world_data_buffers[current_frame];
 
-        unsafe {
-            let data_ptr = buffer.map_memory()?;
-
-            let mut buffer_data = [0f32; 16 * 2];
-            buffer_data[..16].copy_from_slice(view.as_slice());
-            buffer_data[16..].copy_from_slice(projection.as_slice());
-            let buffer_data_slice = bytemuck::cast_slice(&buffer_data);
+        let copy_regions = [vk::BufferCopy {
+            src_offset: world_data.offset,
+            dst_offset: 0,
+            size: world_data.size,
+        }];
 
-            data_ptr.copy_from_nonoverlapping(buffer_data_slice.as_ptr(), buffer_data_slice.len());
+        let barriers = [vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::UNIFORM_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(buffer.handle())
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build()];
 
-            buffer.unmap_memory();
+        unsafe {
+            device.cmd_copy_buffer(command_buffer, world_data.buffer, buffer.handle(), &copy_regions);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::VERTEX_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &barriers,
+                &[],
+            );
         }
-
-        Ok(())
     }
 
-    #[inline]
-    pub fn descriptor_set(&self, current_frame: usize) -> vk::DescriptorSet {
-        self.descriptor_sets[current_frame]
+    // draw data is shared by all frames, so it must not be replaced while any of them is in flight
+    pub fn write_draw_data(&self, draw_data_buffer: &Buffer) {
+        for &descriptor_set in &self.descriptor_sets {
+            descriptors::write_storage_buffer(&self.device, descriptor_set, DRAW_DATA_BINDING, draw_data_buffer);
+        }
     }
 
-    #[inline]
-    pub fn layout(&self) -> vk::DescriptorSetLayout {
-        self.descriptor_set_layout
+    // each frame in flight reads its own joint buffer
+    pub fn write_joint_matrices(&self, joint_buffers: &[Buffer]) {
+        for (&descriptor_set, buffer) in self.descriptor_sets.iter().zip(joint_buffers) {
+            descriptors::write_storage_buffer(&self.device, descriptor_set, JOINT_MATRICES_BINDING, buffer);
+        }
     }
-}
-
-pub struct DescriptorPool {
-    device: Arc<Device>,
-    descriptor_pool: vk::DescriptorPool,
-}
 
-impl DescriptorPool {
-    pub fn new(device: Arc<Device>, size: usize) -> Result<Self> {
-        let pool_sizes = [vk::DescriptorPoolSize {
-            ty: vk::DescriptorType::UNIFORM_BUFFER,
-            descriptor_count: size as u32,
-        }];
-
-        let descriptor_pool_create_info = vk::DescriptorPoolCreateInfo::builder()
-            .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
-            .max_sets(size as u32)
-            .pool_sizes(&pool_sizes);
-
-        let descriptor_pool = unsafe {
-            device
-                .handle()
-                .create_descriptor_pool(&descriptor_pool_create_info, None)?
-        };
-        log::debug!("created descriptor pool {:?}", descriptor_pool);
-
-        Ok(Self {
-            device,
-            descriptor_pool,
-        })
+    // each frame in flight reads its own morph weight buffer
+    pub fn write_morph_weights(&self, morph_weight_buffers: &[Buffer]) {
+        for (&descriptor_set, buffer) in self.descriptor_sets.iter().zip(morph_weight_buffers) {
+            descriptors::write_storage_buffer(&self.device, descriptor_set, MORPH_WEIGHTS_BINDING, buffer);
+        }
     }
 
-    pub unsafe fn destroy(&self) {
-        self.device.handle().destroy_descriptor_pool(self.descriptor_pool, None);
-        log::debug!("dropped descriptor pool {:?}", self.descriptor_pool);
+    // morph deltas are part of the scene geometry, which must not be replaced while any frame is in flight
+    pub fn write_morph_deltas(&self, morph_delta_buffer: &Buffer) {
+        for &descriptor_set in &self.descriptor_sets {
+            descriptors::write_storage_buffer(&self.device, descriptor_set, MORPH_DELTAS_BINDING, morph_delta_buffer);
+        }
     }
 
     #[inline]
-    pub fn handle(&self) -> vk::DescriptorPool {
-        self.descriptor_pool
+    pub fn descriptor_set(&self, current_frame: usize) -> vk::DescriptorSet {
+        self.descriptor_sets[current_frame]
     }
 }
@@ -0,0 +1,602 @@
+use super::mesh_pipelines::PipelineKey;
+use crate::rendering::prelude::*;
+use crate::rendering::staging::{StagingAllocation, StagingRing};
+use crate::rendering::{
+    BoundingBox, Buffer, Device, Frustum, JointRange, LodSelector, Mesh, MeshInstance, MeshLod, ShaderFeatures,
+    UploadManager,
+};
+
+pub const COMMAND_STRIDE: u32 = std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as u32;
+
+// how indirect commands are submitted, depends on the supported device features
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum DrawMode {
+    // one indirect call per batch
+    MultiDrawIndirect,
+    // one indirect call per command
+    DrawIndirect,
+    // indirect draws can't use the first instance, so commands are issued from the CPU copy
+    Direct,
+}
+
+// per-draw data, must match the draw data struct in the mesh shaders
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct DrawData {
+    pub model: [f32; 16],
+    pub material_index: u32,
+    pub object_id: u32,
+    // first joint matrix of skinned draws
+    pub joint_offset: u32,
+    // first weight of the instance and the number of morph targets of the mesh
+    pub morph_weight_offset: u32,
+    pub morph_target_count: u32,
+    // added to the vertex index times the target count to find the first delta of a vertex
+    pub morph_delta_offset: i32,
+    pub padding: [u32; 2],
+}
+
+unsafe impl bytemuck::Pod for DrawData {}
+unsafe impl bytemuck::Zeroable for DrawData {}
+
+// bounds of a draw for the culling, must match the draw bounds struct in the culling shader
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct DrawBounds {
+    pub sphere: [f32; 4],
+    pub batch: u32,
+    pub padding: [u32; 3],
+}
+
+unsafe impl bytemuck::Pod for DrawBounds {}
+unsafe impl bytemuck::Zeroable for DrawBounds {}
+
+// number of instances which passed or failed the last frustum test
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct CullStats {
+    pub visible: u32,
+    pub culled: u32,
+}
+
+// instances which are drawn with the same pipeline and material set. Each instance has a command slot,
+// the commands of a frame are packed at the beginning of the slots of their batch
+#[derive(Debug, Copy, Clone)]
+pub struct DrawBatch {
+    pub key: PipelineKey,
+    pub material_set: vk::DescriptorSet,
+    pub first_command: u32,
+    pub command_count: u32,
+    instance_count: u32,
+}
+
+// commands and bounds of the current frame, copied into the command slots before drawing.
+// Draw data is only copied when instances moved
+#[derive(Debug, Copy, Clone)]
+pub struct DrawUpdate {
+    commands: StagingAllocation,
+    bounds: StagingAllocation,
+    draw_data: Option<StagingAllocation>,
+}
+
+// static data of a single instance, its draw data has the same index.
+// Skinned instances keep the bounds of their mesh to compute the world ones from the joints
+#[derive(Debug, Clone)]
+struct DrawInstance {
+    mesh: usize,
+    sphere: glm::Vec4,
+    bounds: BoundingBox,
+    skin: Option<(JointRange, BoundingBox)>,
+}
+
+// geometry of a mesh used by the instances
+#[derive(Debug, Clone)]
+struct MeshRange {
+    vertex_offset: i32,
+    lods: Vec<MeshLod>,
+}
+
+// indirect commands for the whole scene, each instance of a command reads its data by the instance index.
+// Commands are rebuilt every frame from the visible instances and their lods
+pub struct IndirectDraws {
+    device: Arc<Device>,
+    mode: DrawMode,
+    max_draw_count: u32,
+    instances: Vec<DrawInstance>,
+    // copy of the draw data buffer, rewritten when instances move
+    draw_data: Vec<DrawData>,
+    is_draw_data_dirty: bool,
+    meshes: Vec<Option<MeshRange>>,
+    // lods of the previous frame, for the hysteresis
+    lod_levels: Vec<usize>,
+    batches: Vec<DrawBatch>,
+    commands: Vec<vk::DrawIndexedIndirectCommand>,
+    bounds: Vec<DrawBounds>,
+    command_buffer: Option<Buffer>,
+    draw_data_buffer: Option<Buffer>,
+    bounds_buffer: Option<Buffer>,
+    batch_offsets_buffer: Option<Buffer>,
+}
+
+impl IndirectDraws {
+    pub fn new(device: Arc<Device>) -> Self {
+        let features = &device.capabilities().features;
+        let mode = if features.draw_indirect_first_instance != vk::TRUE {
+            DrawMode::Direct
+        } else if features.multi_draw_indirect == vk::TRUE {
+            DrawMode::MultiDrawIndirect
+        } else {
+            DrawMode::DrawIndirect
+        };
+        let max_draw_count = device.properties().limits.max_draw_indirect_count.max(1);
+
+        log::debug!("created indirect draws, mode: {:?}", mode);
+
+        Self {
+            device,
+            mode,
+            max_draw_count,
+            instances: Vec::new(),
+            draw_data: Vec::new(),
+            is_draw_data_dirty: false,
+            meshes: Vec::new(),
+            lod_levels: Vec::new(),
+            batches: Vec::new(),
+            commands: Vec::new(),
+            bounds: Vec::new(),
+            command_buffer: None,
+            draw_data_buffer: None,
+            bounds_buffer: None,
+            batch_offsets_buffer: None,
+        }
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.buffers().for_each(|buffer| buffer.destroy());
+    }
+
+    fn buffers(&self) -> impl Iterator<Item = &Buffer> {
+        self.command_buffer
+            .iter()
+            .chain(self.draw_data_buffer.iter())
+            .chain(self.bounds_buffer.iter())
+            .chain(self.batch_offsets_buffer.iter())
+    }
+
+    // replaces all draws, buffers must not be used by any frame in flight
+    pub fn update(&mut self, uploader: &mut UploadManager, builder: DrawListBuilder) -> Result<()> {
+        self.device.wait_idle()?;
+
+        let DrawListBuilder {
+            draw_data,
+            instances,
+            meshes,
+            batches,
+        } = builder;
+
+        unsafe { self.destroy() };
+        self.command_buffer = None;
+        self.draw_data_buffer = None;
+        self.bounds_buffer = None;
+        self.batch_offsets_buffer = None;
+
+        let slot_count = instances.len();
+        let batch_offsets = batches.iter().map(|batch| batch.first_command).collect::<Vec<_>>();
+
+        // commands and bounds are written every frame, they are also read by the culling
+        self.command_buffer = Some(self.create_buffer(
+            (slot_count * COMMAND_STRIDE as usize) as vk::DeviceSize,
+            vk::BufferUsageFlags::INDIRECT_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?);
+        self.bounds_buffer = Some(self.create_buffer(
+            (slot_count * std::mem::size_of::<DrawBounds>()) as vk::DeviceSize,
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?);
+        self.draw_data_buffer = Some(self.upload_buffer(
+            uploader,
+            bytemuck::cast_slice(&draw_data),
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?);
+        self.batch_offsets_buffer = Some(self.upload_buffer(
+            uploader,
+            bytemuck::cast_slice(&batch_offsets),
+            vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?);
+        uploader.flush()?;
+
+        log::debug!(
+            "updated indirect draws: {} instances in {} batches",
+            instances.len(),
+            batches.len()
+        );
+
+        self.lod_levels = vec![0; slot_count];
+        self.commands = Vec::with_capacity(slot_count);
+        self.bounds = Vec::with_capacity(slot_count);
+        self.instances = instances;
+        self.draw_data = draw_data;
+        self.is_draw_data_dirty = false;
+        self.meshes = meshes;
+        self.batches = batches;
+
+        Ok(())
+    }
+
+    // moves the instances of the draws, the instance of each draw is found by its object id.
+    // Draw data is copied by the next update, skinned bounds still follow their joints
+    pub fn update_instances(&mut self, instances: &[MeshInstance]) {
+        for (draw_data, draw_instance) in self.draw_data.iter_mut().zip(&mut self.instances) {
+            let instance = match instances.get(draw_data.object_id as usize) {
+                Some(instance) => instance,
+                None => continue,
+            };
+
+            draw_data.model.copy_from_slice(instance.transform.as_slice());
+            if draw_instance.skin.is_none() {
+                draw_instance.bounds = instance.bounds;
+                draw_instance.sphere = instance.bounding_sphere;
+            }
+        }
+
+        self.is_draw_data_dirty = !self.draw_data.is_empty();
+    }
+
+    // builds the commands of this frame, consecutive visible instances of the same mesh and lod are drawn
+    // with one instanced command. Spheres are tested first, only the ones which intersect the frustum
+    // are tested by their boxes
+    pub fn prepare(
+        &mut self,
+        frustum: &Frustum,
+        lod_selector: &LodSelector,
+        joint_matrices: &[glm::Mat4],
+    ) -> CullStats {
+        let mut stats = CullStats::default();
+
+        // unused slots stay empty, so they can be read as a whole
+        self.commands.clear();
+        self.commands
+            .resize(self.instances.len(), vk::DrawIndexedIndirectCommand::default());
+        self.bounds.clear();
+        self.bounds.resize(self.instances.len(), DrawBounds::default());
+
+        for (batch_index, batch) in self.batches.iter_mut().enumerate() {
+            let first_slot = batch.first_command as usize;
+            let mut command_count = 0;
+
+            for index in first_slot..first_slot + batch.instance_count as usize {
+                let instance = &mut self.instances[index];
+                if let Some((joints, mesh_bounds)) = &instance.skin {
+                    if let Some(joint_matrices) = joint_matrices.get(joints.offset as usize..joints.end() as usize) {
+                        instance.bounds = skinned_bounds(mesh_bounds, joint_matrices);
+                        instance.sphere = instance.bounds.bounding_sphere(&glm::Mat4::identity());
+                    }
+                }
+
+                let instance = &self.instances[index];
+                if !frustum.intersects_sphere(&instance.sphere) || !frustum.intersects_box(&instance.bounds) {
+                    stats.culled += 1;
+                    continue;
+                }
+                stats.visible += 1;
+
+                let mesh = self.meshes[instance.mesh].as_ref().unwrap();
+                let lod = lod_selector.select(&instance.sphere, self.lod_levels[index], mesh.lods.len());
+                self.lod_levels[index] = lod;
+                let lod = mesh.lods[lod];
+
+                // instances are merged only when their draw data is consecutive
+                if command_count > 0 {
+                    let slot = first_slot + command_count - 1;
+                    let command = &mut self.commands[slot];
+                    if command.first_index == lod.first_index
+                        && command.vertex_offset == mesh.vertex_offset
+                        && command.first_instance + command.instance_count == index as u32
+                    {
+                        command.instance_count += 1;
+
+                        let bounds = &mut self.bounds[slot];
+                        let sphere = merge_spheres(&glm::make_vec4(&bounds.sphere), &instance.sphere);
+                        bounds.sphere.copy_from_slice(sphere.as_slice());
+                        continue;
+                    }
+                }
+
+                let slot = first_slot + command_count;
+                self.commands[slot] = vk::DrawIndexedIndirectCommand {
+                    index_count: lod.index_count,
+                    instance_count: 1,
+                    first_index: lod.first_index,
+                    vertex_offset: mesh.vertex_offset,
+                    first_instance: index as u32,
+                };
+
+                let mut sphere = [0.0; 4];
+                sphere.copy_from_slice(instance.sphere.as_slice());
+                self.bounds[slot] = DrawBounds {
+                    sphere,
+                    batch: batch_index as u32,
+                    padding: [0; 3],
+                };
+
+                command_count += 1;
+            }
+
+            batch.command_count = command_count as u32;
+        }
+
+        stats
+    }
+
+    // commands must be prepared first
+    pub fn stage(&mut self, staging: &mut StagingRing) -> Result<Option<DrawUpdate>> {
+        if self.commands.is_empty() {
+            return Ok(None);
+        }
+
+        // commands are stored as raw bytes, ash types don't implement Pod
+        let command_bytes = unsafe {
+            std::slice::from_raw_parts(
+                self.commands.as_ptr() as *const u8,
+                std::mem::size_of_val(self.commands.as_slice()),
+            )
+        };
+
+        let alignment = self.device.properties().limits.optimal_buffer_copy_offset_alignment;
+        let draw_data = match self.is_draw_data_dirty {
+            true => Some(staging.write(bytemuck::cast_slice(&self.draw_data), alignment)?),
+            false => None,
+        };
+        self.is_draw_data_dirty = false;
+
+        Ok(Some(DrawUpdate {
+            commands: staging.write(command_bytes, alignment)?,
+            bounds: staging.write(bytemuck::cast_slice(&self.bounds), alignment)?,
+            draw_data,
+        }))
+    }
+
+    // must be recorded outside of a render pass, before the culling and the draws
+    pub fn record_update(&self, command_buffer: vk::CommandBuffer, update: &DrawUpdate) {
+        let (commands, bounds, draw_data) = match (&self.command_buffer, &self.bounds_buffer, &self.draw_data_buffer) {
+            (Some(commands), Some(bounds), Some(draw_data)) => (commands, bounds, draw_data),
+            _ => return,
+        };
+        let device = self.device.handle();
+
+        let copy = |source: &StagingAllocation, destination: &Buffer| {
+            let copy_regions = [vk::BufferCopy {
+                src_offset: source.offset,
+                dst_offset: 0,
+                size: source.size,
+            }];
+            unsafe { device.cmd_copy_buffer(command_buffer, source.buffer, destination.handle(), &copy_regions) };
+        };
+
+        let barriers = [vk::MemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::INDIRECT_COMMAND_READ | vk::AccessFlags::SHADER_READ)
+            .build()];
+
+        let read_stages = vk::PipelineStageFlags::DRAW_INDIRECT
+            | vk::PipelineStageFlags::COMPUTE_SHADER
+            | vk::PipelineStageFlags::VERTEX_SHADER;
+
+        unsafe {
+            // slots and draw data are still read by the previous frame
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                read_stages,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &[],
+                &[],
+            );
+
+            copy(&update.commands, commands);
+            copy(&update.bounds, bounds);
+            if let Some(update) = &update.draw_data {
+                copy(update, draw_data);
+            }
+
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                read_stages,
+                vk::DependencyFlags::empty(),
+                &barriers,
+                &[],
+                &[],
+            );
+        }
+    }
+
+    fn create_buffer(&self, size: vk::DeviceSize, usage: vk::BufferUsageFlags) -> Result<Buffer> {
+        Buffer::new(
+            self.device.clone(),
+            size.max(1),
+            vk::BufferUsageFlags::TRANSFER_DST | usage,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )
+    }
+
+    fn upload_buffer(&self, uploader: &mut UploadManager, data: &[u8], usage: vk::BufferUsageFlags) -> Result<Buffer> {
+        let buffer = self.create_buffer(data.len() as vk::DeviceSize, usage)?;
+
+        match uploader.upload_buffer(&buffer, 0, data) {
+            Ok(()) => Ok(buffer),
+            Err(e) => {
+                unsafe { buffer.destroy() };
+                Err(e)
+            }
+        }
+    }
+
+    pub fn record_batch(&self, command_buffer: vk::CommandBuffer, batch: &DrawBatch) {
+        let device = self.device.handle();
+        let indirect_buffer = match &self.command_buffer {
+            Some(buffer) => buffer.handle(),
+            None => return,
+        };
+        let offset = |command: u32| (command * COMMAND_STRIDE) as vk::DeviceSize;
+
+        unsafe {
+            match self.mode {
+                DrawMode::MultiDrawIndirect => {
+                    let mut first_command = batch.first_command;
+                    let end = batch.first_command + batch.command_count;
+                    while first_command < end {
+                        let draw_count = (end - first_command).min(self.max_draw_count);
+                        device.cmd_draw_indexed_indirect(
+                            command_buffer,
+                            indirect_buffer,
+                            offset(first_command),
+                            draw_count,
+                            COMMAND_STRIDE,
+                        );
+                        first_command += draw_count;
+                    }
+                }
+                DrawMode::DrawIndirect => {
+                    for command in batch.first_command..batch.first_command + batch.command_count {
+                        device.cmd_draw_indexed_indirect(
+                            command_buffer,
+                            indirect_buffer,
+                            offset(command),
+                            1,
+                            COMMAND_STRIDE,
+                        );
+                    }
+                }
+                DrawMode::Direct => {
+                    let first = batch.first_command as usize;
+                    let last = first + batch.command_count as usize;
+                    for command in &self.commands[first..last] {
+                        device.cmd_draw_indexed(
+                            command_buffer,
+                            command.index_count,
+                            command.instance_count,
+                            command.first_index,
+                            command.vertex_offset,
+                            command.first_instance,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[inline]
+    pub fn batches(&self) -> &[DrawBatch] {
+        &self.batches
+    }
+
+    // number of command slots, which is the number of instances
+    #[inline]
+    pub fn command_count(&self) -> u32 {
+        self.instances.len() as u32
+    }
+
+    #[inline]
+    pub fn command_buffer(&self) -> Option<&Buffer> {
+        self.command_buffer.as_ref()
+    }
+
+    #[inline]
+    pub fn draw_data_buffer(&self) -> Option<&Buffer> {
+        self.draw_data_buffer.as_ref()
+    }
+
+    #[inline]
+    pub fn bounds_buffer(&self) -> Option<&Buffer> {
+        self.bounds_buffer.as_ref()
+    }
+
+    #[inline]
+    pub fn batch_offsets_buffer(&self) -> Option<&Buffer> {
+        self.batch_offsets_buffer.as_ref()
+    }
+}
+
+// draws must be pushed already sorted by pipeline and material set to form batches,
+// instances of a mesh must be pushed one after another to be drawn instanced
+#[derive(Default)]
+pub struct DrawListBuilder {
+    draw_data: Vec<DrawData>,
+    instances: Vec<DrawInstance>,
+    meshes: Vec<Option<MeshRange>>,
+    batches: Vec<DrawBatch>,
+}
+
+impl DrawListBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(
+        &mut self,
+        key: PipelineKey,
+        material_set: vk::DescriptorSet,
+        mesh: &Mesh,
+        instance: &MeshInstance,
+        draw_data: DrawData,
+    ) {
+        let index = self.instances.len() as u32;
+
+        match self.batches.last_mut() {
+            Some(batch) if batch.key == key && batch.material_set == material_set => batch.instance_count += 1,
+            _ => self.batches.push(DrawBatch {
+                key,
+                material_set,
+                first_command: index,
+                command_count: 0,
+                instance_count: 1,
+            }),
+        }
+
+        if self.meshes.len() <= instance.mesh {
+            self.meshes.resize(instance.mesh + 1, None);
+        }
+        self.meshes[instance.mesh].get_or_insert_with(|| MeshRange {
+            vertex_offset: mesh.vertex_offset(),
+            lods: mesh.lods().to_vec(),
+        });
+
+        self.draw_data.push(draw_data);
+        self.instances.push(DrawInstance {
+            mesh: instance.mesh,
+            sphere: instance.bounding_sphere,
+            bounds: instance.bounds,
+            skin: instance
+                .joints
+                .filter(|_| key.features.contains(ShaderFeatures::SKINNED))
+                .map(|joints| (joints, *mesh.bounds())),
+        });
+    }
+}
+
+// every skinned vertex is a weighted average of its position transformed by the joints,
+// so it stays inside the union of the mesh bounds transformed by each joint
+fn skinned_bounds(mesh_bounds: &BoundingBox, joint_matrices: &[glm::Mat4]) -> BoundingBox {
+    joint_matrices
+        .iter()
+        .map(|joint_matrix| mesh_bounds.transformed(joint_matrix))
+        .reduce(|bounds, joint_bounds| bounds.union(&joint_bounds))
+        .unwrap_or(*mesh_bounds)
+}
+
+// smallest sphere which encloses both spheres
+fn merge_spheres(first: &glm::Vec4, second: &glm::Vec4) -> glm::Vec4 {
+    let offset = second.xyz() - first.xyz();
+    let distance = glm::length(&offset);
+
+    if distance + second.w <= first.w {
+        return *first;
+    }
+    if distance + first.w <= second.w {
+        return *second;
+    }
+
+    let radius = (distance + first.w + second.w) * 0.5;
+    let center = first.xyz() + offset * ((radius - first.w) / distance);
+    glm::vec4(center.x, center.y, center.z, radius)
+}
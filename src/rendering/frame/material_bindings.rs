@@ -0,0 +1,216 @@
+use super::graphics_pipeline_layout::GraphicsPipelineLayout;
+use crate::rendering::descriptors;
+use crate::rendering::prelude::*;
+use crate::rendering::{
+    BindlessDescriptorSet, Buffer, DescriptorAllocator, Device, Material, Sampler, Texture, UploadManager,
+};
+
+pub const MATERIAL_SET: usize = 1;
+const MATERIALS_BINDING: u32 = 0;
+const SAMPLER_BINDING: u32 = 1;
+const TEXTURES_BINDING: u32 = 2;
+// only declared when each material has its own set
+const NORMAL_TEXTURE_BINDING: u32 = 3;
+
+// material set is either one global set with all textures, or a set per material with its own textures
+pub struct MaterialBindings {
+    device: Arc<Device>,
+    descriptor_set_layout: vk::DescriptorSetLayout,
+    sampler: Sampler,
+    bindless: Option<BindlessDescriptorSet>,
+    descriptor_allocator: DescriptorAllocator,
+    descriptor_sets: Vec<vk::DescriptorSet>,
+    materials_buffer: Option<Buffer>,
+    // bound instead of missing textures when textures are not bindless
+    fallback_texture: Option<Texture>,
+}
+
+impl MaterialBindings {
+    pub fn new(device: Arc<Device>, pipeline_layout: &GraphicsPipelineLayout) -> Result<Self> {
+        let description = pipeline_layout.description();
+
+        let expected_bindings = [
+            (MATERIALS_BINDING, vk::DescriptorType::STORAGE_BUFFER),
+            (SAMPLER_BINDING, vk::DescriptorType::SAMPLER),
+            (TEXTURES_BINDING, vk::DescriptorType::SAMPLED_IMAGE),
+        ];
+        for &(binding, descriptor_type) in &expected_bindings {
+            if description
+                .binding(MATERIAL_SET, binding)
+                .map(|binding| binding.descriptor_type)
+                != Some(descriptor_type)
+            {
+                return Err(Error::msg(format!(
+                    "shaders must declare {:?} at set {} binding {}",
+                    descriptor_type, MATERIAL_SET, binding
+                )));
+            }
+        }
+
+        let is_bindless = description
+            .binding(MATERIAL_SET, TEXTURES_BINDING)
+            .map(|binding| binding.is_runtime_array())
+            .unwrap_or_default();
+        if !is_bindless
+            && description
+                .binding(MATERIAL_SET, NORMAL_TEXTURE_BINDING)
+                .map(|binding| binding.descriptor_type)
+                != Some(vk::DescriptorType::SAMPLED_IMAGE)
+        {
+            return Err(Error::msg(format!(
+                "shaders must declare the normal texture at set {} binding {}",
+                MATERIAL_SET, NORMAL_TEXTURE_BINDING
+            )));
+        }
+
+        let descriptor_set_layout = pipeline_layout.descriptor_set_layout(MATERIAL_SET);
+        let sampler = Sampler::new(device.clone())?;
+
+        let bindless = if is_bindless {
+            let pool_sizes = description.pool_sizes(&device, MATERIAL_SET);
+            let capacity = device.capabilities().max_bindless_textures;
+            match BindlessDescriptorSet::new(device.clone(), descriptor_set_layout, &pool_sizes, capacity) {
+                Ok(bindless) => Some(bindless),
+                Err(e) => {
+                    unsafe { sampler.destroy() };
+                    return Err(e);
+                }
+            }
+        } else {
+            None
+        };
+
+        log::debug!("created material bindings, bindless textures: {}", is_bindless);
+
+        Ok(Self {
+            descriptor_allocator: DescriptorAllocator::new(device.clone()),
+            device,
+            descriptor_set_layout,
+            sampler,
+            bindless,
+            descriptor_sets: Vec::new(),
+            materials_buffer: None,
+            fallback_texture: None,
+        })
+    }
+
+    pub unsafe fn destroy(&self) {
+        if let Some(materials_buffer) = &self.materials_buffer {
+            materials_buffer.destroy();
+        }
+        if let Some(fallback_texture) = &self.fallback_texture {
+            fallback_texture.destroy();
+        }
+        if let Some(bindless) = &self.bindless {
+            bindless.destroy();
+        }
+        self.descriptor_allocator.destroy();
+        self.sampler.destroy();
+    }
+
+    // replaces all materials, sets must not be used by any frame in flight
+    pub fn update(&mut self, uploader: &mut UploadManager, materials: &[Material], textures: &[Texture]) -> Result<()> {
+        self.device.wait_idle()?;
+
+        // upload material data
+        let materials_data = materials.iter().map(Material::data).collect::<Vec<_>>();
+        let materials_data = bytemuck::cast_slice(&materials_data);
+
+        let materials_buffer = Buffer::new(
+            self.device.clone(),
+            materials_data.len().max(1) as vk::DeviceSize,
+            vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::STORAGE_BUFFER,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+        uploader.upload_buffer(&materials_buffer, 0, materials_data)?;
+
+        if let Some(old_buffer) = self.materials_buffer.replace(materials_buffer) {
+            unsafe { old_buffer.destroy() };
+        }
+        let materials_buffer = self.materials_buffer.as_ref().unwrap();
+
+        // update descriptor sets
+        self.descriptor_sets.clear();
+        self.descriptor_allocator.reset()?;
+
+        match &self.bindless {
+            Some(bindless) => {
+                if textures.len() > bindless.capacity() as usize {
+                    return Err(Error::msg(format!(
+                        "scene has {} textures, but only {} can be bound",
+                        textures.len(),
+                        bindless.capacity()
+                    )));
+                }
+
+                let descriptor_set = bindless.handle();
+                self.write_common_bindings(descriptor_set, materials_buffer);
+
+                let image_views = textures
+                    .iter()
+                    .map(|texture| texture.image_view().handle())
+                    .collect::<Vec<_>>();
+                descriptors::write_sampled_images(&self.device, descriptor_set, TEXTURES_BINDING, 0, &image_views);
+
+                self.descriptor_sets.push(descriptor_set);
+            }
+            None => {
+                if self.fallback_texture.is_none() {
+                    self.fallback_texture = Some(Texture::from_rgba8(
+                        self.device.clone(),
+                        uploader,
+                        vk::Extent2D { width: 1, height: 1 },
+                        vk::Format::R8G8B8A8_UNORM,
+                        &[255; 4],
+                    )?);
+                }
+                let fallback_texture = self.fallback_texture.as_ref().unwrap();
+
+                for material in materials {
+                    let descriptor_set = self.descriptor_allocator.allocate(self.descriptor_set_layout)?;
+                    self.write_common_bindings(descriptor_set, materials_buffer);
+
+                    let texture = |texture: Option<usize>| {
+                        texture
+                            .and_then(|index| textures.get(index))
+                            .unwrap_or(fallback_texture)
+                            .image_view()
+                            .handle()
+                    };
+                    descriptors::write_sampled_images(
+                        &self.device,
+                        descriptor_set,
+                        TEXTURES_BINDING,
+                        0,
+                        &[texture(material.base_color_texture)],
+                    );
+                    descriptors::write_sampled_images(
+                        &self.device,
+                        descriptor_set,
+                        NORMAL_TEXTURE_BINDING,
+                        0,
+                        &[texture(material.normal_texture)],
+                    );
+
+                    self.descriptor_sets.push(descriptor_set);
+                }
+            }
+        }
+
+        uploader.flush()
+    }
+
+    // all materials use the same set when textures are bindless
+    #[inline]
+    pub fn descriptor_set(&self, material: usize) -> vk::DescriptorSet {
+        match self.bindless {
+            Some(_) => self.descriptor_sets[0],
+            None => self.descriptor_sets[material],
+        }
+    }
+
+    fn write_common_bindings(&self, descriptor_set: vk::DescriptorSet, materials_buffer: &Buffer) {
+        descriptors::write_storage_buffer(&self.device, descriptor_set, MATERIALS_BINDING, materials_buffer);
+        descriptors::write_sampler(&self.device, descriptor_set, SAMPLER_BINDING, self.sampler.handle());
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use super::graphics_pipeline_layout::GraphicsPipelineLayout;
+use crate::rendering::pipeline_layout::PipelineLayoutDescription;
+use crate::rendering::prelude::*;
+use crate::rendering::{
+    BlendMode, DepthState, Device, GraphicsPipeline, PipelineCache, ShaderCompiler, ShaderFeatures, ShaderModule,
+    VertexLayout,
+};
+
+const MESH_VERTEX_SHADER: &str = "mesh.vert";
+const MESH_FRAGMENT_SHADER: &str = "mesh.frag";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    pub features: ShaderFeatures,
+    pub vertex_layout: VertexLayout,
+    pub render_pass: vk::RenderPass,
+    // render state of the material which isn't a shader feature
+    pub double_sided: bool,
+    pub blend_mode: BlendMode,
+}
+
+// mesh pipeline permutations, created on demand and shared by all meshes with the same key
+pub struct MeshPipelines {
+    device: Arc<Device>,
+    shader_compiler: ShaderCompiler,
+    // defines shared by all permutations
+    global_defines: Vec<(&'static str, &'static str)>,
+    variants: HashMap<PipelineKey, PipelineVariant>,
+}
+
+impl MeshPipelines {
+    pub fn new(
+        device: Arc<Device>,
+        shader_compiler: ShaderCompiler,
+        global_defines: Vec<(&'static str, &'static str)>,
+    ) -> Self {
+        Self {
+            device,
+            shader_compiler,
+            global_defines,
+            variants: HashMap::new(),
+        }
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.variants.values().for_each(|variant| variant.destroy());
+    }
+
+    #[inline]
+    pub fn shader_compiler(&self) -> &ShaderCompiler {
+        &self.shader_compiler
+    }
+
+    pub fn compile_shaders(&self, features: ShaderFeatures, vertex_layout: VertexLayout) -> Result<MeshShaders> {
+        MeshShaders::compile(
+            &self.device,
+            &self.shader_compiler,
+            &self.global_defines,
+            features,
+            vertex_layout,
+        )
+    }
+
+    pub fn insert(
+        &mut self,
+        pipeline_cache: &PipelineCache,
+        pipeline_layout: &GraphicsPipelineLayout,
+        key: PipelineKey,
+        shaders: MeshShaders,
+    ) -> Result<vk::Pipeline> {
+        let pipeline = match shaders.build_pipeline(&self.device, pipeline_cache, pipeline_layout, key) {
+            Ok(pipeline) => pipeline,
+            Err(e) => {
+                unsafe { shaders.destroy() };
+                return Err(e);
+            }
+        };
+        let handle = pipeline.handle();
+
+        if let Some(variant) = self.variants.insert(key, PipelineVariant { shaders, pipeline }) {
+            unsafe { variant.destroy() };
+        }
+
+        log::debug!(
+            "created mesh pipeline variant {:?} for {}",
+            key.features,
+            key.vertex_layout
+        );
+        Ok(handle)
+    }
+
+    pub fn get_or_create(
+        &mut self,
+        pipeline_cache: &PipelineCache,
+        pipeline_layout: &GraphicsPipelineLayout,
+        key: PipelineKey,
+    ) -> Result<vk::Pipeline> {
+        if let Some(variant) = self.variants.get(&key) {
+            return Ok(variant.pipeline.handle());
+        }
+
+        let shaders = self.compile_shaders(key.features, key.vertex_layout)?;
+        self.insert(pipeline_cache, pipeline_layout, key, shaders)
+    }
+
+    #[inline]
+    pub fn get(&self, key: &PipelineKey) -> Option<vk::Pipeline> {
+        self.variants.get(key).map(|variant| variant.pipeline.handle())
+    }
+
+    // rebuilds variants which depend on changed files, keeping the old pipeline on errors
+    pub fn reload(
+        &mut self,
+        pipeline_cache: &PipelineCache,
+        pipeline_layout: &GraphicsPipelineLayout,
+        changed: &[PathBuf],
+    ) -> Result<()> {
+        let affected = self
+            .variants
+            .iter()
+            .filter(|(_, variant)| changed.iter().any(|path| variant.shaders.dependencies.contains(path)))
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        if affected.is_empty() {
+            return Ok(());
+        }
+
+        // old pipelines can still be used by frames in flight
+        self.device.wait_idle()?;
+
+        for key in affected {
+            match self
+                .compile_shaders(key.features, key.vertex_layout)
+                .and_then(|shaders| self.insert(pipeline_cache, pipeline_layout, key, shaders))
+            {
+                Ok(_) => log::info!("reloaded mesh pipeline {:?} for {}", key.features, key.vertex_layout),
+                Err(e) => log::error!(
+                    "failed to reload mesh pipeline {:?} for {}, keeping previous one: {:?}",
+                    key.features,
+                    key.vertex_layout,
+                    e
+                ),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub struct MeshShaders {
+    pub vertex_shader_module: ShaderModule,
+    pub fragment_shader_module: ShaderModule,
+    pub dependencies: Vec<PathBuf>,
+}
+
+impl MeshShaders {
+    fn compile(
+        device: &Arc<Device>,
+        shader_compiler: &ShaderCompiler,
+        global_defines: &[(&'static str, &'static str)],
+        features: ShaderFeatures,
+        vertex_layout: VertexLayout,
+    ) -> Result<Self> {
+        let mut defines = features.defines();
+        defines.extend(vertex_layout.defines());
+        defines.extend_from_slice(global_defines);
+        let vertex_shader = shader_compiler.compile(MESH_VERTEX_SHADER, &defines)?;
+        let fragment_shader = shader_compiler.compile(MESH_FRAGMENT_SHADER, &defines)?;
+
+        let vertex_shader_module = ShaderModule::new(device.clone(), &vertex_shader.code)?;
+        let fragment_shader_module = match ShaderModule::new(device.clone(), &fragment_shader.code) {
+            Ok(module) => module,
+            Err(e) => {
+                unsafe { vertex_shader_module.destroy() };
+                return Err(e);
+            }
+        };
+
+        let mut dependencies = vertex_shader.dependencies;
+        for path in fragment_shader.dependencies {
+            if !dependencies.contains(&path) {
+                dependencies.push(path);
+            }
+        }
+
+        Ok(Self {
+            vertex_shader_module,
+            fragment_shader_module,
+            dependencies,
+        })
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.vertex_shader_module.destroy();
+        self.fragment_shader_module.destroy();
+    }
+
+    pub fn modules(&self) -> [&ShaderModule; 2] {
+        [&self.vertex_shader_module, &self.fragment_shader_module]
+    }
+
+    fn build_pipeline(
+        &self,
+        device: &Arc<Device>,
+        pipeline_cache: &PipelineCache,
+        pipeline_layout: &GraphicsPipelineLayout,
+        key: PipelineKey,
+    ) -> Result<GraphicsPipeline> {
+        // all permutations share the same layout so descriptor sets can be bound once
+        let description = PipelineLayoutDescription::from_shaders(&self.modules())?;
+        if !description.is_compatible(pipeline_layout.description()) {
+            return Err(Error::msg(format!(
+                "mesh pipeline permutation {:?} has incompatible pipeline layout",
+                key.features
+            )));
+        }
+
+        let cull_mode = if key.double_sided {
+            vk::CullModeFlags::NONE
+        } else {
+            vk::CullModeFlags::BACK
+        };
+        // blended surfaces are depth tested against opaque ones but don't occlude each other
+        let depth = match key.blend_mode {
+            BlendMode::Opaque => DepthState::default(),
+            _ => DepthState::read_only(),
+        };
+
+        GraphicsPipeline::builder(pipeline_layout.handle(), key.render_pass)
+            .subpass(0)
+            .shader(&self.vertex_shader_module)
+            .shader(&self.fragment_shader_module)
+            .vertex_layout(
+                &key.vertex_layout.binding_descriptions(),
+                &key.vertex_layout.attribute_descriptions(),
+            )
+            .topology(vk::PrimitiveTopology::TRIANGLE_LIST)
+            .cull_mode(cull_mode, vk::FrontFace::CLOCKWISE)
+            .depth(depth)
+            .blend(key.blend_mode, 1)
+            .dynamic_states(&[vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR])
+            .build(device.clone(), pipeline_cache)
+    }
+}
+
+struct PipelineVariant {
+    shaders: MeshShaders,
+    pipeline: GraphicsPipeline,
+}
+
+impl PipelineVariant {
+    unsafe fn destroy(&self) {
+        self.pipeline.destroy();
+        self.shaders.destroy();
+    }
+}
@@ -1,16 +1,34 @@
 mod deferred_render_pass;
+mod depth_pyramid;
 mod frame_logic;
+mod gpu_culling;
 mod graphics_pipeline_layout;
+mod indirect_draws;
+mod material_bindings;
+mod mesh_pipelines;
+mod per_frame_buffers;
+
+pub use self::indirect_draws::CullStats;
 
 use self::frame_logic::*;
 use super::prelude::*;
-use super::{CommandPool, Device, PipelineCache, Swapchain};
+use super::{CommandPool, DescriptorLayoutCache, Device, PipelineCache, ShaderCompiler, Swapchain, UploadManager};
+
+pub const MAX_FRAMES_IN_FLIGHT: usize = 2;
+
+// optional rendering paths, each is used only when the device supports it
+#[derive(Debug, Copy, Clone, Default)]
+pub struct FrameOptions {
+    pub bindless_textures: bool,
+    pub gpu_culling: bool,
+}
 
 pub struct Frame {
     device: Arc<Device>,
     logic: FrameLogic,
     current_frame: usize,
     frame_sync_objects: FrameSyncObjects,
+    cull_stats: CullStats,
 }
 
 impl Frame {
@@ -18,18 +36,31 @@ impl Frame {
         device: Arc<Device>,
         command_pool: Arc<CommandPool>,
         pipeline_cache: &PipelineCache,
+        layout_cache: &DescriptorLayoutCache,
+        shader_compiler: ShaderCompiler,
         swapchain: &Swapchain,
+        options: FrameOptions,
     ) -> Result<Self> {
-        let logic = FrameLogic::new(device.clone(), pipeline_cache, command_pool, swapchain)?;
+        let logic = FrameLogic::new(
+            device.clone(),
+            pipeline_cache,
+            layout_cache,
+            shader_compiler,
+            command_pool,
+            swapchain,
+            MAX_FRAMES_IN_FLIGHT,
+            options,
+        )?;
 
         let current_frame = 0;
-        let frame_sync_objects = FrameSyncObjects::new(device.clone(), swapchain.image_views().len())?;
+        let frame_sync_objects = FrameSyncObjects::new(device.clone(), MAX_FRAMES_IN_FLIGHT)?;
 
         Ok(Self {
             device,
             logic,
             current_frame,
             frame_sync_objects,
+            cull_stats: CullStats::default(),
         })
     }
 
@@ -38,13 +69,25 @@ impl Frame {
         self.frame_sync_objects.destroy();
     }
 
-    pub fn draw(&mut self, swapchain: &Swapchain) -> Result<bool> {
+    pub fn draw(
+        &mut self,
+        swapchain: &Swapchain,
+        uploader: &mut UploadManager,
+        view: &glm::Mat4,
+        projection: &glm::Mat4,
+        joint_matrices: &[glm::Mat4],
+        morph_weights: &[f32],
+    ) -> Result<bool> {
         let wait_semaphores = [self.frame_sync_objects.image_available_semaphore(self.current_frame)];
         let wait_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
         let wait_fence = self.frame_sync_objects.inflight_fence(self.current_frame);
         let signal_semaphores = [self.frame_sync_objects.render_finished_semaphore(self.current_frame)];
 
         self.frame_sync_objects.wait_for_fence(self.current_frame)?;
+        uploader.staging_mut().reclaim()?;
+        // uploads which were not submitted yet must not end up in the staging region of this frame
+        uploader.flush()?;
+        self.logic.begin_frame(self.current_frame)?;
 
         let image_index = match swapchain.acquire_next_image(wait_semaphores[0]) {
             Ok((image_index, _)) => image_index,
@@ -52,7 +95,30 @@ impl Frame {
             Err(e) => return Err(anyhow::Error::new(e)),
         };
 
-        let command_buffers = [self.logic.command_buffer(image_index as usize)];
+        let world_data = self.logic.pipeline_layout().uniform_buffers().stage_world_data(
+            uploader.staging_mut(),
+            view,
+            projection,
+        )?;
+
+        let animation_update =
+            self.logic
+                .stage_animation_data(uploader.staging_mut(), joint_matrices, morph_weights)?;
+
+        let (cull_stats, draw_update) =
+            self.logic
+                .prepare_draws(uploader.staging_mut(), view, projection, joint_matrices)?;
+        self.cull_stats = cull_stats;
+        let cull_data = self.logic.stage_cull_data(uploader.staging_mut(), view, projection)?;
+
+        let command_buffers = [self.logic.record_command_buffer(
+            self.current_frame,
+            image_index as usize,
+            &world_data,
+            &animation_update,
+            draw_update.as_ref(),
+            cull_data.as_ref(),
+        )?];
 
         self.frame_sync_objects.reset_fences(self.current_frame)?;
 
@@ -67,6 +133,7 @@ impl Frame {
                 .handle()
                 .queue_submit(self.device.queues().graphics_queue, &submit_infos, wait_fence)?;
         };
+        uploader.staging_mut().retire(wait_fence)?;
 
         let was_resized = swapchain.present_image(&signal_semaphores, image_index)?;
 
@@ -76,13 +143,13 @@ impl Frame {
     }
 
     pub fn recreate_logic(&mut self, swapchain: &Swapchain) -> Result<()> {
-        self.logic.recreate_frame_buffers(swapchain)?;
-        self.logic.recreate_command_buffers(swapchain)
+        self.logic.recreate_frame_buffers(swapchain)
     }
 
+    // draws of the last frame which passed the frustum test
     #[inline]
-    pub fn current_frame(&self) -> usize {
-        self.current_frame
+    pub fn cull_stats(&self) -> CullStats {
+        self.cull_stats
     }
 
     #[inline]
@@ -151,7 +218,7 @@ impl FrameSyncObjects {
 
     pub fn wait_for_fence(&self, frame: usize) -> Result<()> {
         let fences = [self.inflight_fences[frame]];
-        unsafe { self.device.handle().wait_for_fences(&fences, true, std::u64::MAX)? }
+        unsafe { self.device.handle().wait_for_fences(&fences, true, u64::MAX)? }
         Ok(())
     }
 
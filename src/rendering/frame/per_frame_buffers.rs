@@ -0,0 +1,133 @@
+use crate::rendering::prelude::*;
+use crate::rendering::staging::{StagingAllocation, StagingRing};
+use crate::rendering::{Buffer, Device};
+
+// storage buffers with data which changes every frame, like joint matrices or morph weights,
+// so each frame in flight has its own buffer. Elements are made of a fixed number of floats
+pub struct PerFrameBuffers {
+    device: Arc<Device>,
+    name: &'static str,
+    element_size: usize,
+    element_count: usize,
+    buffers: Vec<Buffer>,
+}
+
+impl PerFrameBuffers {
+    // scenes without any elements still get buffers, so descriptor sets are always valid
+    pub fn new(
+        device: Arc<Device>,
+        name: &'static str,
+        element_size: usize,
+        max_frames_in_flight: usize,
+    ) -> Result<Self> {
+        let buffers = create_buffers(&device, max_frames_in_flight, element_size, 0)?;
+
+        Ok(Self {
+            device,
+            name,
+            element_size,
+            element_count: 0,
+            buffers,
+        })
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.buffers.iter().for_each(|buffer| buffer.destroy());
+    }
+
+    // buffers must not be used by any frame in flight, returns whether they were replaced
+    pub fn resize(&mut self, element_count: usize) -> Result<bool> {
+        if element_count == self.element_count {
+            return Ok(false);
+        }
+
+        let buffers = create_buffers(&self.device, self.buffers.len(), self.element_size, element_count)?;
+        unsafe { self.destroy() };
+        self.buffers = buffers;
+        self.element_count = element_count;
+
+        log::debug!("resized {} buffers to {} elements", self.name, element_count);
+        Ok(true)
+    }
+
+    // extra floats which don't fit the buffers are ignored
+    pub fn stage(&self, staging: &mut StagingRing, data: &[f32]) -> Result<Option<StagingAllocation>> {
+        let float_count = data
+            .len()
+            .min(self.element_count * self.element_size / std::mem::size_of::<f32>());
+        if float_count == 0 {
+            return Ok(None);
+        }
+
+        let alignment = self.device.properties().limits.optimal_buffer_copy_offset_alignment;
+        staging
+            .write(bytemuck::cast_slice(&data[..float_count]), alignment)
+            .map(Some)
+    }
+
+    pub fn record_update(&self, command_buffer: vk::CommandBuffer, current_frame: usize, data: &StagingAllocation) {
+        let device = self.device.handle();
+        let buffer = &self.buffers[current_frame];
+
+        let copy_regions = [vk::BufferCopy {
+            src_offset: data.offset,
+            dst_offset: 0,
+            size: data.size,
+        }];
+
+        let barriers = [vk::BufferMemoryBarrier::builder()
+            .src_access_mask(vk::AccessFlags::TRANSFER_WRITE)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .buffer(buffer.handle())
+            .offset(0)
+            .size(vk::WHOLE_SIZE)
+            .build()];
+
+        unsafe {
+            device.cmd_copy_buffer(command_buffer, data.buffer, buffer.handle(), &copy_regions);
+            device.cmd_pipeline_barrier(
+                command_buffer,
+                vk::PipelineStageFlags::TRANSFER,
+                vk::PipelineStageFlags::VERTEX_SHADER,
+                vk::DependencyFlags::empty(),
+                &[],
+                &barriers,
+                &[],
+            );
+        }
+    }
+
+    #[inline]
+    pub fn buffers(&self) -> &[Buffer] {
+        &self.buffers
+    }
+}
+
+fn create_buffers(
+    device: &Arc<Device>,
+    count: usize,
+    element_size: usize,
+    element_count: usize,
+) -> Result<Vec<Buffer>> {
+    let size = (element_count.max(1) * element_size) as vk::DeviceSize;
+
+    let mut buffers = Vec::with_capacity(count);
+    for _ in 0..count {
+        match Buffer::new(
+            device.clone(),
+            size,
+            vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::STORAGE_BUFFER,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) {
+            Ok(buffer) => buffers.push(buffer),
+            Err(e) => {
+                buffers.iter().for_each(|buffer| unsafe { buffer.destroy() });
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(buffers)
+}
@@ -0,0 +1,124 @@
+use super::mesh::BoundingBox;
+
+// planes point inside the frustum, xyz is the normal and w is the distance
+#[derive(Debug, Copy, Clone)]
+pub struct Frustum {
+    planes: [glm::Vec4; 6],
+}
+
+impl Frustum {
+    // planes are extracted from the rows of the combined matrix, the near plane is at zero depth
+    pub fn from_view_projection(view_projection: &glm::Mat4) -> Self {
+        let row = |index: usize| -> glm::Vec4 { view_projection.row(index).transpose() };
+        let planes = [
+            row(3) + row(0),
+            row(3) - row(0),
+            row(3) + row(1),
+            row(3) - row(1),
+            row(2),
+            row(3) - row(2),
+        ];
+
+        Self {
+            planes: [
+                normalize_plane(&planes[0]),
+                normalize_plane(&planes[1]),
+                normalize_plane(&planes[2]),
+                normalize_plane(&planes[3]),
+                normalize_plane(&planes[4]),
+                normalize_plane(&planes[5]),
+            ],
+        }
+    }
+
+    // xyz of the sphere is the center and w is the radius
+    pub fn intersects_sphere(&self, sphere: &glm::Vec4) -> bool {
+        let center = sphere.xyz();
+        self.planes
+            .iter()
+            .all(|plane| glm::dot(&plane.xyz(), &center) + plane.w >= -sphere.w)
+    }
+
+    // tests the corner which is the farthest along each plane normal
+    pub fn intersects_box(&self, bounds: &BoundingBox) -> bool {
+        self.planes.iter().all(|plane| {
+            let corner = glm::vec3(
+                if plane.x >= 0.0 { bounds.max.x } else { bounds.min.x },
+                if plane.y >= 0.0 { bounds.max.y } else { bounds.min.y },
+                if plane.z >= 0.0 { bounds.max.z } else { bounds.min.z },
+            );
+            glm::dot(&plane.xyz(), &corner) + plane.w >= 0.0
+        })
+    }
+
+    pub fn planes_data(&self) -> [[f32; 4]; 6] {
+        let mut result = [[0.0; 4]; 6];
+        for (plane, result) in self.planes.iter().zip(result.iter_mut()) {
+            result.copy_from_slice(plane.as_slice());
+        }
+        result
+    }
+}
+
+fn normalize_plane(plane: &glm::Vec4) -> glm::Vec4 {
+    plane / glm::length(&plane.xyz())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEAR: f32 = 0.1;
+    const FAR: f32 = 100.0;
+
+    // same projection as the camera, which looks down -z from the origin
+    fn frustum() -> Frustum {
+        let mut projection = glm::perspective(1.0, f32::to_radians(90.0), NEAR, FAR);
+        projection.m22 *= -1.0;
+        Frustum::from_view_projection(&projection)
+    }
+
+    #[test]
+    fn normalizes_planes() {
+        for plane in &frustum().planes_data() {
+            let length = glm::length(&glm::vec3(plane[0], plane[1], plane[2]));
+            assert!((length - 1.0).abs() < 1e-5, "{:?}", plane);
+        }
+    }
+
+    #[test]
+    fn culls_spheres_outside_of_planes() {
+        let frustum = frustum();
+
+        assert!(frustum.intersects_sphere(&glm::vec4(0.0, 0.0, -10.0, 0.5)));
+        // behind the camera and beyond the far plane
+        assert!(!frustum.intersects_sphere(&glm::vec4(0.0, 0.0, 10.0, 0.5)));
+        assert!(!frustum.intersects_sphere(&glm::vec4(0.0, 0.0, -FAR - 1.0, 0.5)));
+        // the side planes are at 45 degrees
+        assert!(!frustum.intersects_sphere(&glm::vec4(-12.0, 0.0, -10.0, 0.5)));
+        assert!(!frustum.intersects_sphere(&glm::vec4(0.0, 12.0, -10.0, 0.5)));
+        assert!(frustum.intersects_sphere(&glm::vec4(-10.5, 0.0, -10.0, 1.0)));
+    }
+
+    // gl depth maps the near plane to -1, so the zero depth plane is between near and twice its distance
+    #[test]
+    fn near_plane_is_at_zero_gl_depth() {
+        let frustum = frustum();
+        let zero_depth = 2.0 * FAR * NEAR / (FAR + NEAR);
+
+        assert!(!frustum.intersects_sphere(&glm::vec4(0.0, 0.0, -zero_depth + 0.01, 0.0)));
+        assert!(frustum.intersects_sphere(&glm::vec4(0.0, 0.0, -zero_depth - 0.01, 0.0)));
+    }
+
+    #[test]
+    fn culls_boxes_outside_of_planes() {
+        let frustum = frustum();
+        let bounds = |min: glm::Vec3, max: glm::Vec3| BoundingBox { min, max };
+
+        assert!(frustum.intersects_box(&bounds(glm::vec3(-1.0, -1.0, -11.0), glm::vec3(1.0, 1.0, -9.0))));
+        assert!(!frustum.intersects_box(&bounds(glm::vec3(-1.0, -1.0, 9.0), glm::vec3(1.0, 1.0, 11.0))));
+        assert!(!frustum.intersects_box(&bounds(glm::vec3(-14.0, -1.0, -11.0), glm::vec3(-12.0, 1.0, -9.0))));
+        // crosses the left plane
+        assert!(frustum.intersects_box(&bounds(glm::vec3(-12.0, -1.0, -11.0), glm::vec3(-9.0, 1.0, -9.0))));
+    }
+}
@@ -0,0 +1,330 @@
+use super::prelude::*;
+use super::reflection::ShaderReflection;
+use super::{shader, Device, PipelineCache, ShaderModule};
+
+pub struct GraphicsPipeline {
+    device: Arc<Device>,
+    pipeline: vk::Pipeline,
+}
+
+impl GraphicsPipeline {
+    pub fn builder(layout: vk::PipelineLayout, render_pass: vk::RenderPass) -> GraphicsPipelineBuilder {
+        GraphicsPipelineBuilder::new(layout, render_pass)
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.device.handle().destroy_pipeline(self.pipeline, None);
+        log::debug!("dropped pipeline {:?}", self.pipeline);
+    }
+
+    #[inline]
+    pub fn handle(&self) -> vk::Pipeline {
+        self.pipeline
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    Opaque,
+    Alpha,
+    #[allow(unused)]
+    Additive,
+}
+
+impl BlendMode {
+    pub fn attachment_state(self) -> vk::PipelineColorBlendAttachmentState {
+        let builder = vk::PipelineColorBlendAttachmentState::builder()
+            .color_write_mask(vk::ColorComponentFlags::all())
+            .color_blend_op(vk::BlendOp::ADD)
+            .alpha_blend_op(vk::BlendOp::ADD);
+
+        match self {
+            BlendMode::Opaque => builder
+                .blend_enable(false)
+                .src_color_blend_factor(vk::BlendFactor::ONE)
+                .dst_color_blend_factor(vk::BlendFactor::ZERO)
+                .src_alpha_blend_factor(vk::BlendFactor::ONE)
+                .dst_alpha_blend_factor(vk::BlendFactor::ZERO),
+            BlendMode::Alpha => builder
+                .blend_enable(true)
+                .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+                .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+                .src_alpha_blend_factor(vk::BlendFactor::ONE)
+                .dst_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA),
+            BlendMode::Additive => builder
+                .blend_enable(true)
+                .src_color_blend_factor(vk::BlendFactor::ONE)
+                .dst_color_blend_factor(vk::BlendFactor::ONE)
+                .src_alpha_blend_factor(vk::BlendFactor::ONE)
+                .dst_alpha_blend_factor(vk::BlendFactor::ONE),
+        }
+        .build()
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct DepthState {
+    pub test: bool,
+    pub write: bool,
+    pub compare_op: vk::CompareOp,
+}
+
+impl Default for DepthState {
+    fn default() -> Self {
+        Self {
+            test: true,
+            write: true,
+            compare_op: vk::CompareOp::LESS_OR_EQUAL,
+        }
+    }
+}
+
+impl DepthState {
+    #[allow(unused)]
+    pub fn disabled() -> Self {
+        Self {
+            test: false,
+            write: false,
+            compare_op: vk::CompareOp::ALWAYS,
+        }
+    }
+
+    pub fn read_only() -> Self {
+        Self {
+            write: false,
+            ..Default::default()
+        }
+    }
+}
+
+// defaults describe an opaque, depth tested, back face culled triangle list pipeline
+// with dynamic viewport and scissor
+pub struct GraphicsPipelineBuilder {
+    layout: vk::PipelineLayout,
+    render_pass: vk::RenderPass,
+    subpass: u32,
+    stages: Vec<(vk::ShaderModule, ShaderReflection)>,
+    vertex_bindings: Vec<vk::VertexInputBindingDescription>,
+    vertex_attributes: Vec<vk::VertexInputAttributeDescription>,
+    topology: vk::PrimitiveTopology,
+    polygon_mode: vk::PolygonMode,
+    cull_mode: vk::CullModeFlags,
+    front_face: vk::FrontFace,
+    line_width: f32,
+    samples: vk::SampleCountFlags,
+    depth: DepthState,
+    blend_attachments: Vec<vk::PipelineColorBlendAttachmentState>,
+    dynamic_states: Vec<vk::DynamicState>,
+}
+
+impl GraphicsPipelineBuilder {
+    pub fn new(layout: vk::PipelineLayout, render_pass: vk::RenderPass) -> Self {
+        Self {
+            layout,
+            render_pass,
+            subpass: 0,
+            stages: Vec::new(),
+            vertex_bindings: Vec::new(),
+            vertex_attributes: Vec::new(),
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::BACK,
+            front_face: vk::FrontFace::CLOCKWISE,
+            line_width: 1.0,
+            samples: vk::SampleCountFlags::TYPE_1,
+            depth: Default::default(),
+            blend_attachments: vec![BlendMode::Opaque.attachment_state()],
+            dynamic_states: vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR],
+        }
+    }
+
+    pub fn subpass(mut self, subpass: u32) -> Self {
+        self.subpass = subpass;
+        self
+    }
+
+    // the stage is taken from the module reflection and replaces a previously set module of that stage
+    pub fn shader(mut self, module: &ShaderModule) -> Self {
+        self.stages.retain(|(_, reflection)| reflection.stage != module.stage());
+        self.stages.push((module.handle(), module.reflection().clone()));
+        self.stages.sort_by_key(|(_, reflection)| reflection.stage.as_raw());
+        self
+    }
+
+    pub fn vertex_layout(
+        mut self,
+        bindings: &[vk::VertexInputBindingDescription],
+        attributes: &[vk::VertexInputAttributeDescription],
+    ) -> Self {
+        self.vertex_bindings = bindings.to_vec();
+        self.vertex_attributes = attributes.to_vec();
+        self
+    }
+
+    pub fn topology(mut self, topology: vk::PrimitiveTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    #[allow(unused)]
+    pub fn polygon_mode(mut self, polygon_mode: vk::PolygonMode) -> Self {
+        self.polygon_mode = polygon_mode;
+        self
+    }
+
+    pub fn cull_mode(mut self, cull_mode: vk::CullModeFlags, front_face: vk::FrontFace) -> Self {
+        self.cull_mode = cull_mode;
+        self.front_face = front_face;
+        self
+    }
+
+    #[allow(unused)]
+    pub fn line_width(mut self, line_width: f32) -> Self {
+        self.line_width = line_width;
+        self
+    }
+
+    #[allow(unused)]
+    pub fn samples(mut self, samples: vk::SampleCountFlags) -> Self {
+        self.samples = samples;
+        self
+    }
+
+    pub fn depth(mut self, depth: DepthState) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    // sets the same blend mode for the specified number of color attachments
+    pub fn blend(mut self, blend_mode: BlendMode, color_attachment_count: usize) -> Self {
+        self.blend_attachments = vec![blend_mode.attachment_state(); color_attachment_count];
+        self
+    }
+
+    #[allow(unused)]
+    pub fn blend_attachments(mut self, attachments: &[vk::PipelineColorBlendAttachmentState]) -> Self {
+        self.blend_attachments = attachments.to_vec();
+        self
+    }
+
+    pub fn dynamic_states(mut self, dynamic_states: &[vk::DynamicState]) -> Self {
+        self.dynamic_states = dynamic_states.to_vec();
+        self
+    }
+
+    pub fn build(&self, device: Arc<Device>, pipeline_cache: &PipelineCache) -> Result<GraphicsPipeline> {
+        if self.stages.is_empty() {
+            return Err(Error::msg("graphics pipeline has no shader stages"));
+        }
+        self.validate_stages()?;
+
+        let main_function_name = shader::main_function_name();
+
+        let shader_stages = self
+            .stages
+            .iter()
+            .map(|(module, reflection)| {
+                vk::PipelineShaderStageCreateInfo::builder()
+                    .module(*module)
+                    .name(main_function_name)
+                    .stage(reflection.stage)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+
+        let vertex_input_state_create_info = vk::PipelineVertexInputStateCreateInfo::builder()
+            .vertex_binding_descriptions(&self.vertex_bindings)
+            .vertex_attribute_descriptions(&self.vertex_attributes);
+
+        let input_assembly_state_create_info = vk::PipelineInputAssemblyStateCreateInfo::builder()
+            .primitive_restart_enable(false)
+            .topology(self.topology);
+
+        // viewport and scissor are expected to be dynamic, only their count matters here
+        let viewports = [vk::Viewport::builder().build()];
+        let scissors = [vk::Rect2D::builder().build()];
+
+        let viewport_state_create_info = vk::PipelineViewportStateCreateInfo::builder()
+            .scissors(&scissors)
+            .viewports(&viewports);
+
+        let rasterization_state_create_info = vk::PipelineRasterizationStateCreateInfo::builder()
+            .cull_mode(self.cull_mode)
+            .front_face(self.front_face)
+            .line_width(self.line_width)
+            .polygon_mode(self.polygon_mode);
+
+        let multisample_state_create_info =
+            vk::PipelineMultisampleStateCreateInfo::builder().rasterization_samples(self.samples);
+
+        let stencil_state = vk::StencilOpState::builder()
+            .fail_op(vk::StencilOp::KEEP)
+            .pass_op(vk::StencilOp::KEEP)
+            .depth_fail_op(vk::StencilOp::KEEP)
+            .compare_op(vk::CompareOp::ALWAYS)
+            .build();
+
+        let depth_stencil_state_create_info = vk::PipelineDepthStencilStateCreateInfo::builder()
+            .depth_test_enable(self.depth.test)
+            .depth_write_enable(self.depth.write)
+            .depth_compare_op(self.depth.compare_op)
+            .depth_bounds_test_enable(false)
+            .stencil_test_enable(false)
+            .front(stencil_state)
+            .back(stencil_state);
+
+        let color_blend_state = vk::PipelineColorBlendStateCreateInfo::builder()
+            .logic_op_enable(false)
+            .logic_op(vk::LogicOp::COPY)
+            .attachments(&self.blend_attachments);
+
+        let dynamic_state_create_info =
+            vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&self.dynamic_states);
+
+        let graphics_pipeline_create_infos = [vk::GraphicsPipelineCreateInfo::builder()
+            .stages(&shader_stages)
+            .vertex_input_state(&vertex_input_state_create_info)
+            .input_assembly_state(&input_assembly_state_create_info)
+            .viewport_state(&viewport_state_create_info)
+            .rasterization_state(&rasterization_state_create_info)
+            .multisample_state(&multisample_state_create_info)
+            .depth_stencil_state(&depth_stencil_state_create_info)
+            .color_blend_state(&color_blend_state)
+            .layout(self.layout)
+            .render_pass(self.render_pass)
+            .subpass(self.subpass)
+            .dynamic_state(&dynamic_state_create_info)
+            .base_pipeline_handle(vk::Pipeline::null())
+            .base_pipeline_index(-1)
+            .build()];
+
+        let pipeline = unsafe {
+            device
+                .handle()
+                .create_graphics_pipelines(pipeline_cache.handle(), &graphics_pipeline_create_infos, None)
+                .map_err(|(_, e)| e)?[0]
+        };
+        log::debug!("created graphics pipeline {:?}", pipeline);
+
+        Ok(GraphicsPipeline { device, pipeline })
+    }
+
+    fn validate_stages(&self) -> Result<()> {
+        for (_, reflection) in &self.stages {
+            match reflection.stage {
+                vk::ShaderStageFlags::VERTEX => reflection.check_vertex_input(&self.vertex_attributes)?,
+                vk::ShaderStageFlags::COMPUTE => {
+                    return Err(Error::msg("compute shader can't be used in a graphics pipeline"))
+                }
+                _ => {}
+            }
+        }
+
+        // stages are sorted in pipeline order
+        for pair in self.stages.windows(2) {
+            pair[0].1.check_interface(&pair[1].1)?;
+        }
+
+        Ok(())
+    }
+}
@@ -93,6 +93,28 @@ impl ImageView {
         format: vk::Format,
         aspect_flags: vk::ImageAspectFlags,
         mip_levels: u32,
+    ) -> Result<Self> {
+        Self::with_mip_range(device, image, format, aspect_flags, 0, mip_levels)
+    }
+
+    // view of a single mip level, e.g. to write it from a compute shader
+    pub fn for_mip_level(
+        device: Arc<Device>,
+        image: &Image,
+        format: vk::Format,
+        aspect_flags: vk::ImageAspectFlags,
+        mip_level: u32,
+    ) -> Result<Self> {
+        Self::with_mip_range(device, image.handle(), format, aspect_flags, mip_level, 1)
+    }
+
+    fn with_mip_range(
+        device: Arc<Device>,
+        image: vk::Image,
+        format: vk::Format,
+        aspect_flags: vk::ImageAspectFlags,
+        base_mip_level: u32,
+        level_count: u32,
     ) -> Result<Self> {
         let image_view_create_info = vk::ImageViewCreateInfo::builder()
             .view_type(vk::ImageViewType::TYPE_2D)
@@ -105,8 +127,8 @@ impl ImageView {
             })
             .subresource_range(vk::ImageSubresourceRange {
                 aspect_mask: aspect_flags,
-                base_mip_level: 0,
-                level_count: mip_levels,
+                base_mip_level,
+                level_count,
                 base_array_layer: 0,
                 layer_count: 1,
             })
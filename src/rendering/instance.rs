@@ -5,6 +5,7 @@ pub const APPLICATION_NAME: &str = "vrs";
 pub const ENGINE_TITLE: &str = "ash";
 pub const APPLICATION_VERSION: u32 = vk::make_version(1, 0, 0);
 pub const ENGINE_VERSION: u32 = vk::make_version(1, 0, 0);
+pub const API_VERSION: u32 = vk::make_version(1, 1, 0);
 
 pub struct Instance {
     instance: ash::Instance,
@@ -23,7 +24,8 @@ impl Instance {
             .application_name(&application_name)
             .engine_name(&engine_name)
             .application_version(APPLICATION_VERSION)
-            .engine_version(ENGINE_VERSION);
+            .engine_version(ENGINE_VERSION)
+            .api_version(API_VERSION);
 
         //
         let mut required_extensions = ash_window::enumerate_required_extensions(window)?;
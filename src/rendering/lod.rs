@@ -0,0 +1,139 @@
+use super::prelude::*;
+use super::vertex::VertexData;
+
+pub const MAX_LOD_COUNT: usize = 4;
+
+// a lod is used while the projected size of the bounding sphere is below its threshold,
+// the size is the radius relative to the half of the screen height
+const LOD_SCREEN_SIZES: [f32; MAX_LOD_COUNT - 1] = [0.25, 0.12, 0.06];
+// the size must cross a threshold by this fraction before the lod changes, so it doesn't flicker at the boundary
+const LOD_HYSTERESIS: f32 = 0.15;
+
+// each lod aims at half of the triangles of the previous one
+const LOD_REDUCTION: f32 = 0.5;
+// lods which remove fewer triangles are not worth the memory
+const MIN_LOD_REDUCTION: f32 = 0.8;
+// relative to the mesh extents, grows with every lod
+const LOD_TARGET_ERROR: f32 = 0.01;
+
+// range of the shared index buffer
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct MeshLod {
+    pub first_index: u32,
+    pub index_count: u32,
+}
+
+// simplified index lists referencing the original vertices, the full mesh is not included
+pub fn generate_lods(vertices: &VertexData, indices: &[u32]) -> Result<Vec<Vec<u32>>> {
+    let vertex_data = vertices.position_adapter()?;
+
+    let mut result: Vec<Vec<u32>> = Vec::new();
+    let mut target_error = LOD_TARGET_ERROR;
+
+    while result.len() + 1 < MAX_LOD_COUNT {
+        let previous = result.last().map(Vec::as_slice).unwrap_or(indices);
+        let target_count = ((previous.len() as f32 * LOD_REDUCTION) as usize / 3) * 3;
+        if target_count < 3 {
+            break;
+        }
+
+        let simplified = meshopt::simplify(previous, &vertex_data, target_count, target_error);
+        if simplified.is_empty() || simplified.len() as f32 > previous.len() as f32 * MIN_LOD_REDUCTION {
+            break;
+        }
+
+        result.push(meshopt::optimize_vertex_cache(&simplified, vertices.len()));
+        target_error *= 2.0;
+    }
+
+    Ok(result)
+}
+
+// picks lods by the projected size of the bounding spheres
+#[derive(Debug, Copy, Clone)]
+pub struct LodSelector {
+    camera_position: glm::Vec3,
+    projection_scale: f32,
+}
+
+impl LodSelector {
+    pub fn new(view: &glm::Mat4, projection: &glm::Mat4) -> Self {
+        let camera_position = glm::inverse(view).column(3).xyz();
+
+        Self {
+            camera_position,
+            projection_scale: projection[(1, 1)].abs(),
+        }
+    }
+
+    // xyz of the sphere is the center and w is the radius
+    pub fn screen_size(&self, sphere: &glm::Vec4) -> f32 {
+        let distance = glm::distance(&self.camera_position, &sphere.xyz());
+        if distance <= sphere.w {
+            return f32::INFINITY;
+        }
+        sphere.w * self.projection_scale / distance
+    }
+
+    // moves from the current lod only when the size is far enough from the threshold between them
+    pub fn select(&self, sphere: &glm::Vec4, current_lod: usize, lod_count: usize) -> usize {
+        let size = self.screen_size(sphere);
+        let mut lod = current_lod.min(lod_count.saturating_sub(1));
+
+        while lod + 1 < lod_count && size < LOD_SCREEN_SIZES[lod] * (1.0 - LOD_HYSTERESIS) {
+            lod += 1;
+        }
+        while lod > 0 && size > LOD_SCREEN_SIZES[lod - 1] * (1.0 + LOD_HYSTERESIS) {
+            lod -= 1;
+        }
+
+        lod
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the projection scale is one with a 90 degree field of view, so the size is the radius over the distance
+    fn selector() -> LodSelector {
+        let projection = glm::perspective(1.0, f32::to_radians(90.0), 0.1, 100.0);
+        LodSelector::new(&glm::identity(), &projection)
+    }
+
+    fn sphere(size: f32) -> glm::Vec4 {
+        glm::vec4(0.0, 0.0, -10.0, size * 10.0)
+    }
+
+    #[test]
+    fn projects_sphere_size() {
+        let selector = selector();
+        assert!((selector.screen_size(&sphere(0.1)) - 0.1).abs() < 1e-5);
+        assert_eq!(selector.screen_size(&glm::vec4(0.0, 0.0, -1.0, 2.0)), f32::INFINITY);
+    }
+
+    #[test]
+    fn keeps_lod_within_hysteresis() {
+        let selector = selector();
+
+        // just below and above the threshold between the first two lods
+        assert_eq!(selector.select(&sphere(0.24), 0, 4), 0);
+        assert_eq!(selector.select(&sphere(0.26), 1, 4), 1);
+
+        // past the hysteresis band
+        assert_eq!(selector.select(&sphere(0.2), 0, 4), 1);
+        assert_eq!(selector.select(&sphere(0.3), 1, 4), 0);
+    }
+
+    #[test]
+    fn skips_several_lods() {
+        let selector = selector();
+
+        assert_eq!(selector.select(&sphere(0.01), 0, 4), 3);
+        assert_eq!(selector.select(&sphere(1.0), 3, 4), 0);
+        // limited by the lods of the mesh
+        assert_eq!(selector.select(&sphere(0.01), 0, 2), 1);
+        assert_eq!(selector.select(&sphere(0.01), 3, 2), 1);
+        assert_eq!(selector.select(&sphere(0.01), 0, 1), 0);
+    }
+}
@@ -0,0 +1,109 @@
+use super::{BlendMode, ShaderFeatures};
+
+// texture index of materials without the texture
+pub const NO_TEXTURE: u32 = u32::MAX;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum AlphaMode {
+    #[default]
+    Opaque,
+    Mask,
+    Blend,
+}
+
+// textures are referenced by the index of the image in the scene, all of them share one sampler,
+// each texture reads the uv set given by its tex coord
+#[derive(Debug, Clone)]
+pub struct Material {
+    pub base_color_factor: [f32; 4],
+    pub alpha_mode: AlphaMode,
+    pub alpha_cutoff: f32,
+    // back faces are drawn too, lit with the flipped normal
+    pub double_sided: bool,
+    pub base_color_texture: Option<usize>,
+    pub base_color_tex_coord: u32,
+    pub normal_texture: Option<usize>,
+    pub normal_tex_coord: u32,
+}
+
+impl Default for Material {
+    fn default() -> Self {
+        Self {
+            base_color_factor: [1.0; 4],
+            alpha_mode: AlphaMode::Opaque,
+            alpha_cutoff: 0.5,
+            double_sided: false,
+            base_color_texture: None,
+            base_color_tex_coord: 0,
+            normal_texture: None,
+            normal_tex_coord: 0,
+        }
+    }
+}
+
+impl Material {
+    pub fn from_gltf(material: &gltf::Material) -> Self {
+        let pbr = material.pbr_metallic_roughness();
+
+        Self {
+            base_color_factor: pbr.base_color_factor(),
+            alpha_mode: match material.alpha_mode() {
+                gltf::material::AlphaMode::Opaque => AlphaMode::Opaque,
+                gltf::material::AlphaMode::Mask => AlphaMode::Mask,
+                gltf::material::AlphaMode::Blend => AlphaMode::Blend,
+            },
+            alpha_cutoff: material.alpha_cutoff(),
+            double_sided: material.double_sided(),
+            base_color_texture: pbr.base_color_texture().map(|info| info.texture().source().index()),
+            base_color_tex_coord: pbr.base_color_texture().map_or(0, |info| info.tex_coord()),
+            normal_texture: material.normal_texture().map(|info| info.texture().source().index()),
+            normal_tex_coord: material.normal_texture().map_or(0, |info| info.tex_coord()),
+        }
+    }
+
+    pub fn features(&self) -> ShaderFeatures {
+        let mut features = ShaderFeatures::empty();
+        features.set(ShaderFeatures::BASE_COLOR_TEXTURE, self.base_color_texture.is_some());
+        features.set(ShaderFeatures::NORMAL_MAP, self.normal_texture.is_some());
+        features.set(ShaderFeatures::ALPHA_MASK, self.alpha_mode == AlphaMode::Mask);
+        features
+    }
+
+    // blended materials are drawn after opaque ones without writing depth
+    pub fn blend_mode(&self) -> BlendMode {
+        match self.alpha_mode {
+            AlphaMode::Opaque | AlphaMode::Mask => BlendMode::Opaque,
+            AlphaMode::Blend => BlendMode::Alpha,
+        }
+    }
+
+    pub fn data(&self) -> MaterialData {
+        let texture_index = |texture: Option<usize>| texture.map(|index| index as u32).unwrap_or(NO_TEXTURE);
+
+        MaterialData {
+            base_color_factor: self.base_color_factor,
+            base_color_texture: texture_index(self.base_color_texture),
+            normal_texture: texture_index(self.normal_texture),
+            alpha_cutoff: self.alpha_cutoff,
+            base_color_tex_coord: self.base_color_tex_coord,
+            normal_tex_coord: self.normal_tex_coord,
+            padding: [0; 3],
+        }
+    }
+}
+
+// material layout in the storage buffer, must match `MaterialData` in shaders
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct MaterialData {
+    pub base_color_factor: [f32; 4],
+    pub base_color_texture: u32,
+    pub normal_texture: u32,
+    pub alpha_cutoff: f32,
+    pub base_color_tex_coord: u32,
+    pub normal_tex_coord: u32,
+    pub padding: [u32; 3],
+}
+
+unsafe impl bytemuck::Pod for MaterialData {}
+unsafe impl bytemuck::Zeroable for MaterialData {}
@@ -1,94 +1,313 @@
+use std::collections::HashMap;
+
+use super::lod::{self, MeshLod};
 use super::prelude::*;
-use super::{Buffer, CommandPool, Device};
+use super::vertex::{MorphDelta, VertexAttribute, VertexData, VertexLayout, VertexStorage};
+use super::{Buffer, Device, ShaderFeatures, UploadManager};
+
+// bounds are in world space and are computed once when the instance is placed,
+// skinned instances recompute them from their joints every frame
+#[derive(Debug, Clone)]
+pub struct MeshInstance {
+    pub mesh: usize,
+    pub transform: glm::Mat4,
+    pub bounds: BoundingBox,
+    pub bounding_sphere: glm::Vec4,
+    pub joints: Option<JointRange>,
+    // first weight of the morph targets of the mesh in the weights of all instances
+    pub morph_weight_offset: Option<u32>,
+}
 
-#[repr(C)]
-#[derive(Debug, Clone, Copy)]
-pub struct Vertex {
-    pub position: [f32; 3],
-    pub normal: [f32; 3],
+impl MeshInstance {
+    pub fn new(mesh: usize, transform: glm::Mat4, mesh_bounds: &BoundingBox) -> Self {
+        Self {
+            mesh,
+            bounds: mesh_bounds.transformed(&transform),
+            bounding_sphere: mesh_bounds.bounding_sphere(&transform),
+            transform,
+            joints: None,
+            morph_weight_offset: None,
+        }
+    }
+
+    // moves the instance, joints and morph weights stay assigned
+    pub fn set_transform(&mut self, transform: glm::Mat4, mesh_bounds: &BoundingBox) {
+        self.bounds = mesh_bounds.transformed(&transform);
+        self.bounding_sphere = mesh_bounds.bounding_sphere(&transform);
+        self.transform = transform;
+    }
 }
 
-unsafe impl bytemuck::Pod for Vertex {}
-unsafe impl bytemuck::Zeroable for Vertex {}
-
-impl Vertex {
-    pub fn get_binding_descriptions() -> [vk::VertexInputBindingDescription; 1] {
-        [vk::VertexInputBindingDescription {
-            binding: 0,
-            stride: std::mem::size_of::<Self>() as u32,
-            input_rate: vk::VertexInputRate::VERTEX,
-        }]
-    }
-
-    pub fn get_attribute_descriptions() -> [vk::VertexInputAttributeDescription; 2] {
-        [
-            vk::VertexInputAttributeDescription {
-                location: 0,
-                binding: 0,
-                format: vk::Format::R32G32B32_SFLOAT,
-                offset: offset_of!(Self, position) as u32,
-            },
-            vk::VertexInputAttributeDescription {
-                location: 1,
-                binding: 0,
-                format: vk::Format::R32G32B32_SFLOAT,
-                offset: offset_of!(Self, normal) as u32,
-            },
-        ]
+// joint matrices of a skinned instance, a range of the matrices of all skins
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct JointRange {
+    pub offset: u32,
+    pub count: u32,
+}
+
+impl JointRange {
+    #[inline]
+    pub fn end(&self) -> u32 {
+        self.offset + self.count
+    }
+}
+
+// axis aligned box, either in mesh or in world space
+#[derive(Debug, Copy, Clone)]
+pub struct BoundingBox {
+    pub min: glm::Vec3,
+    pub max: glm::Vec3,
+}
+
+impl BoundingBox {
+    pub fn from_positions(positions: &[[f32; 3]]) -> Self {
+        let mut positions = positions.iter().map(|position| glm::make_vec3(position));
+        let first = positions.next().unwrap_or_else(glm::Vec3::zeros);
+
+        positions.fold(Self { min: first, max: first }, |bounds, position| Self {
+            min: glm::min2(&bounds.min, &position),
+            max: glm::max2(&bounds.max, &position),
+        })
+    }
+
+    // box around the vertices displaced by any combination of morph target weights between 0 and 1
+    pub fn with_morph_targets(&self, deltas: &[MorphDelta], target_count: usize) -> Self {
+        if target_count == 0 {
+            return *self;
+        }
+
+        let (mut grow_min, mut grow_max) = (glm::Vec3::zeros(), glm::Vec3::zeros());
+        for target in 0..target_count {
+            let (mut target_min, mut target_max) = (glm::Vec3::zeros(), glm::Vec3::zeros());
+            for delta in deltas.iter().skip(target).step_by(target_count) {
+                let position = glm::make_vec3(&delta.position[..3]);
+                target_min = glm::min2(&target_min, &position);
+                target_max = glm::max2(&target_max, &position);
+            }
+            grow_min += target_min;
+            grow_max += target_max;
+        }
+
+        Self {
+            min: self.min + grow_min,
+            max: self.max + grow_max,
+        }
+    }
+
+    pub fn union(&self, other: &Self) -> Self {
+        Self {
+            min: glm::min2(&self.min, &other.min),
+            max: glm::max2(&self.max, &other.max),
+        }
+    }
+
+    // box around the transformed corners, computed from the absolute values of the rotation and scale
+    pub fn transformed(&self, transform: &glm::Mat4) -> Self {
+        let center = (self.min + self.max) * 0.5;
+        let half_extent = (self.max - self.min) * 0.5;
+
+        let center = transform * glm::vec4(center.x, center.y, center.z, 1.0);
+        let basis = glm::abs(&glm::mat4_to_mat3(transform));
+        let half_extent = basis * half_extent;
+
+        Self {
+            min: center.xyz() - half_extent,
+            max: center.xyz() + half_extent,
+        }
+    }
+
+    // sphere around the transformed box, xyz is the center and w is the radius
+    pub fn bounding_sphere(&self, transform: &glm::Mat4) -> glm::Vec4 {
+        let center = (self.min + self.max) * 0.5;
+        let center = transform * glm::vec4(center.x, center.y, center.z, 1.0);
+
+        // the sphere is scaled by the largest axis scale of the transform
+        let scale = (0..3)
+            .map(|axis| {
+                glm::length(&glm::vec3(
+                    transform[(0, axis)],
+                    transform[(1, axis)],
+                    transform[(2, axis)],
+                ))
+            })
+            .fold(0.0, f32::max);
+        let radius = glm::distance(&self.min, &self.max) * 0.5 * scale;
+
+        glm::vec4(center.x, center.y, center.z, radius)
     }
 }
 
+// range of the shared geometry buffers, all lods share the same vertices
+#[derive(Debug, Clone)]
 pub struct Mesh {
-    index_count: u32,
-    vertex_buffer: Buffer,
-    index_buffer: Buffer,
+    material: usize,
+    features: ShaderFeatures,
+    vertex_layout: VertexLayout,
+    bounds: BoundingBox,
+    lods: Vec<MeshLod>,
+    vertex_offset: i32,
+    morph_target_count: u32,
+    morph_delta_offset: u32,
 }
 
 impl Mesh {
-    pub fn new(device: Arc<Device>, command_pool: &CommandPool, vertices: &[Vertex], indices: &[u16]) -> Result<Self> {
-        let vertex_buffer_size = std::mem::size_of_val(vertices) as vk::DeviceSize;
-        let index_buffer_size = std::mem::size_of_val(indices) as vk::DeviceSize;
-        let staging_buffer_size = vertex_buffer_size + index_buffer_size;
+    #[inline]
+    pub fn material(&self) -> usize {
+        self.material
+    }
 
-        // create staging buffer
-        let staging_buffer = Buffer::new(
-            device.clone(),
-            staging_buffer_size,
-            vk::BufferUsageFlags::TRANSFER_SRC,
-            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
-        )?;
+    // features of the vertex data which affect the shader permutation
+    #[inline]
+    pub fn features(&self) -> ShaderFeatures {
+        self.features
+    }
 
-        // write data to staging buffer
-        unsafe {
-            let data_ptr = device.handle().map_memory(
-                staging_buffer.memory().handle(),
-                0,
-                staging_buffer_size,
-                vk::MemoryMapFlags::empty(),
-            )? as *mut u8;
+    // selects the vertex buffer and the vertex inputs of the pipeline
+    #[inline]
+    pub fn vertex_layout(&self) -> VertexLayout {
+        self.vertex_layout
+    }
 
-            let vertices_data = bytemuck::cast_slice(vertices);
-            data_ptr
-                .offset(0)
-                .copy_from_nonoverlapping(vertices_data.as_ptr(), vertices_data.len());
+    #[inline]
+    pub fn bounds(&self) -> &BoundingBox {
+        &self.bounds
+    }
 
-            let indices_data = bytemuck::cast_slice(indices);
-            data_ptr
-                .add(vertices_data.len())
-                .copy_from_nonoverlapping(indices_data.as_ptr(), indices_data.len());
+    // the first lod is the full mesh, each next one is simpler
+    #[inline]
+    pub fn lods(&self) -> &[MeshLod] {
+        &self.lods
+    }
 
-            assert_eq!(staging_buffer_size as usize, vertices_data.len() + indices_data.len());
+    #[inline]
+    pub fn vertex_offset(&self) -> i32 {
+        self.vertex_offset
+    }
 
-            device.handle().unmap_memory(staging_buffer.memory().handle());
+    #[inline]
+    pub fn morph_target_count(&self) -> u32 {
+        self.morph_target_count
+    }
+
+    // first delta of the mesh in the morph delta buffer, deltas are ordered by vertex and then by target
+    #[inline]
+    pub fn morph_delta_offset(&self) -> u32 {
+        self.morph_delta_offset
+    }
+}
+
+// collects geometry of all meshes to upload it into shared buffers,
+// vertices are grouped by layout and indices of all layouts share one buffer
+#[derive(Default)]
+pub struct GeometryBuilder {
+    storage: VertexStorage,
+    vertices: HashMap<VertexLayout, VertexPool>,
+    indices: Vec<u32>,
+    morph_deltas: Vec<MorphDelta>,
+}
+
+impl GeometryBuilder {
+    pub fn new(storage: VertexStorage) -> Self {
+        Self {
+            storage,
+            ..Self::default()
         }
+    }
 
-        // create vertex buffer
-        let vertex_buffer = Buffer::new(
-            device.clone(),
-            vertex_buffer_size,
-            vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::VERTEX_BUFFER,
-            vk::MemoryPropertyFlags::DEVICE_LOCAL,
-        )?;
+    // indices stay relative to the mesh, the vertex offset is applied by the draw
+    pub fn add_mesh(&mut self, vertices: &VertexData, indices: &[u32], material: usize) -> Result<Mesh> {
+        let simplified = lod::generate_lods(vertices, indices)?;
+
+        let mut lods = Vec::with_capacity(simplified.len() + 1);
+        for indices in std::iter::once(indices).chain(simplified.iter().map(Vec::as_slice)) {
+            lods.push(MeshLod {
+                first_index: self.indices.len() as u32,
+                index_count: indices.len() as u32,
+            });
+            self.indices.extend_from_slice(indices);
+        }
+
+        let vertex_layout = vertices.layout(self.storage);
+        let pool = self
+            .vertices
+            .entry(vertex_layout)
+            .or_insert_with(|| VertexPool::new(vertex_layout));
+
+        // skinning needs both attributes, the instance also has to have a skin
+        let mut features = ShaderFeatures::empty();
+        features.set(
+            ShaderFeatures::SKINNED,
+            vertex_layout.contains(VertexAttribute::Joints0) && vertex_layout.contains(VertexAttribute::Weights0),
+        );
+        features.set(ShaderFeatures::MORPH_TARGETS, !vertices.morph_targets.is_empty());
+
+        let morph_deltas = vertices.morph_deltas();
+        let target_count = vertices.morph_targets.len();
+
+        let mesh = Mesh {
+            material,
+            features,
+            vertex_layout,
+            bounds: BoundingBox::from_positions(&vertices.positions).with_morph_targets(&morph_deltas, target_count),
+            lods,
+            vertex_offset: pool.vertex_count as i32,
+            morph_target_count: target_count as u32,
+            morph_delta_offset: self.morph_deltas.len() as u32,
+        };
+        self.morph_deltas.extend(morph_deltas);
+        vertices.write(vertex_layout, &mut pool.bindings);
+        pool.vertex_count += vertices.len();
+
+        Ok(mesh)
+    }
+
+    pub fn build(self, device: Arc<Device>, uploader: &mut UploadManager) -> Result<GeometryBuffers> {
+        GeometryBuffers::new(device, uploader, &self.vertices, &self.indices, &self.morph_deltas)
+    }
+}
+
+// vertices of all meshes with the same layout, one byte vector per binding
+pub struct VertexPool {
+    vertex_count: usize,
+    bindings: Vec<Vec<u8>>,
+}
+
+impl VertexPool {
+    fn new(layout: VertexLayout) -> Self {
+        Self {
+            vertex_count: 0,
+            bindings: vec![Vec::new(); layout.binding_count()],
+        }
+    }
+}
+
+// buffers and offsets of all bindings of a layout, as they are passed to vkCmdBindVertexBuffers
+#[derive(Debug, Clone, Default)]
+pub struct VertexBindings {
+    pub buffers: Vec<vk::Buffer>,
+    pub offsets: Vec<vk::DeviceSize>,
+}
+
+// vertex buffers of each layout and the index buffer shared by all meshes of the scene,
+// morph deltas are read from a storage buffer by the vertex shader
+pub struct GeometryBuffers {
+    vertex_buffers: HashMap<VertexLayout, (Buffer, Vec<vk::DeviceSize>)>,
+    index_buffer: Buffer,
+    morph_delta_buffer: Buffer,
+}
+
+impl GeometryBuffers {
+    pub const INDEX_TYPE: vk::IndexType = vk::IndexType::UINT32;
+
+    pub fn new(
+        device: Arc<Device>,
+        uploader: &mut UploadManager,
+        vertices: &HashMap<VertexLayout, VertexPool>,
+        indices: &[u32],
+        morph_deltas: &[MorphDelta],
+    ) -> Result<Self> {
+        // empty scenes still get a valid index buffer
+        let index_buffer_size = std::mem::size_of_val(indices).max(1) as vk::DeviceSize;
 
         // create index buffer
         let index_buffer = Buffer::new(
@@ -98,115 +317,108 @@ impl Mesh {
             vk::MemoryPropertyFlags::DEVICE_LOCAL,
         )?;
 
-        // copy data from staging to vertex buffer
-        let allocate_info = vk::CommandBufferAllocateInfo::builder()
-            .command_pool(command_pool.handle())
-            .command_buffer_count(1)
-            .level(vk::CommandBufferLevel::PRIMARY);
-
-        let command_buffers = unsafe { device.handle().allocate_command_buffers(&allocate_info)? };
-        let command_buffer = command_buffers[0];
-
-        unsafe {
-            let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
-            device.handle().begin_command_buffer(command_buffer, &begin_info)?;
-
-            let copy_regions = [vk::BufferCopy {
-                src_offset: 0,
-                dst_offset: 0,
-                size: vertex_buffer_size,
-            }];
-            device.handle().cmd_copy_buffer(
-                command_buffer,
-                staging_buffer.handle(),
-                vertex_buffer.handle(),
-                &copy_regions,
-            );
-
-            let copy_regions = [vk::BufferCopy {
-                src_offset: vertex_buffer_size,
-                dst_offset: 0,
-                size: index_buffer_size,
-            }];
-            device.handle().cmd_copy_buffer(
-                command_buffer,
-                staging_buffer.handle(),
-                index_buffer.handle(),
-                &copy_regions,
-            );
-
-            device.handle().end_command_buffer(command_buffer)?;
-        }
-
-        let submit_info = [vk::SubmitInfo::builder().command_buffers(&command_buffers).build()];
+        // scenes without morph targets still get a buffer, so descriptor sets are always valid
+        let morph_delta_buffer = match Buffer::new(
+            device.clone(),
+            std::mem::size_of_val(morph_deltas).max(std::mem::size_of::<MorphDelta>()) as vk::DeviceSize,
+            vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::STORAGE_BUFFER,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        ) {
+            Ok(buffer) => buffer,
+            Err(e) => {
+                unsafe { index_buffer.destroy() };
+                return Err(e);
+            }
+        };
+
+        let mut result = Self {
+            vertex_buffers: HashMap::with_capacity(vertices.len()),
+            index_buffer,
+            morph_delta_buffer,
+        };
 
-        unsafe {
-            device
-                .handle()
-                .queue_submit(device.queues().graphics_queue, &submit_info, vk::Fence::null())?;
+        // schedule data upload
+        if let Err(e) = result.create_vertex_buffers(&device, uploader, vertices) {
+            unsafe { result.destroy() };
+            return Err(e);
         }
-
-        device.wait_idle()?;
-
-        unsafe {
-            device
-                .handle()
-                .free_command_buffers(command_pool.handle(), &command_buffers);
+        if let Err(e) = uploader.upload_buffer(&result.index_buffer, 0, bytemuck::cast_slice(indices)) {
+            unsafe { result.destroy() };
+            return Err(e);
+        }
+        if let Err(e) = uploader.upload_buffer(&result.morph_delta_buffer, 0, bytemuck::cast_slice(morph_deltas)) {
+            unsafe { result.destroy() };
+            return Err(e);
         }
 
-        // destroy staging buffer
-        unsafe { staging_buffer.destroy() };
+        log::debug!(
+            "created geometry buffers with {} vertices in {} layouts, {} indices and {} morph deltas",
+            vertices.values().map(|pool| pool.vertex_count).sum::<usize>(),
+            vertices.len(),
+            indices.len(),
+            morph_deltas.len()
+        );
 
-        // done
-        let index_count = indices.len() as u32;
+        Ok(result)
+    }
 
-        Ok(Self {
-            index_count,
-            vertex_buffer,
-            index_buffer,
-        })
+    // the bindings of a layout are consecutive ranges of one buffer
+    fn create_vertex_buffers(
+        &mut self,
+        device: &Arc<Device>,
+        uploader: &mut UploadManager,
+        vertices: &HashMap<VertexLayout, VertexPool>,
+    ) -> Result<()> {
+        for (layout, pool) in vertices {
+            let mut offsets = Vec::with_capacity(pool.bindings.len());
+            let mut size = 0;
+            for binding in &pool.bindings {
+                offsets.push(size);
+                size += binding.len() as vk::DeviceSize;
+            }
+
+            let buffer = Buffer::new(
+                device.clone(),
+                size.max(1),
+                vk::BufferUsageFlags::TRANSFER_DST | vk::BufferUsageFlags::VERTEX_BUFFER,
+                vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            )?;
+            let (buffer, offsets) = &*self.vertex_buffers.entry(*layout).or_insert((buffer, offsets));
+
+            for (binding, offset) in pool.bindings.iter().zip(offsets) {
+                uploader.upload_buffer(buffer, *offset, binding)?;
+            }
+        }
+
+        Ok(())
     }
 
     pub unsafe fn destroy(&self) {
-        self.vertex_buffer.destroy();
+        self.vertex_buffers.values().for_each(|(buffer, _)| buffer.destroy());
         self.index_buffer.destroy();
+        self.morph_delta_buffer.destroy();
     }
 
-    #[inline]
-    pub fn index_count(&self) -> u32 {
-        self.index_count
+    pub fn vertex_bindings(&self) -> HashMap<VertexLayout, VertexBindings> {
+        self.vertex_buffers
+            .iter()
+            .map(|(layout, (buffer, offsets))| {
+                let bindings = VertexBindings {
+                    buffers: vec![buffer.handle(); offsets.len()],
+                    offsets: offsets.clone(),
+                };
+                (*layout, bindings)
+            })
+            .collect()
     }
 
     #[inline]
-    pub fn vertex_buffer(&self) -> &Buffer {
-        &self.vertex_buffer
+    pub fn index_buffer(&self) -> &Buffer {
+        &self.index_buffer
     }
 
     #[inline]
-    pub fn index_buffer(&self) -> &Buffer {
-        &self.index_buffer
+    pub fn morph_delta_buffer(&self) -> &Buffer {
+        &self.morph_delta_buffer
     }
 }
-
-#[allow(unused)]
-pub const QUAD_VERTICES: [Vertex; 4] = [
-    Vertex {
-        position: [0.0, 0.0, 0.0],
-        normal: [1.0, 0.0, 0.0],
-    },
-    Vertex {
-        position: [1.0, 0.0, 0.0],
-        normal: [0.0, 1.0, 0.0],
-    },
-    Vertex {
-        position: [1.0, 1.0, 0.0],
-        normal: [0.0, 0.0, 1.0],
-    },
-    Vertex {
-        position: [0.0, 1.0, 0.0],
-        normal: [0.5, 0.5, 0.0],
-    },
-];
-
-#[allow(unused)]
-pub const QUAD_INDICES: [u16; 6] = [0, 1, 2, 2, 3, 0];
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+
+use super::prelude::*;
+use super::vertex::{MorphTarget, VertexData};
+
+// fifo cache model used to report the efficiency of the index order
+const CACHE_SIZE: u32 = 16;
+// the overdraw order may make the vertex cache efficiency this much worse
+const OVERDRAW_THRESHOLD: f32 = 1.05;
+
+pub struct OptimizedMesh {
+    pub vertices: VertexData,
+    pub indices: Vec<u32>,
+    pub report: OptimizationReport,
+}
+
+// average cache miss ratio is the number of transformed vertices per triangle, 0.5 is the best case
+#[derive(Debug, Copy, Clone, Default)]
+pub struct OptimizationReport {
+    pub vertex_count_before: usize,
+    pub vertex_count_after: usize,
+    pub acmr_before: f32,
+    pub acmr_after: f32,
+}
+
+impl std::fmt::Display for OptimizationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "vertices: {} -> {}, acmr: {:.3} -> {:.3}",
+            self.vertex_count_before, self.vertex_count_after, self.acmr_before, self.acmr_after
+        )
+    }
+}
+
+// removes duplicated vertices, then reorders triangles for the vertex cache and the overdraw,
+// and vertices in the order they are fetched
+pub fn optimize(vertices: &VertexData, indices: &[u32]) -> Result<OptimizedMesh> {
+    let vertex_count_before = vertices.len();
+    let acmr_before = acmr(indices, vertex_count_before);
+
+    // vertices are only equal when all of their attributes and morph deltas are
+    let morph_delta_ids = morph_delta_ids(vertices);
+    let (vertex_count, remap) =
+        meshopt::generate_vertex_remap_multi::<()>(vertices.len(), &streams(vertices, &morph_delta_ids), Some(indices));
+    let indices = meshopt::remap_index_buffer(Some(indices), vertex_count, &remap);
+    let vertices = remap_vertices(vertices, vertex_count, &remap);
+
+    let mut indices = meshopt::optimize_vertex_cache(&indices, vertices.len());
+    let positions = vertices.positions.iter().copied().map(Position).collect::<Vec<_>>();
+    meshopt::optimize_overdraw_in_place_decoder(&mut indices, &positions, OVERDRAW_THRESHOLD);
+
+    let remap = meshopt::optimize_vertex_fetch_remap(&indices, vertices.len());
+    let indices = meshopt::remap_index_buffer(Some(&indices), remap.len(), &remap);
+    let vertices = remap_vertices(&vertices, remap.len(), &remap);
+
+    let report = OptimizationReport {
+        vertex_count_before,
+        vertex_count_after: vertices.len(),
+        acmr_before,
+        acmr_after: acmr(&indices, vertices.len()),
+    };
+
+    Ok(OptimizedMesh {
+        vertices,
+        indices,
+        report,
+    })
+}
+
+pub fn acmr(indices: &[u32], vertex_count: usize) -> f32 {
+    if indices.is_empty() {
+        return 0.0;
+    }
+    meshopt::analyze_vertex_cache(indices, vertex_count, CACHE_SIZE, 0, 0).acmr
+}
+
+// only the decoder variant of the overdraw optimizer takes the indices mutably, it reads positions through this
+struct Position([f32; 3]);
+
+impl meshopt::DecodePosition for Position {
+    fn decode_position(&self) -> [f32; 3] {
+        self.0
+    }
+}
+
+// vertices with equal deltas for all morph targets share an id. Streams are limited to 256 bytes,
+// so the deltas of many targets can't be compared directly
+fn morph_delta_ids(vertices: &VertexData) -> Vec<u32> {
+    let target_count = vertices.morph_targets.len();
+    if target_count == 0 {
+        return Vec::new();
+    }
+
+    let deltas = vertices.morph_deltas();
+    let mut ids = HashMap::new();
+    deltas
+        .chunks(target_count)
+        .map(|vertex_deltas| {
+            let bits = bytemuck::cast_slice::<_, u32>(vertex_deltas).to_vec();
+            let next_id = ids.len() as u32;
+            *ids.entry(bits).or_insert(next_id)
+        })
+        .collect()
+}
+
+fn streams<'a>(vertices: &'a VertexData, morph_delta_ids: &'a [u32]) -> Vec<meshopt::VertexStream<'a>> {
+    let mut streams = vec![meshopt::VertexStream::new(vertices.positions.as_ptr())];
+    if let Some(normals) = &vertices.normals {
+        streams.push(meshopt::VertexStream::new(normals.as_ptr()));
+    }
+    if let Some(tex_coords) = &vertices.tex_coords_0 {
+        streams.push(meshopt::VertexStream::new(tex_coords.as_ptr()));
+    }
+    if let Some(tex_coords) = &vertices.tex_coords_1 {
+        streams.push(meshopt::VertexStream::new(tex_coords.as_ptr()));
+    }
+    if let Some(tangents) = &vertices.tangents {
+        streams.push(meshopt::VertexStream::new(tangents.as_ptr()));
+    }
+    if let Some(colors) = &vertices.colors {
+        streams.push(meshopt::VertexStream::new(colors.as_ptr()));
+    }
+    if let Some(joints) = &vertices.joints {
+        streams.push(meshopt::VertexStream::new(joints.as_ptr()));
+    }
+    if let Some(weights) = &vertices.weights {
+        streams.push(meshopt::VertexStream::new(weights.as_ptr()));
+    }
+    if !morph_delta_ids.is_empty() {
+        streams.push(meshopt::VertexStream::new(morph_delta_ids.as_ptr()));
+    }
+    streams
+}
+
+fn remap_vertices(vertices: &VertexData, vertex_count: usize, remap: &[u32]) -> VertexData {
+    fn remap_stream<T: Clone + Default>(stream: &[T], vertex_count: usize, remap: &[u32]) -> Vec<T> {
+        meshopt::remap_vertex_buffer(stream, vertex_count, remap)
+    }
+
+    VertexData {
+        positions: remap_stream(&vertices.positions, vertex_count, remap),
+        normals: vertices
+            .normals
+            .as_deref()
+            .map(|stream| remap_stream(stream, vertex_count, remap)),
+        tex_coords_0: vertices
+            .tex_coords_0
+            .as_deref()
+            .map(|stream| remap_stream(stream, vertex_count, remap)),
+        tex_coords_1: vertices
+            .tex_coords_1
+            .as_deref()
+            .map(|stream| remap_stream(stream, vertex_count, remap)),
+        tangents: vertices
+            .tangents
+            .as_deref()
+            .map(|stream| remap_stream(stream, vertex_count, remap)),
+        colors: vertices
+            .colors
+            .as_deref()
+            .map(|stream| remap_stream(stream, vertex_count, remap)),
+        joints: vertices
+            .joints
+            .as_deref()
+            .map(|stream| remap_stream(stream, vertex_count, remap)),
+        weights: vertices
+            .weights
+            .as_deref()
+            .map(|stream| remap_stream(stream, vertex_count, remap)),
+        morph_targets: vertices
+            .morph_targets
+            .iter()
+            .map(|target| MorphTarget {
+                positions: target
+                    .positions
+                    .as_deref()
+                    .map(|stream| remap_stream(stream, vertex_count, remap)),
+                normals: target
+                    .normals
+                    .as_deref()
+                    .map(|stream| remap_stream(stream, vertex_count, remap)),
+                tangents: target
+                    .tangents
+                    .as_deref()
+                    .map(|stream| remap_stream(stream, vertex_count, remap)),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // corners of each triangle starting at the smallest one, so the same winding compares equal
+    fn triangles(vertices: &VertexData, indices: &[u32]) -> Vec<[([f32; 3], [f32; 2]); 3]> {
+        let corner = |index: u32| {
+            let index = index as usize;
+            (
+                vertices.positions[index],
+                vertices.tex_coords_0.as_ref().unwrap()[index],
+            )
+        };
+        let mut result = indices
+            .chunks(3)
+            .map(|triangle| {
+                let mut corners = [corner(triangle[0]), corner(triangle[1]), corner(triangle[2])];
+                let first = (0..3)
+                    .min_by(|&a, &b| corners[a].partial_cmp(&corners[b]).unwrap())
+                    .unwrap();
+                corners.rotate_left(first);
+                corners
+            })
+            .collect::<Vec<_>>();
+        result.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        result
+    }
+
+    #[test]
+    fn merges_duplicated_vertices() {
+        // a quad with the shared edge stored twice
+        let positions = [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0], [0.0, 1.0, 0.0]];
+        let tex_coords = [[0.0, 0.0], [1.0, 0.0], [1.0, 1.0], [0.0, 1.0]];
+        let corners = [0, 1, 2, 0, 2, 3];
+        let vertices = VertexData {
+            positions: corners.iter().map(|&corner| positions[corner]).collect(),
+            tex_coords_0: Some(corners.iter().map(|&corner| tex_coords[corner]).collect()),
+            ..Default::default()
+        };
+        let indices = [0, 1, 2, 3, 4, 5];
+
+        let optimized = optimize(&vertices, &indices).unwrap();
+
+        assert_eq!(optimized.vertices.len(), 4);
+        assert_eq!(optimized.vertices.tex_coords_0.as_ref().map(Vec::len), Some(4));
+        assert_eq!(
+            (
+                optimized.report.vertex_count_before,
+                optimized.report.vertex_count_after
+            ),
+            (6, 4)
+        );
+        assert_eq!(
+            triangles(&optimized.vertices, &optimized.indices),
+            triangles(&vertices, &indices)
+        );
+    }
+
+    #[test]
+    fn keeps_vertices_with_different_attributes() {
+        // same positions, but the second triangle has its own uvs
+        let vertices = VertexData {
+            positions: [[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [1.0, 1.0, 0.0]].repeat(2),
+            tex_coords_0: Some(vec![
+                [0.0, 0.0],
+                [1.0, 0.0],
+                [1.0, 1.0],
+                [0.5, 0.0],
+                [1.0, 0.0],
+                [1.0, 1.0],
+            ]),
+            ..Default::default()
+        };
+        let indices = [0, 1, 2, 3, 4, 5];
+
+        let optimized = optimize(&vertices, &indices).unwrap();
+
+        assert_eq!(optimized.vertices.len(), 4);
+        assert_eq!(
+            triangles(&optimized.vertices, &optimized.indices),
+            triangles(&vertices, &indices)
+        );
+    }
+}
@@ -1,33 +1,65 @@
 pub mod buffer;
+pub mod capabilities;
 pub mod command_buffer;
+pub mod compute_pipeline;
+pub mod descriptors;
 pub mod device;
 pub mod frame;
 pub mod framebuffer;
+pub mod frustum;
+pub mod graphics_pipeline;
 pub mod image;
 pub mod instance;
+pub mod lod;
+pub mod material;
 pub mod mesh;
+pub mod mesh_optimizer;
+pub mod permutation;
 pub mod pipeline;
+pub mod pipeline_layout;
+pub mod reflection;
 pub mod shader;
+pub mod shader_compiler;
+pub mod shader_watcher;
+pub mod staging;
 pub mod surface;
 pub mod swapchain;
+pub mod texture;
+pub mod upload;
 pub mod utils;
 pub mod validation;
+pub mod vertex;
 
 pub use self::buffer::{Buffer, Memory};
+pub use self::capabilities::DeviceCapabilities;
 pub use self::command_buffer::CommandPool;
-pub use self::device::Device;
-pub use self::frame::{Frame, FrameSyncObjects};
+pub use self::compute_pipeline::ComputePipeline;
+pub use self::descriptors::{BindlessDescriptorSet, DescriptorAllocator, DescriptorLayoutCache};
+pub use self::device::{Device, DeviceSelector};
+pub use self::frame::{CullStats, Frame, FrameOptions};
 pub use self::framebuffer::Framebuffer;
+pub use self::frustum::Frustum;
+pub use self::graphics_pipeline::{BlendMode, DepthState, GraphicsPipeline};
 pub use self::image::{Image, ImageView};
 pub use self::instance::Instance;
-pub use self::mesh::{Mesh, Vertex};
+pub use self::lod::{LodSelector, MeshLod};
+pub use self::material::Material;
+pub use self::mesh::{BoundingBox, GeometryBuffers, GeometryBuilder, JointRange, Mesh, MeshInstance, VertexBindings};
+pub use self::permutation::ShaderFeatures;
 pub use self::pipeline::PipelineCache;
+pub use self::pipeline_layout::PipelineLayout;
 pub use self::shader::ShaderModule;
+pub use self::shader_compiler::ShaderCompiler;
+pub use self::shader_watcher::ShaderWatcher;
+pub use self::staging::StagingRing;
 pub use self::surface::Surface;
-pub use self::swapchain::Swapchain;
+pub use self::swapchain::{PresentMode, Swapchain};
+pub use self::texture::{Sampler, Texture};
+pub use self::upload::UploadManager;
 pub use self::validation::Validation;
+pub use self::vertex::{VertexAttribute, VertexData, VertexLayout, VertexStorage};
 
-pub(self) mod prelude {
+mod prelude {
     pub use std::collections::HashSet;
     pub use std::ffi::{c_void, CStr, CString};
     pub use std::os::raw::c_char;
@@ -0,0 +1,66 @@
+use std::ops::{BitOr, BitOrAssign};
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ShaderFeatures(u32);
+
+impl ShaderFeatures {
+    pub const BASE_COLOR_TEXTURE: Self = Self(1 << 0);
+    pub const NORMAL_MAP: Self = Self(1 << 1);
+    pub const ALPHA_MASK: Self = Self(1 << 2);
+    pub const SKINNED: Self = Self(1 << 3);
+    pub const MORPH_TARGETS: Self = Self(1 << 4);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    #[inline]
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    #[inline]
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    #[inline]
+    pub fn set(&mut self, other: Self, enabled: bool) {
+        if enabled {
+            self.0 |= other.0;
+        } else {
+            self.0 &= !other.0;
+        }
+    }
+
+    // preprocessor defines which select this permutation in shaders
+    pub fn defines(self) -> Vec<(&'static str, &'static str)> {
+        FEATURE_DEFINES
+            .iter()
+            .filter(|(feature, _)| self.contains(*feature))
+            .map(|&(_, name)| (name, "1"))
+            .collect()
+    }
+}
+
+impl BitOr for ShaderFeatures {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for ShaderFeatures {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+const FEATURE_DEFINES: [(ShaderFeatures, &str); 5] = [
+    (ShaderFeatures::BASE_COLOR_TEXTURE, "HAS_BASE_COLOR_TEXTURE"),
+    (ShaderFeatures::NORMAL_MAP, "HAS_NORMAL_MAP"),
+    (ShaderFeatures::ALPHA_MASK, "ALPHA_MASK"),
+    (ShaderFeatures::SKINNED, "SKINNED"),
+    (ShaderFeatures::MORPH_TARGETS, "MORPH_TARGETS"),
+];
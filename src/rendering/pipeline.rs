@@ -1,26 +1,57 @@
+use std::path::PathBuf;
+
+use super::instance::APPLICATION_NAME;
 use super::prelude::*;
 use super::Device;
 
+const PIPELINE_CACHE_FILE_NAME: &str = "pipeline_cache.bin";
+const PIPELINE_CACHE_HEADER_SIZE: usize = 16 + vk::UUID_SIZE;
+
 pub struct PipelineCache {
     device: Arc<Device>,
     pipeline_cache: vk::PipelineCache,
+    path: Option<PathBuf>,
 }
 
 impl PipelineCache {
     pub fn new(device: Arc<Device>) -> Result<Self> {
-        let pipeline_cache_create_info = vk::PipelineCacheCreateInfo::builder();
+        let path = dirs::cache_dir().map(|dir| dir.join(APPLICATION_NAME).join(PIPELINE_CACHE_FILE_NAME));
+
+        let initial_data = match &path {
+            Some(path) => load_pipeline_cache_data(device.properties(), path),
+            None => {
+                log::warn!("user cache directory not found, pipeline cache will not be persisted");
+                Vec::new()
+            }
+        };
+
+        let pipeline_cache_create_info = vk::PipelineCacheCreateInfo::builder().initial_data(&initial_data);
 
         let pipeline_cache = unsafe {
             device
                 .handle()
                 .create_pipeline_cache(&pipeline_cache_create_info, None)?
         };
-        log::debug!("created pipeline cache {:?}", pipeline_cache);
+        log::debug!(
+            "created pipeline cache {:?} with {} bytes of initial data",
+            pipeline_cache,
+            initial_data.len()
+        );
 
-        Ok(Self { device, pipeline_cache })
+        Ok(Self {
+            device,
+            pipeline_cache,
+            path,
+        })
     }
 
     pub unsafe fn destroy(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = self.save(path) {
+                log::error!("failed to save pipeline cache to {:?}: {:?}", path, e);
+            }
+        }
+
         self.device.handle().destroy_pipeline_cache(self.pipeline_cache, None);
         log::debug!("dropped pipeline cache {:?}", self.pipeline_cache);
     }
@@ -29,4 +60,142 @@ impl PipelineCache {
     pub fn handle(&self) -> vk::PipelineCache {
         self.pipeline_cache
     }
+
+    fn save(&self, path: &Path) -> Result<()> {
+        let data = unsafe { self.device.handle().get_pipeline_cache_data(self.pipeline_cache)? };
+
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        // write to a temporary file first so that an interrupted write doesn't leave a corrupt cache
+        let temp_path = path.with_extension("tmp");
+        std::fs::write(&temp_path, &data)?;
+        std::fs::rename(&temp_path, path)?;
+
+        log::debug!("saved {} bytes of pipeline cache to {:?}", data.len(), path);
+        Ok(())
+    }
+}
+
+fn load_pipeline_cache_data(properties: &vk::PhysicalDeviceProperties, path: &Path) -> Vec<u8> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            log::warn!("failed to read pipeline cache from {:?}: {:?}", path, e);
+            return Vec::new();
+        }
+    };
+
+    match validate_pipeline_cache_header(properties, &data) {
+        Ok(()) => {
+            log::debug!("loaded {} bytes of pipeline cache from {:?}", data.len(), path);
+            data
+        }
+        Err(e) => {
+            log::warn!("discarding pipeline cache {:?}: {}", path, e);
+            Vec::new()
+        }
+    }
+}
+
+fn validate_pipeline_cache_header(properties: &vk::PhysicalDeviceProperties, data: &[u8]) -> Result<()> {
+    if data.len() < PIPELINE_CACHE_HEADER_SIZE {
+        return Err(Error::msg("file is too small"));
+    }
+
+    let read_u32 = |offset: usize| {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(&data[offset..offset + 4]);
+        u32::from_le_bytes(bytes)
+    };
+
+    let header_size = read_u32(0) as usize;
+    let header_version = read_u32(4);
+    let vendor_id = read_u32(8);
+    let device_id = read_u32(12);
+    let uuid = &data[16..PIPELINE_CACHE_HEADER_SIZE];
+
+    if header_size < PIPELINE_CACHE_HEADER_SIZE || header_size > data.len() {
+        return Err(Error::msg(format!("invalid header size {}", header_size)));
+    }
+    if header_version != vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32 {
+        return Err(Error::msg(format!("unsupported header version {}", header_version)));
+    }
+    if vendor_id != properties.vendor_id || device_id != properties.device_id {
+        return Err(Error::msg(format!(
+            "created for device {:04x}:{:04x}",
+            vendor_id, device_id
+        )));
+    }
+    if uuid != properties.pipeline_cache_uuid {
+        return Err(Error::msg("pipeline cache UUID mismatch"));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties() -> vk::PhysicalDeviceProperties {
+        vk::PhysicalDeviceProperties {
+            vendor_id: 0x10de,
+            device_id: 0x1b80,
+            pipeline_cache_uuid: [7; vk::UUID_SIZE],
+            ..Default::default()
+        }
+    }
+
+    // header written by the driver, followed by some cache data
+    fn cache_data(properties: &vk::PhysicalDeviceProperties) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(PIPELINE_CACHE_HEADER_SIZE as u32).to_le_bytes());
+        data.extend_from_slice(&(vk::PipelineCacheHeaderVersion::ONE.as_raw() as u32).to_le_bytes());
+        data.extend_from_slice(&properties.vendor_id.to_le_bytes());
+        data.extend_from_slice(&properties.device_id.to_le_bytes());
+        data.extend_from_slice(&properties.pipeline_cache_uuid);
+        data.extend_from_slice(&[0xab; 64]);
+        data
+    }
+
+    #[test]
+    fn accepts_valid_header() {
+        let properties = properties();
+        validate_pipeline_cache_header(&properties, &cache_data(&properties)).unwrap();
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let properties = properties();
+        let data = cache_data(&properties);
+
+        let error = validate_pipeline_cache_header(&properties, &data[..PIPELINE_CACHE_HEADER_SIZE - 1]).unwrap_err();
+        assert!(error.to_string().contains("too small"), "{}", error);
+        assert!(validate_pipeline_cache_header(&properties, &[]).is_err());
+
+        // the header claims more bytes than the file has
+        let mut data = data[..PIPELINE_CACHE_HEADER_SIZE].to_vec();
+        data[..4].copy_from_slice(&(PIPELINE_CACHE_HEADER_SIZE as u32 + 4).to_le_bytes());
+        let error = validate_pipeline_cache_header(&properties, &data).unwrap_err();
+        assert!(error.to_string().contains("invalid header size"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_other_devices() {
+        let properties = properties();
+        let data = cache_data(&properties);
+
+        let mut other_uuid = properties;
+        other_uuid.pipeline_cache_uuid[0] ^= 1;
+        let error = validate_pipeline_cache_header(&other_uuid, &data).unwrap_err();
+        assert!(error.to_string().contains("UUID mismatch"), "{}", error);
+
+        let mut other_device = properties;
+        other_device.device_id += 1;
+        let error = validate_pipeline_cache_header(&other_device, &data).unwrap_err();
+        assert!(error.to_string().contains("created for device 10de:1b80"), "{}", error);
+    }
 }
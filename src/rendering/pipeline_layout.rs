@@ -0,0 +1,253 @@
+use super::prelude::*;
+use super::{DescriptorLayoutCache, Device, ShaderModule};
+
+pub struct PipelineLayout {
+    device: Arc<Device>,
+    pipeline_layout: vk::PipelineLayout,
+    descriptor_set_layouts: Vec<vk::DescriptorSetLayout>,
+    description: PipelineLayoutDescription,
+}
+
+impl PipelineLayout {
+    pub fn from_shaders(
+        device: Arc<Device>,
+        layout_cache: &DescriptorLayoutCache,
+        shaders: &[&ShaderModule],
+    ) -> Result<Self> {
+        let description = PipelineLayoutDescription::from_shaders(shaders)?;
+        Self::new(device, layout_cache, description)
+    }
+
+    // set layouts are owned by the cache and are shared with other pipeline layouts
+    pub fn new(
+        device: Arc<Device>,
+        layout_cache: &DescriptorLayoutCache,
+        description: PipelineLayoutDescription,
+    ) -> Result<Self> {
+        let mut descriptor_set_layouts = Vec::with_capacity(description.sets.len());
+        for bindings in &description.sets {
+            let layout_bindings = bindings
+                .iter()
+                .map(|binding| {
+                    vk::DescriptorSetLayoutBinding::builder()
+                        .binding(binding.binding)
+                        .descriptor_type(binding.descriptor_type)
+                        .descriptor_count(binding.descriptor_count(&device))
+                        .stage_flags(binding.stages)
+                        .build()
+                })
+                .collect::<Vec<_>>();
+
+            // runtime sized arrays are bound as partially filled bindless arrays
+            let binding_flags = if bindings.iter().any(LayoutBinding::is_runtime_array) {
+                if !device.capabilities().descriptor_indexing {
+                    return Err(Error::msg("runtime descriptor arrays require descriptor indexing"));
+                }
+
+                bindings
+                    .iter()
+                    .map(|binding| {
+                        if binding.is_runtime_array() {
+                            vk::DescriptorBindingFlags::PARTIALLY_BOUND
+                                | vk::DescriptorBindingFlags::VARIABLE_DESCRIPTOR_COUNT
+                                | vk::DescriptorBindingFlags::UPDATE_AFTER_BIND
+                        } else {
+                            vk::DescriptorBindingFlags::empty()
+                        }
+                    })
+                    .collect::<Vec<_>>()
+            } else {
+                Vec::new()
+            };
+
+            descriptor_set_layouts.push(layout_cache.get(&layout_bindings, &binding_flags)?);
+        }
+
+        let push_constant_ranges = description.push_constant_range.iter().copied().collect::<Vec<_>>();
+
+        let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::builder()
+            .set_layouts(&descriptor_set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+
+        let pipeline_layout = unsafe {
+            device
+                .handle()
+                .create_pipeline_layout(&pipeline_layout_create_info, None)?
+        };
+        log::debug!("created pipeline layout {:?}", pipeline_layout);
+
+        Ok(Self {
+            device,
+            pipeline_layout,
+            descriptor_set_layouts,
+            description,
+        })
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.device.handle().destroy_pipeline_layout(self.pipeline_layout, None);
+        log::debug!("dropped pipeline layout {:?}", self.pipeline_layout);
+    }
+
+    #[inline]
+    pub fn handle(&self) -> vk::PipelineLayout {
+        self.pipeline_layout
+    }
+
+    #[inline]
+    pub fn descriptor_set_layout(&self, set: usize) -> vk::DescriptorSetLayout {
+        self.descriptor_set_layouts[set]
+    }
+
+    #[inline]
+    pub fn description(&self) -> &PipelineLayoutDescription {
+        &self.description
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayoutBinding {
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    pub count: u32,
+    pub stages: vk::ShaderStageFlags,
+    pub name: String,
+}
+
+impl LayoutBinding {
+    #[inline]
+    pub fn is_runtime_array(&self) -> bool {
+        self.count == 0
+    }
+
+    // runtime arrays use the maximum number of descriptors supported by the device
+    pub fn descriptor_count(&self, device: &Device) -> u32 {
+        if self.is_runtime_array() {
+            device.capabilities().max_bindless_textures
+        } else {
+            self.count
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PipelineLayoutDescription {
+    // indexed by set number, sets which are not used by any stage are empty
+    pub sets: Vec<Vec<LayoutBinding>>,
+    pub push_constant_range: Option<vk::PushConstantRange>,
+}
+
+impl PipelineLayoutDescription {
+    // merges the reflected bindings of all stages, failing when stages disagree
+    pub fn from_shaders(shaders: &[&ShaderModule]) -> Result<Self> {
+        let mut result = Self::default();
+
+        for shader in shaders {
+            let reflection = shader.reflection();
+
+            for item in &reflection.bindings {
+                let set = item.set as usize;
+                if result.sets.len() <= set {
+                    result.sets.resize(set + 1, Vec::new());
+                }
+
+                let bindings = &mut result.sets[set];
+                match bindings.iter_mut().find(|binding| binding.binding == item.binding) {
+                    Some(binding) => {
+                        if binding.descriptor_type != item.descriptor_type || binding.count != item.count {
+                            return Err(Error::msg(format!(
+                                "set {} binding {} is {:?}[{}] `{}` in {:?} stage, but {:?}[{}] `{}` in {:?} stage",
+                                item.set,
+                                item.binding,
+                                binding.descriptor_type,
+                                binding.count,
+                                binding.name,
+                                binding.stages,
+                                item.descriptor_type,
+                                item.count,
+                                item.name,
+                                reflection.stage
+                            )));
+                        }
+                        binding.stages |= reflection.stage;
+                    }
+                    None => {
+                        bindings.push(LayoutBinding {
+                            binding: item.binding,
+                            descriptor_type: item.descriptor_type,
+                            count: item.count,
+                            stages: reflection.stage,
+                            name: item.name.clone(),
+                        });
+                    }
+                }
+            }
+
+            // all stages share a single range which covers every block
+            if let Some(push_constants) = &reflection.push_constants {
+                let range = result.push_constant_range.get_or_insert(vk::PushConstantRange {
+                    stage_flags: vk::ShaderStageFlags::empty(),
+                    offset: push_constants.offset,
+                    size: 0,
+                });
+
+                let end = (range.offset + range.size).max(push_constants.offset + push_constants.size);
+                range.offset = range.offset.min(push_constants.offset);
+                range.size = end - range.offset;
+                range.stage_flags |= reflection.stage;
+            }
+        }
+
+        result
+            .sets
+            .iter_mut()
+            .for_each(|bindings| bindings.sort_by_key(|binding| binding.binding));
+
+        Ok(result)
+    }
+
+    // layouts are compatible when they differ only in names, so pipelines can share the vulkan layout
+    pub fn is_compatible(&self, other: &Self) -> bool {
+        let is_same_binding = |left: &LayoutBinding, right: &LayoutBinding| {
+            left.binding == right.binding
+                && left.descriptor_type == right.descriptor_type
+                && left.count == right.count
+                && left.stages == right.stages
+        };
+
+        let are_sets_compatible = self.sets.len() == other.sets.len()
+            && self.sets.iter().zip(&other.sets).all(|(left, right)| {
+                left.len() == right.len() && left.iter().zip(right).all(|(left, right)| is_same_binding(left, right))
+            });
+
+        let are_push_constants_compatible = match (&self.push_constant_range, &other.push_constant_range) {
+            (Some(left), Some(right)) => {
+                left.stage_flags == right.stage_flags && left.offset == right.offset && left.size == right.size
+            }
+            (None, None) => true,
+            _ => false,
+        };
+
+        are_sets_compatible && are_push_constants_compatible
+    }
+
+    pub fn binding(&self, set: usize, binding: u32) -> Option<&LayoutBinding> {
+        self.sets.get(set)?.iter().find(|item| item.binding == binding)
+    }
+
+    // pool sizes required to allocate a single descriptor set with this set layout
+    pub fn pool_sizes(&self, device: &Device, set: usize) -> Vec<vk::DescriptorPoolSize> {
+        let mut result = Vec::<vk::DescriptorPoolSize>::new();
+        for binding in self.sets.get(set).into_iter().flatten() {
+            let descriptor_count = binding.descriptor_count(device);
+            match result.iter_mut().find(|size| size.ty == binding.descriptor_type) {
+                Some(size) => size.descriptor_count += descriptor_count,
+                None => result.push(vk::DescriptorPoolSize {
+                    ty: binding.descriptor_type,
+                    descriptor_count,
+                }),
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,800 @@
+use std::collections::HashMap;
+
+use super::prelude::*;
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+
+#[derive(Debug, Clone)]
+pub struct ShaderReflection {
+    pub stage: vk::ShaderStageFlags,
+    pub bindings: Vec<DescriptorBinding>,
+    pub push_constants: Option<PushConstantBlock>,
+    pub inputs: Vec<InterfaceVariable>,
+    pub outputs: Vec<InterfaceVariable>,
+    pub local_size: Option<[u32; 3]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DescriptorBinding {
+    pub set: u32,
+    pub binding: u32,
+    pub descriptor_type: vk::DescriptorType,
+    // zero for runtime sized arrays
+    pub count: u32,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PushConstantBlock {
+    pub offset: u32,
+    pub size: u32,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InterfaceVariable {
+    pub location: u32,
+    pub format: vk::Format,
+    pub name: String,
+}
+
+impl ShaderReflection {
+    pub fn new(code: &[u32]) -> Result<Self> {
+        Module::parse(code)?.reflect()
+    }
+
+    // checks that every input of the next stage is written by this stage with the same type
+    pub fn check_interface(&self, next: &ShaderReflection) -> Result<()> {
+        for input in &next.inputs {
+            let output = self
+                .outputs
+                .iter()
+                .find(|output| output.location == input.location)
+                .ok_or_else(|| {
+                    Error::msg(format!(
+                        "{:?} stage input `{}` at location {} is not written by {:?} stage",
+                        next.stage, input.name, input.location, self.stage
+                    ))
+                })?;
+
+            if output.format != input.format {
+                return Err(Error::msg(format!(
+                    "{:?} stage output `{}` at location {} is {:?}, but {:?} stage input `{}` is {:?}",
+                    self.stage, output.name, output.location, output.format, next.stage, input.name, input.format
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    // checks that every vertex shader input is provided by the vertex layout with the same format
+    pub fn check_vertex_input(&self, attributes: &[vk::VertexInputAttributeDescription]) -> Result<()> {
+        for input in &self.inputs {
+            let attribute = attributes
+                .iter()
+                .find(|attribute| attribute.location == input.location)
+                .ok_or_else(|| {
+                    Error::msg(format!(
+                        "vertex input `{}` at location {} is not provided by the vertex layout",
+                        input.name, input.location
+                    ))
+                })?;
+
+            if shader_format(attribute.format) != input.format {
+                return Err(Error::msg(format!(
+                    "vertex input `{}` at location {} is {:?}, but the vertex layout provides {:?}",
+                    input.name, input.location, input.format, attribute.format
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Type {
+    Int { width: u32, signed: bool },
+    Float { width: u32 },
+    Vector { component: u32, count: u32 },
+    Matrix { column: u32, count: u32 },
+    Image { dim: u32, sampled: u32 },
+    Sampler,
+    SampledImage,
+    Array { element: u32, length: u32 },
+    RuntimeArray { element: u32 },
+    Struct { members: Vec<u32> },
+    Pointer { pointee: u32 },
+    AccelerationStructure,
+    Other,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Decorations {
+    binding: Option<u32>,
+    set: Option<u32>,
+    location: Option<u32>,
+    offset: Option<u32>,
+    array_stride: Option<u32>,
+    matrix_stride: Option<u32>,
+    builtin: bool,
+    buffer_block: bool,
+}
+
+struct EntryPoint {
+    execution_model: u32,
+    id: u32,
+    name: String,
+    interface: Vec<u32>,
+}
+
+struct Variable {
+    id: u32,
+    type_id: u32,
+    storage_class: u32,
+}
+
+#[derive(Default)]
+struct Module {
+    entry_points: Vec<EntryPoint>,
+    local_sizes: HashMap<u32, [u32; 3]>,
+    names: HashMap<u32, String>,
+    decorations: HashMap<u32, Decorations>,
+    member_decorations: HashMap<(u32, u32), Decorations>,
+    types: HashMap<u32, Type>,
+    constants: HashMap<u32, u32>,
+    variables: Vec<Variable>,
+}
+
+mod op {
+    pub const NAME: u32 = 5;
+    pub const ENTRY_POINT: u32 = 15;
+    pub const EXECUTION_MODE: u32 = 16;
+    pub const TYPE_BOOL: u32 = 20;
+    pub const TYPE_INT: u32 = 21;
+    pub const TYPE_FLOAT: u32 = 22;
+    pub const TYPE_VECTOR: u32 = 23;
+    pub const TYPE_MATRIX: u32 = 24;
+    pub const TYPE_IMAGE: u32 = 25;
+    pub const TYPE_SAMPLER: u32 = 26;
+    pub const TYPE_SAMPLED_IMAGE: u32 = 27;
+    pub const TYPE_ARRAY: u32 = 28;
+    pub const TYPE_RUNTIME_ARRAY: u32 = 29;
+    pub const TYPE_STRUCT: u32 = 30;
+    pub const TYPE_POINTER: u32 = 32;
+    pub const CONSTANT: u32 = 43;
+    pub const SPEC_CONSTANT: u32 = 50;
+    pub const VARIABLE: u32 = 59;
+    pub const DECORATE: u32 = 71;
+    pub const MEMBER_DECORATE: u32 = 72;
+    pub const TYPE_ACCELERATION_STRUCTURE: u32 = 5341;
+}
+
+mod decoration {
+    pub const BUFFER_BLOCK: u32 = 3;
+    pub const ARRAY_STRIDE: u32 = 6;
+    pub const MATRIX_STRIDE: u32 = 7;
+    pub const BUILTIN: u32 = 11;
+    pub const LOCATION: u32 = 30;
+    pub const BINDING: u32 = 33;
+    pub const DESCRIPTOR_SET: u32 = 34;
+    pub const OFFSET: u32 = 35;
+}
+
+mod storage_class {
+    pub const UNIFORM_CONSTANT: u32 = 0;
+    pub const INPUT: u32 = 1;
+    pub const UNIFORM: u32 = 2;
+    pub const OUTPUT: u32 = 3;
+    pub const PUSH_CONSTANT: u32 = 9;
+    pub const STORAGE_BUFFER: u32 = 12;
+}
+
+const EXECUTION_MODE_LOCAL_SIZE: u32 = 17;
+const IMAGE_DIM_BUFFER: u32 = 5;
+const IMAGE_DIM_SUBPASS_DATA: u32 = 6;
+
+impl Module {
+    fn parse(code: &[u32]) -> Result<Self> {
+        if code.len() < 5 {
+            return Err(Error::msg("SPIR-V module is too small"));
+        }
+
+        let swap = match code[0] {
+            SPIRV_MAGIC => false,
+            magic if magic.swap_bytes() == SPIRV_MAGIC => true,
+            _ => return Err(Error::msg("invalid SPIR-V magic number")),
+        };
+        let words = code
+            .iter()
+            .map(|&word| if swap { word.swap_bytes() } else { word })
+            .collect::<Vec<_>>();
+
+        let mut module = Module::default();
+
+        let mut position = 5;
+        while position < words.len() {
+            let word_count = (words[position] >> 16) as usize;
+            let opcode = words[position] & 0xffff;
+            if word_count == 0 || position + word_count > words.len() {
+                return Err(Error::msg(format!("malformed SPIR-V instruction at word {}", position)));
+            }
+
+            module.parse_instruction(opcode, &words[position + 1..position + word_count])?;
+            position += word_count;
+        }
+
+        Ok(module)
+    }
+
+    fn parse_instruction(&mut self, opcode: u32, operands: &[u32]) -> Result<()> {
+        let operand = |index: usize| {
+            operands
+                .get(index)
+                .copied()
+                .ok_or_else(|| Error::msg(format!("missing operand {} for SPIR-V opcode {}", index, opcode)))
+        };
+        let rest = |index: usize| operands.get(index..).unwrap_or_default();
+
+        match opcode {
+            op::NAME => {
+                let (name, _) = parse_string(rest(1));
+                self.names.insert(operand(0)?, name);
+            }
+            op::ENTRY_POINT => {
+                let (name, word_count) = parse_string(rest(2));
+                self.entry_points.push(EntryPoint {
+                    execution_model: operand(0)?,
+                    id: operand(1)?,
+                    name,
+                    interface: rest(2 + word_count).to_vec(),
+                });
+            }
+            op::EXECUTION_MODE if operand(1)? == EXECUTION_MODE_LOCAL_SIZE => {
+                self.local_sizes
+                    .insert(operand(0)?, [operand(2)?, operand(3)?, operand(4)?]);
+            }
+            op::TYPE_BOOL => {
+                self.types.insert(operand(0)?, Type::Other);
+            }
+            op::TYPE_INT => {
+                let ty = Type::Int {
+                    width: operand(1)?,
+                    signed: operand(2)? != 0,
+                };
+                self.types.insert(operand(0)?, ty);
+            }
+            op::TYPE_FLOAT => {
+                let ty = Type::Float { width: operand(1)? };
+                self.types.insert(operand(0)?, ty);
+            }
+            op::TYPE_VECTOR => {
+                let ty = Type::Vector {
+                    component: operand(1)?,
+                    count: operand(2)?,
+                };
+                self.types.insert(operand(0)?, ty);
+            }
+            op::TYPE_MATRIX => {
+                let ty = Type::Matrix {
+                    column: operand(1)?,
+                    count: operand(2)?,
+                };
+                self.types.insert(operand(0)?, ty);
+            }
+            op::TYPE_IMAGE => {
+                let ty = Type::Image {
+                    dim: operand(2)?,
+                    sampled: operand(6)?,
+                };
+                self.types.insert(operand(0)?, ty);
+            }
+            op::TYPE_SAMPLER => {
+                self.types.insert(operand(0)?, Type::Sampler);
+            }
+            op::TYPE_SAMPLED_IMAGE => {
+                self.types.insert(operand(0)?, Type::SampledImage);
+            }
+            op::TYPE_ARRAY => {
+                let length = self
+                    .constants
+                    .get(&operand(2)?)
+                    .copied()
+                    .ok_or_else(|| Error::msg("array length is not a known constant"))?;
+                let ty = Type::Array {
+                    element: operand(1)?,
+                    length,
+                };
+                self.types.insert(operand(0)?, ty);
+            }
+            op::TYPE_RUNTIME_ARRAY => {
+                let ty = Type::RuntimeArray { element: operand(1)? };
+                self.types.insert(operand(0)?, ty);
+            }
+            op::TYPE_STRUCT => {
+                let ty = Type::Struct {
+                    members: rest(1).to_vec(),
+                };
+                self.types.insert(operand(0)?, ty);
+            }
+            op::TYPE_POINTER => {
+                let ty = Type::Pointer { pointee: operand(2)? };
+                self.types.insert(operand(0)?, ty);
+            }
+            op::TYPE_ACCELERATION_STRUCTURE => {
+                self.types.insert(operand(0)?, Type::AccelerationStructure);
+            }
+            op::CONSTANT | op::SPEC_CONSTANT => {
+                self.constants.insert(operand(1)?, operand(2)?);
+            }
+            op::VARIABLE => self.variables.push(Variable {
+                type_id: operand(0)?,
+                id: operand(1)?,
+                storage_class: operand(2)?,
+            }),
+            op::DECORATE => {
+                let decorations = self.decorations.entry(operand(0)?).or_default();
+                apply_decoration(decorations, operand(1)?, operands.get(2).copied());
+            }
+            op::MEMBER_DECORATE => {
+                let decorations = self.member_decorations.entry((operand(0)?, operand(1)?)).or_default();
+                apply_decoration(decorations, operand(2)?, operands.get(3).copied());
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    fn reflect(&self) -> Result<ShaderReflection> {
+        let entry_point = self
+            .entry_points
+            .iter()
+            .find(|entry_point| entry_point.name == "main")
+            .ok_or_else(|| Error::msg("SPIR-V module has no `main` entry point"))?;
+
+        let stage = match entry_point.execution_model {
+            0 => vk::ShaderStageFlags::VERTEX,
+            1 => vk::ShaderStageFlags::TESSELLATION_CONTROL,
+            2 => vk::ShaderStageFlags::TESSELLATION_EVALUATION,
+            3 => vk::ShaderStageFlags::GEOMETRY,
+            4 => vk::ShaderStageFlags::FRAGMENT,
+            5 => vk::ShaderStageFlags::COMPUTE,
+            model => return Err(Error::msg(format!("unsupported execution model {}", model))),
+        };
+
+        let mut result = ShaderReflection {
+            stage,
+            bindings: Vec::new(),
+            push_constants: None,
+            inputs: Vec::new(),
+            outputs: Vec::new(),
+            local_size: self.local_sizes.get(&entry_point.id).copied(),
+        };
+
+        for variable in &self.variables {
+            let decorations = self.decorations.get(&variable.id).cloned().unwrap_or_default();
+            let pointee = match self.types.get(&variable.type_id) {
+                Some(Type::Pointer { pointee }) => *pointee,
+                _ => return Err(Error::msg(format!("variable {} is not a pointer", variable.id))),
+            };
+            let name = self.name(variable.id, pointee);
+
+            match variable.storage_class {
+                storage_class::UNIFORM_CONSTANT | storage_class::UNIFORM | storage_class::STORAGE_BUFFER => {
+                    let (set, binding) = match (decorations.set, decorations.binding) {
+                        (Some(set), Some(binding)) => (set, binding),
+                        _ => continue,
+                    };
+
+                    let (element, count) = match self.types.get(&pointee) {
+                        Some(Type::Array { element, length }) => (*element, *length),
+                        Some(Type::RuntimeArray { element }) => (*element, 0),
+                        _ => (pointee, 1),
+                    };
+
+                    let descriptor_type = self.descriptor_type(variable.storage_class, element)?;
+
+                    result.bindings.push(DescriptorBinding {
+                        set,
+                        binding,
+                        descriptor_type,
+                        count,
+                        name,
+                    });
+                }
+                storage_class::PUSH_CONSTANT => {
+                    let (offset, end) = self.struct_range(pointee)?;
+                    result.push_constants = Some(PushConstantBlock {
+                        offset,
+                        size: end - offset,
+                        name,
+                    });
+                }
+                storage_class::INPUT | storage_class::OUTPUT => {
+                    if !entry_point.interface.contains(&variable.id) || decorations.builtin {
+                        continue;
+                    }
+                    let location = match decorations.location {
+                        Some(location) => location,
+                        None => continue,
+                    };
+
+                    let interface_variable = InterfaceVariable {
+                        location,
+                        format: self.format(pointee),
+                        name,
+                    };
+
+                    if variable.storage_class == storage_class::INPUT {
+                        result.inputs.push(interface_variable);
+                    } else {
+                        result.outputs.push(interface_variable);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        result.bindings.sort_by_key(|binding| (binding.set, binding.binding));
+        result.inputs.sort_by_key(|input| input.location);
+        result.outputs.sort_by_key(|output| output.location);
+
+        Ok(result)
+    }
+
+    fn name(&self, id: u32, type_id: u32) -> String {
+        // anonymous blocks have only the type name
+        self.names
+            .get(&id)
+            .filter(|name| !name.is_empty())
+            .or_else(|| self.names.get(&type_id))
+            .cloned()
+            .unwrap_or_else(|| format!("<{}>", id))
+    }
+
+    fn descriptor_type(&self, storage_class: u32, type_id: u32) -> Result<vk::DescriptorType> {
+        let ty = self
+            .types
+            .get(&type_id)
+            .ok_or_else(|| Error::msg(format!("unknown type {}", type_id)))?;
+
+        Ok(match (storage_class, ty) {
+            (storage_class::STORAGE_BUFFER, _) => vk::DescriptorType::STORAGE_BUFFER,
+            (storage_class::UNIFORM, _) => {
+                let is_buffer_block = self
+                    .decorations
+                    .get(&type_id)
+                    .map(|decorations| decorations.buffer_block)
+                    .unwrap_or_default();
+                if is_buffer_block {
+                    vk::DescriptorType::STORAGE_BUFFER
+                } else {
+                    vk::DescriptorType::UNIFORM_BUFFER
+                }
+            }
+            (_, Type::Sampler) => vk::DescriptorType::SAMPLER,
+            (_, Type::SampledImage) => vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+            (_, Type::AccelerationStructure) => vk::DescriptorType::ACCELERATION_STRUCTURE_NV,
+            (_, Type::Image { dim, sampled }) => match (*dim, *sampled) {
+                (IMAGE_DIM_SUBPASS_DATA, _) => vk::DescriptorType::INPUT_ATTACHMENT,
+                (IMAGE_DIM_BUFFER, 2) => vk::DescriptorType::STORAGE_TEXEL_BUFFER,
+                (IMAGE_DIM_BUFFER, _) => vk::DescriptorType::UNIFORM_TEXEL_BUFFER,
+                (_, 2) => vk::DescriptorType::STORAGE_IMAGE,
+                _ => vk::DescriptorType::SAMPLED_IMAGE,
+            },
+            _ => return Err(Error::msg(format!("type {} can't be used as a descriptor", type_id))),
+        })
+    }
+
+    // returns the byte range covered by the struct members
+    fn struct_range(&self, type_id: u32) -> Result<(u32, u32)> {
+        let members = match self.types.get(&type_id) {
+            Some(Type::Struct { members }) => members,
+            _ => return Err(Error::msg(format!("type {} is not a struct", type_id))),
+        };
+
+        let mut start = u32::MAX;
+        let mut end = 0;
+        for (index, &member) in members.iter().enumerate() {
+            let decorations = self.member_decorations.get(&(type_id, index as u32));
+            let offset = decorations.and_then(|decorations| decorations.offset).unwrap_or(0);
+            let matrix_stride = decorations.and_then(|decorations| decorations.matrix_stride);
+
+            start = start.min(offset);
+            end = end.max(offset + self.type_size(member, matrix_stride)?);
+        }
+
+        Ok((start.min(end), end))
+    }
+
+    fn type_size(&self, type_id: u32, matrix_stride: Option<u32>) -> Result<u32> {
+        let ty = self
+            .types
+            .get(&type_id)
+            .ok_or_else(|| Error::msg(format!("unknown type {}", type_id)))?;
+
+        Ok(match ty {
+            Type::Int { width, .. } | Type::Float { width } => width / 8,
+            Type::Vector { component, count } => self.type_size(*component, None)? * count,
+            Type::Matrix { column, count } => match matrix_stride {
+                Some(stride) => stride * count,
+                None => self.type_size(*column, None)? * count,
+            },
+            Type::Array { element, length } => {
+                let stride = match self.decorations.get(&type_id).and_then(|item| item.array_stride) {
+                    Some(stride) => stride,
+                    None => self.type_size(*element, matrix_stride)?,
+                };
+                stride * length
+            }
+            Type::Struct { .. } => self.struct_range(type_id)?.1,
+            _ => return Err(Error::msg(format!("type {} has no known size", type_id))),
+        })
+    }
+
+    fn format(&self, type_id: u32) -> vk::Format {
+        let (component, count) = match self.types.get(&type_id) {
+            Some(Type::Vector { component, count }) => (*component, *count),
+            _ => (type_id, 1),
+        };
+
+        let formats = match self.types.get(&component) {
+            Some(Type::Float { width: 32 }) => [
+                vk::Format::R32_SFLOAT,
+                vk::Format::R32G32_SFLOAT,
+                vk::Format::R32G32B32_SFLOAT,
+                vk::Format::R32G32B32A32_SFLOAT,
+            ],
+            Some(Type::Int {
+                width: 32,
+                signed: true,
+            }) => [
+                vk::Format::R32_SINT,
+                vk::Format::R32G32_SINT,
+                vk::Format::R32G32B32_SINT,
+                vk::Format::R32G32B32A32_SINT,
+            ],
+            Some(Type::Int {
+                width: 32,
+                signed: false,
+            }) => [
+                vk::Format::R32_UINT,
+                vk::Format::R32G32_UINT,
+                vk::Format::R32G32B32_UINT,
+                vk::Format::R32G32B32A32_UINT,
+            ],
+            _ => return vk::Format::UNDEFINED,
+        };
+
+        formats
+            .get(count as usize - 1)
+            .copied()
+            .unwrap_or(vk::Format::UNDEFINED)
+    }
+}
+
+// format of the value the shader receives, narrower integer and normalized formats are converted
+// to 32 bit components when they are fetched
+fn shader_format(format: vk::Format) -> vk::Format {
+    match format {
+        vk::Format::R8G8B8A8_UINT | vk::Format::R16G16B16A16_UINT => vk::Format::R32G32B32A32_UINT,
+        vk::Format::R8G8B8A8_SINT | vk::Format::R16G16B16A16_SINT => vk::Format::R32G32B32A32_SINT,
+        vk::Format::R8G8B8A8_UNORM | vk::Format::R16G16B16A16_UNORM => vk::Format::R32G32B32A32_SFLOAT,
+        format => format,
+    }
+}
+
+fn apply_decoration(decorations: &mut Decorations, decoration: u32, value: Option<u32>) {
+    match decoration {
+        decoration::BUFFER_BLOCK => decorations.buffer_block = true,
+        decoration::BUILTIN => decorations.builtin = true,
+        decoration::ARRAY_STRIDE => decorations.array_stride = value,
+        decoration::MATRIX_STRIDE => decorations.matrix_stride = value,
+        decoration::LOCATION => decorations.location = value,
+        decoration::BINDING => decorations.binding = value,
+        decoration::DESCRIPTOR_SET => decorations.set = value,
+        decoration::OFFSET => decorations.offset = value,
+        _ => {}
+    }
+}
+
+// returns the string and the number of words it occupies
+fn parse_string(words: &[u32]) -> (String, usize) {
+    let mut bytes = Vec::new();
+    for (index, word) in words.iter().enumerate() {
+        for &byte in word.to_le_bytes().iter() {
+            if byte == 0 {
+                return (String::from_utf8_lossy(&bytes).into_owned(), index + 1);
+            }
+            bytes.push(byte);
+        }
+    }
+    (String::from_utf8_lossy(&bytes).into_owned(), words.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendering::ShaderCompiler;
+
+    fn reflect(name: &str, defines: &[(&str, &str)]) -> ShaderReflection {
+        let compiler = ShaderCompiler::new(concat!(env!("CARGO_MANIFEST_DIR"), "/shaders"));
+        let shader = compiler.compile(name, defines).unwrap();
+        ShaderReflection::new(&shader.code).unwrap()
+    }
+
+    fn bindings(reflection: &ShaderReflection) -> Vec<(u32, u32, vk::DescriptorType, u32)> {
+        reflection
+            .bindings
+            .iter()
+            .map(|binding| (binding.set, binding.binding, binding.descriptor_type, binding.count))
+            .collect()
+    }
+
+    fn locations(variables: &[InterfaceVariable]) -> Vec<(u32, vk::Format)> {
+        variables
+            .iter()
+            .map(|variable| (variable.location, variable.format))
+            .collect()
+    }
+
+    fn attribute(location: u32, format: vk::Format) -> vk::VertexInputAttributeDescription {
+        vk::VertexInputAttributeDescription {
+            location,
+            binding: 0,
+            format,
+            offset: 0,
+        }
+    }
+
+    const MESH_DEFINES: [(&str, &str); 2] = [("HAS_NORMAL", "1"), ("HAS_TEX_COORD_0", "1")];
+
+    #[test]
+    fn reflects_mesh_vertex_shader() {
+        let reflection = reflect("mesh.vert", &MESH_DEFINES);
+
+        assert_eq!(reflection.stage, vk::ShaderStageFlags::VERTEX);
+        assert_eq!(
+            bindings(&reflection),
+            vec![
+                (0, 0, vk::DescriptorType::UNIFORM_BUFFER, 1),
+                (0, 1, vk::DescriptorType::STORAGE_BUFFER, 1),
+                (0, 2, vk::DescriptorType::STORAGE_BUFFER, 1),
+                (0, 3, vk::DescriptorType::STORAGE_BUFFER, 1),
+                (0, 4, vk::DescriptorType::STORAGE_BUFFER, 1),
+            ]
+        );
+        assert!(reflection.push_constants.is_none());
+        assert_eq!(
+            locations(&reflection.inputs),
+            vec![
+                (0, vk::Format::R32G32B32_SFLOAT),
+                (1, vk::Format::R32G32B32_SFLOAT),
+                (2, vk::Format::R32G32_SFLOAT),
+            ]
+        );
+        assert_eq!(reflection.outputs.len(), 5);
+        assert!(reflection.local_size.is_none());
+    }
+
+    #[test]
+    fn reflects_mesh_fragment_shader() {
+        let reflection = reflect("mesh.frag", &MESH_DEFINES);
+
+        assert_eq!(reflection.stage, vk::ShaderStageFlags::FRAGMENT);
+        assert_eq!(
+            bindings(&reflection),
+            vec![
+                (1, 0, vk::DescriptorType::STORAGE_BUFFER, 1),
+                (1, 1, vk::DescriptorType::SAMPLER, 1),
+                (1, 2, vk::DescriptorType::SAMPLED_IMAGE, 1),
+                (1, 3, vk::DescriptorType::SAMPLED_IMAGE, 1),
+            ]
+        );
+        assert_eq!(
+            locations(&reflection.inputs),
+            vec![
+                (0, vk::Format::R32G32B32_SFLOAT),
+                (1, vk::Format::R32G32_SFLOAT),
+                (2, vk::Format::R32_UINT),
+                (3, vk::Format::R32G32_SFLOAT),
+                (4, vk::Format::R32G32B32A32_SFLOAT),
+            ]
+        );
+        assert_eq!(
+            locations(&reflection.outputs),
+            vec![(0, vk::Format::R32G32B32A32_SFLOAT)]
+        );
+    }
+
+    #[test]
+    fn reflects_normal_map_permutation() {
+        let defines = [("HAS_NORMAL", "1"), ("HAS_TANGENT", "1"), ("HAS_NORMAL_MAP", "1")];
+        let vertex = reflect("mesh.vert", &defines);
+        let fragment = reflect("mesh.frag", &defines);
+
+        assert!(locations(&vertex.inputs).contains(&(4, vk::Format::R32G32B32A32_SFLOAT)));
+        assert!(locations(&fragment.inputs).contains(&(5, vk::Format::R32G32B32A32_SFLOAT)));
+        vertex.check_interface(&fragment).unwrap();
+    }
+
+    #[test]
+    fn reflects_compute_shaders() {
+        let cull = reflect("cull.comp", &[]);
+        assert_eq!(cull.stage, vk::ShaderStageFlags::COMPUTE);
+        assert_eq!(cull.local_size, Some([64, 1, 1]));
+        assert_eq!(
+            bindings(&cull),
+            vec![
+                (0, 0, vk::DescriptorType::UNIFORM_BUFFER, 1),
+                (0, 1, vk::DescriptorType::STORAGE_BUFFER, 1),
+                (0, 2, vk::DescriptorType::STORAGE_BUFFER, 1),
+                (0, 3, vk::DescriptorType::STORAGE_BUFFER, 1),
+                (0, 4, vk::DescriptorType::STORAGE_BUFFER, 1),
+                (0, 5, vk::DescriptorType::STORAGE_BUFFER, 1),
+                (0, 6, vk::DescriptorType::SAMPLED_IMAGE, 1),
+                (0, 7, vk::DescriptorType::SAMPLER, 1),
+            ]
+        );
+        assert!(cull.push_constants.is_none());
+        assert!(cull.inputs.is_empty() && cull.outputs.is_empty());
+
+        let depth_pyramid = reflect("depth_pyramid.comp", &[]);
+        assert_eq!(depth_pyramid.local_size, Some([8, 8, 1]));
+        let push_constants = depth_pyramid.push_constants.as_ref().unwrap();
+        assert_eq!((push_constants.offset, push_constants.size), (0, 16));
+        assert_eq!(
+            bindings(&depth_pyramid),
+            vec![
+                (0, 0, vk::DescriptorType::SAMPLED_IMAGE, 1),
+                (0, 1, vk::DescriptorType::SAMPLER, 1),
+                (0, 2, vk::DescriptorType::STORAGE_IMAGE, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_modules() {
+        assert!(ShaderReflection::new(&[SPIRV_MAGIC, 0x0001_0000]).is_err());
+        assert!(ShaderReflection::new(&[0, 0x0001_0000, 0, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn checks_vertex_input() {
+        let reflection = reflect("mesh.vert", &MESH_DEFINES);
+
+        let layout = [
+            attribute(0, vk::Format::R32G32B32_SFLOAT),
+            attribute(1, vk::Format::R32G32B32_SFLOAT),
+            attribute(2, vk::Format::R32G32_SFLOAT),
+        ];
+        reflection.check_vertex_input(&layout).unwrap();
+
+        let error = reflection.check_vertex_input(&layout[..2]).unwrap_err();
+        assert!(error.to_string().contains("location 2 is not provided"), "{}", error);
+
+        let mut mismatched = layout;
+        mismatched[2].format = vk::Format::R32G32B32_SFLOAT;
+        let error = reflection.check_vertex_input(&mismatched).unwrap_err();
+        assert!(error.to_string().contains("location 2 is R32G32_SFLOAT"), "{}", error);
+    }
+
+    #[test]
+    fn checks_stage_interface() {
+        let vertex = reflect("mesh.vert", &MESH_DEFINES);
+        let fragment = reflect("mesh.frag", &MESH_DEFINES);
+        vertex.check_interface(&fragment).unwrap();
+
+        let mut missing = vertex.clone();
+        missing.outputs.retain(|output| output.location != 4);
+        let error = missing.check_interface(&fragment).unwrap_err();
+        assert!(error.to_string().contains("location 4 is not written"), "{}", error);
+
+        let mut mismatched = vertex;
+        mismatched.outputs[0].format = vk::Format::R32G32B32A32_SFLOAT;
+        let error = mismatched.check_interface(&fragment).unwrap_err();
+        assert!(error.to_string().contains("is R32G32B32A32_SFLOAT"), "{}", error);
+    }
+}
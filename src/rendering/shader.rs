@@ -1,27 +1,27 @@
 use super::prelude::*;
-use super::{utils, Device};
+use super::reflection::ShaderReflection;
+use super::Device;
 
 pub struct ShaderModule {
     device: Arc<Device>,
     shader_module: vk::ShaderModule,
+    reflection: ShaderReflection,
 }
 
 impl ShaderModule {
-    pub fn from_file<T>(device: Arc<Device>, path: T) -> Result<Self>
-    where
-        T: AsRef<std::path::Path>,
-    {
-        let code = utils::read_shader_code(path)?;
-        Self::new(device, &code)
-    }
+    pub fn new(device: Arc<Device>, code: &[u32]) -> Result<Self> {
+        let reflection = ShaderReflection::new(code)?;
 
-    pub fn new(device: Arc<Device>, code: &[u8]) -> Result<Self> {
-        let shader_module_create_info = vk::ShaderModuleCreateInfo::builder().code(bytemuck::cast_slice(code));
+        let shader_module_create_info = vk::ShaderModuleCreateInfo::builder().code(code);
 
         let shader_module = unsafe { device.handle().create_shader_module(&shader_module_create_info, None)? };
-        log::debug!("created shader module {:?}", shader_module);
+        log::debug!("created {:?} shader module {:?}", reflection.stage, shader_module);
 
-        Ok(Self { device, shader_module })
+        Ok(Self {
+            device,
+            shader_module,
+            reflection,
+        })
     }
 
     pub unsafe fn destroy(&self) {
@@ -33,6 +33,16 @@ impl ShaderModule {
     pub fn handle(&self) -> vk::ShaderModule {
         self.shader_module
     }
+
+    #[inline]
+    pub fn stage(&self) -> vk::ShaderStageFlags {
+        self.reflection.stage
+    }
+
+    #[inline]
+    pub fn reflection(&self) -> &ShaderReflection {
+        &self.reflection
+    }
 }
 
 pub fn main_function_name() -> &'static CStr {
@@ -0,0 +1,667 @@
+use std::path::PathBuf;
+
+use naga::back::spv;
+use naga::front::glsl;
+use naga::valid::{Capabilities, ValidationFlags, Validator};
+
+use super::prelude::*;
+
+const INCLUDE_DIRECTIVE: &str = "#include";
+const INCLUDE_EXTENSION: &str = "GL_GOOGLE_include_directive";
+
+const SPIRV_HEADER_SIZE: usize = 5;
+const OP_EXTENSION: u32 = 10;
+const OP_CAPABILITY: u32 = 17;
+const CAPABILITY_RUNTIME_DESCRIPTOR_ARRAY: u32 = 5302;
+const DESCRIPTOR_INDEXING_EXTENSION: &str = "SPV_EXT_descriptor_indexing";
+
+const ATOMIC_UINT_TYPE: &str = "AtomicUint";
+const ATOMIC_ADD_FUNCTION: &str = "atomic_add";
+
+#[derive(Debug, Clone)]
+pub struct ShaderCompiler {
+    root: PathBuf,
+}
+
+pub struct CompiledShader {
+    pub code: Vec<u32>,
+    // canonical paths of the shader and all files it includes
+    pub dependencies: Vec<PathBuf>,
+}
+
+impl ShaderCompiler {
+    pub fn new<T>(root: T) -> Self
+    where
+        T: Into<PathBuf>,
+    {
+        Self { root: root.into() }
+    }
+
+    pub fn compile(&self, name: &str, defines: &[(&str, &str)]) -> Result<CompiledShader> {
+        let path = self.root.join(name);
+        let stage = shader_stage(&path)?;
+
+        let mut source = PreprocessedSource::default();
+        source.append_file(&self.root, &path, &mut Vec::new())?;
+
+        let mut options = glsl::Options::from(stage);
+        options
+            .defines
+            .extend(defines.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())));
+
+        let mut module = glsl::Frontend::default()
+            .parse(&options, &source.text)
+            .map_err(|errors| {
+                let messages = errors
+                    .iter()
+                    .map(|error| {
+                        let location = error.meta.location(&source.text);
+                        format!(
+                            "{}: {}",
+                            source.describe_location(location.line_number, location.line_position),
+                            error.kind
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                Error::msg(format!("failed to compile {}:\n{}", name, messages.join("\n")))
+            })?;
+        let has_runtime_arrays = lower_binding_arrays(&mut module);
+        lower_atomics(&mut module).map_err(|e| Error::msg(format!("failed to compile {}: {}", name, e)))?;
+
+        let module_info = Validator::new(ValidationFlags::all(), Capabilities::all())
+            .validate(&module)
+            .map_err(|e| Error::msg(format!("failed to validate {}: {}", name, e.as_inner())))?;
+
+        // glsl already uses the vulkan coordinate space, so it must not be adjusted
+        let options = spv::Options {
+            flags: spv::WriterFlags::LABEL_VARYINGS | spv::WriterFlags::DEBUG,
+            ..Default::default()
+        };
+
+        let mut code = spv::write_vec(&module, &module_info, &options, None)
+            .map_err(|e| Error::msg(format!("failed to generate SPIR-V for {}: {}", name, e)))?;
+        if has_runtime_arrays {
+            require_runtime_descriptor_array(&mut code);
+        }
+
+        log::debug!("compiled shader {} with defines {:?}", name, defines);
+
+        Ok(CompiledShader {
+            code,
+            dependencies: source.files,
+        })
+    }
+}
+
+#[derive(Default)]
+struct PreprocessedSource {
+    text: String,
+    files: Vec<PathBuf>,
+    // file index and line number for each line of the text
+    lines: Vec<(usize, u32)>,
+}
+
+impl PreprocessedSource {
+    // expands includes recursively, each file is included at most once
+    fn append_file(&mut self, root: &Path, path: &Path, stack: &mut Vec<PathBuf>) -> Result<()> {
+        let path =
+            std::fs::canonicalize(path).map_err(|e| Error::msg(format!("failed to find shader {:?}: {}", path, e)))?;
+
+        if stack.contains(&path) {
+            return Err(Error::msg(format!("recursive include of {:?}", path)));
+        }
+        if self.files.contains(&path) {
+            return Ok(());
+        }
+
+        let content = std::fs::read_to_string(&path)
+            .map_err(|e| Error::msg(format!("failed to read shader {:?}: {}", path, e)))?;
+
+        let file_index = self.files.len();
+        self.files.push(path.clone());
+        stack.push(path.clone());
+
+        for (line_index, line) in content.lines().enumerate() {
+            let line_number = line_index as u32 + 1;
+            let trimmed = line.trim_start();
+
+            if trimmed.starts_with(INCLUDE_DIRECTIVE) {
+                let include = parse_include(trimmed).ok_or_else(|| {
+                    Error::msg(format!("{}:{}: invalid include directive", path.display(), line_number))
+                })?;
+
+                // includes are resolved relative to the current file first, then to the shaders root
+                let candidates = [path.parent().map(|dir| dir.join(include)), Some(root.join(include))];
+                let include_path = candidates
+                    .iter()
+                    .flatten()
+                    .find(|candidate| candidate.is_file())
+                    .ok_or_else(|| {
+                        Error::msg(format!(
+                            "{}:{}: include {:?} not found",
+                            path.display(),
+                            line_number,
+                            include
+                        ))
+                    })?;
+
+                self.append_file(root, include_path, stack)?;
+                continue;
+            }
+
+            // the directive is only needed by glslc, naga doesn't know this extension
+            let line = if trimmed.starts_with("#extension") && trimmed.contains(INCLUDE_EXTENSION) {
+                ""
+            } else {
+                line
+            };
+
+            self.text.push_str(line);
+            self.text.push('\n');
+            self.lines.push((file_index, line_number));
+        }
+
+        stack.pop();
+        Ok(())
+    }
+
+    fn describe_location(&self, line_number: u32, line_position: u32) -> String {
+        match self.lines.get(line_number as usize - 1) {
+            Some(&(file_index, original_line)) => format!(
+                "{}:{}:{}",
+                self.files[file_index].display(),
+                original_line,
+                line_position
+            ),
+            None => format!("<unknown>:{}:{}", line_number, line_position),
+        }
+    }
+}
+
+fn parse_include(line: &str) -> Option<&str> {
+    let argument = line[INCLUDE_DIRECTIVE.len()..].trim();
+    let (open, close) = match argument.chars().next()? {
+        '"' => ('"', '"'),
+        '<' => ('<', '>'),
+        _ => return None,
+    };
+
+    let argument = argument.strip_prefix(open)?;
+    let end = argument.find(close)?;
+    Some(&argument[..end])
+}
+
+// glsl frontend parses arrays of textures and samplers as plain arrays in the uniform address space,
+// which is invalid, so they are converted to binding arrays. Returns whether any of them is runtime sized
+fn lower_binding_arrays(module: &mut naga::Module) -> bool {
+    let is_handle = |ty: naga::Handle<naga::Type>, types: &naga::UniqueArena<naga::Type>| {
+        matches!(
+            types[ty].inner,
+            naga::TypeInner::Image { .. } | naga::TypeInner::Sampler { .. }
+        )
+    };
+
+    let binding_arrays = module
+        .types
+        .iter()
+        .filter_map(|(handle, ty)| match ty.inner {
+            naga::TypeInner::Array { base, size, .. } if is_handle(base, &module.types) => Some((
+                handle,
+                naga::Type {
+                    name: ty.name.clone(),
+                    inner: naga::TypeInner::BindingArray { base, size },
+                },
+            )),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    if binding_arrays.is_empty() {
+        return false;
+    }
+
+    let has_runtime_arrays = binding_arrays.iter().any(|(_, ty)| {
+        matches!(
+            ty.inner,
+            naga::TypeInner::BindingArray {
+                size: naga::ArraySize::Dynamic,
+                ..
+            }
+        )
+    });
+
+    for (handle, ty) in binding_arrays {
+        module.types.replace(handle, ty);
+    }
+
+    let types = &module.types;
+    let is_binding_array =
+        |ty: naga::Handle<naga::Type>| matches!(types[ty].inner, naga::TypeInner::BindingArray { .. });
+
+    for (_, variable) in module.global_variables.iter_mut() {
+        if is_binding_array(variable.ty) {
+            variable.space = naga::AddressSpace::Handle;
+        }
+    }
+
+    // elements of binding arrays are handles, so they are used directly instead of being loaded
+    let globals = &module.global_variables;
+    let lower_function = |function: &mut naga::Function| {
+        let expressions = &function.expressions;
+        let is_binding_array_element = |expression: &naga::Expression| match *expression {
+            naga::Expression::Access { base, .. } | naga::Expression::AccessIndex { base, .. } => {
+                match expressions[base] {
+                    naga::Expression::GlobalVariable(variable) => is_binding_array(globals[variable].ty),
+                    _ => false,
+                }
+            }
+            _ => false,
+        };
+
+        let loads = expressions
+            .iter()
+            .filter_map(|(handle, expression)| match *expression {
+                naga::Expression::Load { pointer } if is_binding_array_element(&expressions[pointer]) => {
+                    Some((handle, expressions[pointer].clone()))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        for (handle, expression) in loads {
+            function.expressions[handle] = expression;
+        }
+    };
+
+    for (_, function) in module.functions.iter_mut() {
+        lower_function(function);
+    }
+    for entry_point in module.entry_points.iter_mut() {
+        lower_function(&mut entry_point.function);
+    }
+
+    has_runtime_arrays
+}
+
+// glsl frontend doesn't support atomics, so placeholder structs are replaced with atomic integers
+// and calls of the stub function become atomic additions to the element passed to it
+fn lower_atomics(module: &mut naga::Module) -> Result<()> {
+    let atomic_type = match module
+        .types
+        .iter()
+        .find(|(_, ty)| ty.name.as_deref() == Some(ATOMIC_UINT_TYPE))
+    {
+        Some((handle, _)) => handle,
+        None => return Ok(()),
+    };
+
+    // the struct only wraps a single integer, so the layout of buffers doesn't change
+    module.types.replace(
+        atomic_type,
+        naga::Type {
+            name: Some(ATOMIC_UINT_TYPE.to_owned()),
+            inner: naga::TypeInner::Atomic {
+                kind: naga::ScalarKind::Uint,
+                width: 4,
+            },
+        },
+    );
+
+    let atomic_add = match module
+        .functions
+        .iter()
+        .find(|(_, function)| function.name.as_deref() == Some(ATOMIC_ADD_FUNCTION))
+    {
+        Some((handle, _)) => handle,
+        None => return Ok(()),
+    };
+
+    // atomics can't be passed by value, but the stub is never called after lowering
+    let stub = &mut module.functions[atomic_add];
+    let result_type = stub
+        .result
+        .as_ref()
+        .map(|result| result.ty)
+        .ok_or_else(|| Error::msg(format!("{} must return uint", ATOMIC_ADD_FUNCTION)))?;
+    for argument in stub.arguments.iter_mut() {
+        if argument.ty == atomic_type {
+            argument.ty = result_type;
+        }
+    }
+
+    for (handle, function) in module.functions.iter_mut() {
+        if handle != atomic_add {
+            lower_atomic_calls(&mut function.body, &mut function.expressions, atomic_add, result_type)?;
+        }
+    }
+    for entry_point in module.entry_points.iter_mut() {
+        let function = &mut entry_point.function;
+        lower_atomic_calls(&mut function.body, &mut function.expressions, atomic_add, result_type)?;
+    }
+
+    // the stub only returns a placeholder, so a call which is left would silently break the shader
+    let has_stub_calls = module
+        .functions
+        .iter()
+        .filter(|&(handle, _)| handle != atomic_add)
+        .map(|(_, function)| function)
+        .chain(module.entry_points.iter().map(|entry_point| &entry_point.function))
+        .any(|function| calls_function(&function.body, atomic_add));
+    if has_stub_calls {
+        return Err(Error::msg(format!(
+            "{} is called in a way that can't be lowered to an atomic operation",
+            ATOMIC_ADD_FUNCTION
+        )));
+    }
+
+    Ok(())
+}
+
+fn lower_atomic_calls(
+    block: &mut naga::Block,
+    expressions: &mut naga::Arena<naga::Expression>,
+    atomic_add: naga::Handle<naga::Function>,
+    result_type: naga::Handle<naga::Type>,
+) -> Result<()> {
+    for statement in block.iter_mut() {
+        match statement {
+            naga::Statement::Call {
+                function,
+                arguments,
+                result,
+            } if *function == atomic_add => {
+                // the element is loaded to be passed by value, so its pointer is taken from the load
+                let pointer = match arguments.first().map(|&argument| &expressions[argument]) {
+                    Some(&naga::Expression::Load { pointer }) => pointer,
+                    _ => {
+                        return Err(Error::msg(format!(
+                            "{} must be called with a storage buffer element",
+                            ATOMIC_ADD_FUNCTION
+                        )))
+                    }
+                };
+                let value = *arguments
+                    .get(1)
+                    .ok_or_else(|| Error::msg(format!("{} must be called with a value", ATOMIC_ADD_FUNCTION)))?;
+
+                let atomic_result = naga::Expression::AtomicResult {
+                    ty: result_type,
+                    comparison: false,
+                };
+                let result = match *result {
+                    Some(result) => {
+                        expressions[result] = atomic_result;
+                        result
+                    }
+                    None => expressions.append(atomic_result, naga::Span::UNDEFINED),
+                };
+
+                *statement = naga::Statement::Atomic {
+                    pointer,
+                    fun: naga::AtomicFunction::Add,
+                    value,
+                    result,
+                };
+            }
+            naga::Statement::Block(body) => lower_atomic_calls(body, expressions, atomic_add, result_type)?,
+            naga::Statement::If { accept, reject, .. } => {
+                lower_atomic_calls(accept, expressions, atomic_add, result_type)?;
+                lower_atomic_calls(reject, expressions, atomic_add, result_type)?;
+            }
+            naga::Statement::Switch { cases, .. } => {
+                for case in cases.iter_mut() {
+                    lower_atomic_calls(&mut case.body, expressions, atomic_add, result_type)?;
+                }
+            }
+            naga::Statement::Loop { body, continuing, .. } => {
+                lower_atomic_calls(body, expressions, atomic_add, result_type)?;
+                lower_atomic_calls(continuing, expressions, atomic_add, result_type)?;
+            }
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+fn calls_function(block: &naga::Block, function: naga::Handle<naga::Function>) -> bool {
+    block.iter().any(|statement| match statement {
+        naga::Statement::Call { function: callee, .. } => *callee == function,
+        naga::Statement::Block(body) => calls_function(body, function),
+        naga::Statement::If { accept, reject, .. } => {
+            calls_function(accept, function) || calls_function(reject, function)
+        }
+        naga::Statement::Switch { cases, .. } => cases.iter().any(|case| calls_function(&case.body, function)),
+        naga::Statement::Loop { body, continuing, .. } => {
+            calls_function(body, function) || calls_function(continuing, function)
+        }
+        _ => false,
+    })
+}
+
+// spv backend doesn't declare the capability for runtime sized binding arrays, so it is inserted manually
+fn require_runtime_descriptor_array(code: &mut Vec<u32>) {
+    let mut has_capability = false;
+    let mut has_extension = false;
+    let mut capabilities_end = SPIRV_HEADER_SIZE;
+
+    let mut offset = SPIRV_HEADER_SIZE;
+    while offset < code.len() {
+        let opcode = code[offset] & 0xffff;
+        let word_count = (code[offset] >> 16) as usize;
+        if word_count == 0 {
+            break;
+        }
+
+        match opcode {
+            OP_CAPABILITY => {
+                has_capability |= code.get(offset + 1) == Some(&CAPABILITY_RUNTIME_DESCRIPTOR_ARRAY);
+                capabilities_end = offset + word_count;
+            }
+            OP_EXTENSION => {
+                let name = code[offset + 1..offset + word_count]
+                    .iter()
+                    .flat_map(|word| word.to_le_bytes())
+                    .take_while(|&byte| byte != 0)
+                    .collect::<Vec<_>>();
+                has_extension |= name == DESCRIPTOR_INDEXING_EXTENSION.as_bytes();
+            }
+            // capabilities and extensions are always at the beginning of the module
+            _ => break,
+        }
+
+        offset += word_count;
+    }
+
+    if !has_extension {
+        let mut name = DESCRIPTOR_INDEXING_EXTENSION.as_bytes().to_vec();
+        name.resize((name.len() / 4 + 1) * 4, 0);
+
+        let mut instruction = vec![((name.len() / 4 + 1) as u32) << 16 | OP_EXTENSION];
+        instruction.extend(
+            name.chunks(4)
+                .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])),
+        );
+        code.splice(capabilities_end..capabilities_end, instruction);
+    }
+
+    if !has_capability {
+        code.splice(
+            capabilities_end..capabilities_end,
+            [2 << 16 | OP_CAPABILITY, CAPABILITY_RUNTIME_DESCRIPTOR_ARRAY],
+        );
+    }
+}
+
+fn shader_stage(path: &Path) -> Result<naga::ShaderStage> {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some("vert") => Ok(naga::ShaderStage::Vertex),
+        Some("frag") => Ok(naga::ShaderStage::Fragment),
+        Some("comp") => Ok(naga::ShaderStage::Compute),
+        _ => Err(Error::msg(format!("unknown shader stage for {:?}", path))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OP_TYPE_IMAGE: u32 = 25;
+    const OP_TYPE_RUNTIME_ARRAY: u32 = 29;
+    const OP_ATOMIC_I_ADD: u32 = 234;
+
+    const BINDLESS_DEFINES: [(&str, &str); 1] = [("BINDLESS_TEXTURES", "1")];
+
+    fn shaders_root() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("shaders")
+    }
+
+    fn compile(name: &str, defines: &[(&str, &str)]) -> Vec<u32> {
+        ShaderCompiler::new(shaders_root()).compile(name, defines).unwrap().code
+    }
+
+    fn parse(name: &str, defines: &[(&str, &str)]) -> naga::Module {
+        let root = shaders_root();
+        let path = root.join(name);
+        let mut source = PreprocessedSource::default();
+        source.append_file(&root, &path, &mut Vec::new()).unwrap();
+
+        let mut options = glsl::Options::from(shader_stage(&path).unwrap());
+        options
+            .defines
+            .extend(defines.iter().map(|&(name, value)| (name.to_owned(), value.to_owned())));
+        glsl::Frontend::default().parse(&options, &source.text).unwrap()
+    }
+
+    // opcode and operands of every instruction after the header
+    fn instructions(code: &[u32]) -> Vec<(u32, &[u32])> {
+        let mut instructions = Vec::new();
+        let mut offset = SPIRV_HEADER_SIZE;
+        while offset < code.len() {
+            let word_count = (code[offset] >> 16) as usize;
+            instructions.push((code[offset] & 0xffff, &code[offset + 1..offset + word_count]));
+            offset += word_count;
+        }
+        instructions
+    }
+
+    fn capabilities(code: &[u32]) -> Vec<u32> {
+        instructions(code)
+            .into_iter()
+            .filter(|&(opcode, _)| opcode == OP_CAPABILITY)
+            .map(|(_, operands)| operands[0])
+            .collect()
+    }
+
+    fn extensions(code: &[u32]) -> Vec<String> {
+        instructions(code)
+            .into_iter()
+            .filter(|&(opcode, _)| opcode == OP_EXTENSION)
+            .map(|(_, operands)| {
+                let name = operands
+                    .iter()
+                    .flat_map(|word| word.to_le_bytes())
+                    .take_while(|&byte| byte != 0)
+                    .collect::<Vec<_>>();
+                String::from_utf8(name).unwrap()
+            })
+            .collect()
+    }
+
+    fn has_runtime_image_array(code: &[u32]) -> bool {
+        let instructions = instructions(code);
+        let images = instructions
+            .iter()
+            .filter(|&&(opcode, _)| opcode == OP_TYPE_IMAGE)
+            .map(|(_, operands)| operands[0])
+            .collect::<Vec<_>>();
+        instructions
+            .iter()
+            .any(|&(opcode, operands)| opcode == OP_TYPE_RUNTIME_ARRAY && images.contains(&operands[1]))
+    }
+
+    #[test]
+    fn lowers_texture_arrays_to_binding_arrays() {
+        let mut module = parse("mesh.frag", &BINDLESS_DEFINES);
+        assert!(lower_binding_arrays(&mut module));
+
+        let (_, textures) = module
+            .global_variables
+            .iter()
+            .find(|(_, variable)| variable.name.as_deref() == Some("u_textures"))
+            .unwrap();
+        assert_eq!(textures.space, naga::AddressSpace::Handle);
+        assert!(matches!(
+            module.types[textures.ty].inner,
+            naga::TypeInner::BindingArray {
+                size: naga::ArraySize::Dynamic,
+                ..
+            }
+        ));
+
+        let mut module = parse("mesh.frag", &[]);
+        assert!(!lower_binding_arrays(&mut module));
+    }
+
+    #[test]
+    fn bindless_textures_require_runtime_descriptor_array() {
+        let code = compile("mesh.frag", &BINDLESS_DEFINES);
+
+        assert!(capabilities(&code).contains(&CAPABILITY_RUNTIME_DESCRIPTOR_ARRAY));
+        assert!(extensions(&code)
+            .iter()
+            .any(|name| name == DESCRIPTOR_INDEXING_EXTENSION));
+        assert!(has_runtime_image_array(&code));
+    }
+
+    #[test]
+    fn bound_textures_dont_require_descriptor_indexing() {
+        let code = compile("mesh.frag", &[]);
+
+        assert!(!capabilities(&code).contains(&CAPABILITY_RUNTIME_DESCRIPTOR_ARRAY));
+        assert!(!extensions(&code)
+            .iter()
+            .any(|name| name == DESCRIPTOR_INDEXING_EXTENSION));
+        assert!(!has_runtime_image_array(&code));
+    }
+
+    #[test]
+    fn runtime_descriptor_array_is_required_once() {
+        let code = compile("mesh.frag", &BINDLESS_DEFINES);
+
+        let mut required_again = code.clone();
+        require_runtime_descriptor_array(&mut required_again);
+        assert_eq!(required_again, code);
+    }
+
+    fn stub_calls(module: &naga::Module) -> usize {
+        let (atomic_add, _) = module
+            .functions
+            .iter()
+            .find(|(_, function)| function.name.as_deref() == Some(ATOMIC_ADD_FUNCTION))
+            .unwrap();
+        module
+            .functions
+            .iter()
+            .map(|(_, function)| function)
+            .chain(module.entry_points.iter().map(|entry_point| &entry_point.function))
+            .filter(|function| calls_function(&function.body, atomic_add))
+            .count()
+    }
+
+    #[test]
+    fn lowers_atomic_stub_calls() {
+        let mut module = parse("cull.comp", &[]);
+        assert!(stub_calls(&module) > 0);
+
+        lower_atomics(&mut module).unwrap();
+        assert_eq!(stub_calls(&module), 0);
+    }
+
+    #[test]
+    fn culling_uses_atomic_addition() {
+        let code = compile("cull.comp", &[]);
+
+        assert!(instructions(&code).iter().any(|&(opcode, _)| opcode == OP_ATOMIC_I_ADD));
+    }
+}
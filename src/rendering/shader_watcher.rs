@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::prelude::*;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// polls modification times of all files in the shaders directory
+pub struct ShaderWatcher {
+    root: PathBuf,
+    modified: HashMap<PathBuf, SystemTime>,
+    last_poll: Instant,
+}
+
+impl ShaderWatcher {
+    pub fn new<T>(root: T) -> Self
+    where
+        T: Into<PathBuf>,
+    {
+        let root = root.into();
+
+        let mut modified = HashMap::new();
+        scan_directory(&root, &mut modified);
+
+        Self {
+            root,
+            modified,
+            last_poll: Instant::now(),
+        }
+    }
+
+    // returns canonical paths of files which were changed since the last poll
+    pub fn poll(&mut self) -> Vec<PathBuf> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return Vec::new();
+        }
+        self.last_poll = Instant::now();
+
+        let mut modified = HashMap::new();
+        scan_directory(&self.root, &mut modified);
+
+        let changed = modified
+            .iter()
+            .filter(|(path, time)| self.modified.get(*path) != Some(time))
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+
+        changed
+            .iter()
+            .for_each(|path| log::debug!("shader file changed: {:?}", path));
+
+        self.modified = modified;
+        changed
+    }
+}
+
+fn scan_directory(dir: &Path, modified: &mut HashMap<PathBuf, SystemTime>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("failed to read shaders directory {:?}: {}", dir, e);
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        if metadata.is_dir() {
+            scan_directory(&path, modified);
+        } else if let (Ok(path), Ok(time)) = (std::fs::canonicalize(&path), metadata.modified()) {
+            modified.insert(path, time);
+        }
+    }
+}
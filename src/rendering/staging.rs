@@ -0,0 +1,192 @@
+use std::collections::VecDeque;
+
+use super::prelude::*;
+use super::{Buffer, Device};
+
+pub const STAGING_RING_SIZE: vk::DeviceSize = 32 * 1024 * 1024;
+
+pub struct StagingRing {
+    device: Arc<Device>,
+    buffer: Buffer,
+    data_ptr: *mut u8,
+    head: vk::DeviceSize,
+    used: vk::DeviceSize,
+    unretired: RetiredRegion,
+    retired: VecDeque<RetiredRegion>,
+    // the unretired region contains data of transfers which were not submitted yet
+    has_pending_transfers: bool,
+}
+
+impl StagingRing {
+    pub fn new(device: Arc<Device>, size: vk::DeviceSize) -> Result<Self> {
+        // the ring can be used directly as a source of per-frame data, not only for transfers
+        let buffer = Buffer::new(
+            device.clone(),
+            size,
+            vk::BufferUsageFlags::TRANSFER_SRC
+                | vk::BufferUsageFlags::UNIFORM_BUFFER
+                | vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::VERTEX_BUFFER
+                | vk::BufferUsageFlags::INDEX_BUFFER
+                | vk::BufferUsageFlags::INDIRECT_BUFFER,
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+
+        let data_ptr = unsafe { buffer.map_memory()? };
+
+        Ok(Self {
+            device,
+            buffer,
+            data_ptr,
+            head: 0,
+            used: 0,
+            unretired: Default::default(),
+            retired: VecDeque::new(),
+            has_pending_transfers: false,
+        })
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.retired
+            .iter()
+            .chain(std::iter::once(&self.unretired))
+            .flat_map(|region| region.temporary_buffers.iter())
+            .for_each(|buffer| buffer.destroy());
+
+        self.buffer.unmap_memory();
+        self.buffer.destroy();
+    }
+
+    pub fn write(&mut self, data: &[u8], alignment: vk::DeviceSize) -> Result<StagingAllocation> {
+        let allocation = self.allocate(data.len() as vk::DeviceSize, alignment)?;
+        unsafe {
+            allocation.data_ptr.copy_from_nonoverlapping(data.as_ptr(), data.len());
+        }
+        Ok(allocation)
+    }
+
+    // data of transfers which are submitted later, the region can then only be retired by retire_transfers
+    pub fn write_transfer(&mut self, data: &[u8], alignment: vk::DeviceSize) -> Result<StagingAllocation> {
+        let allocation = self.write(data, alignment)?;
+        self.has_pending_transfers = true;
+        Ok(allocation)
+    }
+
+    pub fn allocate(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Result<StagingAllocation> {
+        if let Some(offset) = self.allocate_in_ring(size, alignment) {
+            return Ok(StagingAllocation {
+                buffer: self.buffer.handle(),
+                offset,
+                size,
+                data_ptr: unsafe { self.data_ptr.add(offset as usize) },
+            });
+        }
+
+        // fall back to a dedicated buffer which lives until the same fence as the ring space would
+        log::debug!(
+            "staging ring is out of space for {} bytes, using temporary buffer",
+            size
+        );
+
+        let buffer = Buffer::new(
+            self.device.clone(),
+            size,
+            self.buffer.usage(),
+            vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        )?;
+
+        let allocation = StagingAllocation {
+            buffer: buffer.handle(),
+            offset: 0,
+            size,
+            data_ptr: unsafe { buffer.map_memory()? },
+        };
+
+        self.unretired.temporary_buffers.push(buffer);
+
+        Ok(allocation)
+    }
+
+    // the fence must signal after all uses of the region, so pending transfers must be submitted first
+    pub fn retire(&mut self, fence: vk::Fence) -> Result<()> {
+        if self.has_pending_transfers {
+            return Err(Error::msg(
+                "staging region contains transfers which were not submitted, uploads must be flushed first",
+            ));
+        }
+
+        self.retire_region(fence);
+        Ok(())
+    }
+
+    // the fence must signal after the pending transfers and all other uses of the region
+    pub fn retire_transfers(&mut self, fence: vk::Fence) {
+        self.has_pending_transfers = false;
+        self.retire_region(fence);
+    }
+
+    fn retire_region(&mut self, fence: vk::Fence) {
+        let mut region = std::mem::take(&mut self.unretired);
+        region.fence = fence;
+        self.retired.push_back(region);
+    }
+
+    pub fn reclaim(&mut self) -> Result<()> {
+        while let Some(region) = self.retired.front() {
+            if !unsafe { self.device.handle().get_fence_status(region.fence)? } {
+                break;
+            }
+
+            self.used -= region.size;
+            unsafe { region.temporary_buffers.iter().for_each(|buffer| buffer.destroy()) };
+
+            self.retired.pop_front();
+        }
+
+        Ok(())
+    }
+
+    fn allocate_in_ring(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Option<vk::DeviceSize> {
+        let capacity = self.buffer.size();
+        if size == 0 || size > capacity {
+            return None;
+        }
+
+        let aligned = align_up(self.head, alignment);
+        let (offset, consumed) = if aligned + size <= capacity {
+            (aligned, aligned - self.head + size)
+        } else {
+            // skip the tail end of the buffer and wrap around
+            (0, capacity - self.head + size)
+        };
+
+        if self.used + consumed > capacity {
+            return None;
+        }
+
+        self.head = offset + size;
+        self.used += consumed;
+        self.unretired.size += consumed;
+
+        Some(offset)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct StagingAllocation {
+    pub buffer: vk::Buffer,
+    pub offset: vk::DeviceSize,
+    pub size: vk::DeviceSize,
+    data_ptr: *mut u8,
+}
+
+#[derive(Default)]
+struct RetiredRegion {
+    fence: vk::Fence,
+    size: vk::DeviceSize,
+    temporary_buffers: Vec<Buffer>,
+}
+
+pub fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    value.div_ceil(alignment) * alignment
+}
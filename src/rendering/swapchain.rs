@@ -5,21 +5,27 @@ pub struct Swapchain {
     device: Arc<Device>,
     swapchain_ext: ash::extensions::khr::Swapchain,
     swapchain: vk::SwapchainKHR,
-    images: Vec<vk::Image>,
     image_views: Vec<ImageView>,
     format: vk::Format,
     extent: vk::Extent2D,
+    present_mode: vk::PresentModeKHR,
 }
 
 impl Swapchain {
-    pub fn new(instance: &Instance, surface: &Surface, device: Arc<Device>, window: &Window) -> Result<Self> {
+    pub fn new(
+        instance: &Instance,
+        surface: &Surface,
+        device: Arc<Device>,
+        window: &Window,
+        present_mode: PresentMode,
+    ) -> Result<Self> {
         let size = window.inner_size();
         let size = [size.width, size.height];
 
         // select swapchain properties
         let swapchain_support = device.query_swapchain_support(surface)?;
         let surface_format = choose_swapchain_format(&swapchain_support.available_formats);
-        let present_mode = choose_swapchain_present_mode(&swapchain_support.available_present_modes);
+        let present_mode = choose_swapchain_present_mode(&swapchain_support.available_present_modes, present_mode);
         let extent = choose_swapchain_extent(&swapchain_support.capabilities, size);
 
         // select image count
@@ -60,7 +66,7 @@ impl Swapchain {
 
         let swapchain_ext = ash::extensions::khr::Swapchain::new(instance.handle(), device.handle());
         let swapchain = unsafe { swapchain_ext.create_swapchain(&swapchain_create_info, None)? };
-        log::debug!("created swapchain");
+        log::debug!("created swapchain with present mode {:?}", present_mode);
 
         let images = unsafe { swapchain_ext.get_swapchain_images(swapchain)? };
 
@@ -72,10 +78,10 @@ impl Swapchain {
             device,
             swapchain_ext,
             swapchain,
-            images,
             image_views,
             format: surface_format.format,
             extent,
+            present_mode,
         })
     }
 
@@ -91,7 +97,7 @@ impl Swapchain {
     pub fn acquire_next_image(&self, semaphore: vk::Semaphore) -> Result<(u32, bool), vk::Result> {
         let (image_index, is_sub_optimal) = unsafe {
             self.swapchain_ext
-                .acquire_next_image(self.swapchain, std::u64::MAX, semaphore, vk::Fence::null())?
+                .acquire_next_image(self.swapchain, u64::MAX, semaphore, vk::Fence::null())?
         };
 
         Ok((image_index, is_sub_optimal))
@@ -102,13 +108,13 @@ impl Swapchain {
 
         let swapchains = [self.swapchain];
         let present_info = vk::PresentInfoKHR::builder()
-            .wait_semaphores(&signal_semaphores)
+            .wait_semaphores(signal_semaphores)
             .swapchains(&swapchains)
             .image_indices(&indices);
 
         let result = unsafe {
             self.swapchain_ext
-                .queue_present(self.device.queues().graphics_queue, &present_info)
+                .queue_present(self.device.queues().present_queue, &present_info)
         };
 
         match result {
@@ -134,8 +140,60 @@ impl Swapchain {
     }
 
     #[inline]
-    pub fn image_count(&self) -> u32 {
-        self.images.len() as u32
+    pub fn present_mode(&self) -> vk::PresentModeKHR {
+        self.present_mode
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum PresentMode {
+    #[default]
+    Fifo,
+    FifoRelaxed,
+    Mailbox,
+    Immediate,
+}
+
+impl PresentMode {
+    pub fn next(self) -> Self {
+        match self {
+            PresentMode::Fifo => PresentMode::FifoRelaxed,
+            PresentMode::FifoRelaxed => PresentMode::Mailbox,
+            PresentMode::Mailbox => PresentMode::Immediate,
+            PresentMode::Immediate => PresentMode::Fifo,
+        }
+    }
+
+    // FIFO is the only mode guaranteed to be supported, so it always goes last
+    fn candidates(self) -> &'static [vk::PresentModeKHR] {
+        match self {
+            PresentMode::Fifo => &[vk::PresentModeKHR::FIFO],
+            PresentMode::FifoRelaxed => &[vk::PresentModeKHR::FIFO_RELAXED, vk::PresentModeKHR::FIFO],
+            PresentMode::Mailbox => &[
+                vk::PresentModeKHR::MAILBOX,
+                vk::PresentModeKHR::IMMEDIATE,
+                vk::PresentModeKHR::FIFO,
+            ],
+            PresentMode::Immediate => &[
+                vk::PresentModeKHR::IMMEDIATE,
+                vk::PresentModeKHR::MAILBOX,
+                vk::PresentModeKHR::FIFO,
+            ],
+        }
+    }
+}
+
+impl std::str::FromStr for PresentMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "fifo" | "vsync" => Ok(PresentMode::Fifo),
+            "fifo_relaxed" | "relaxed" => Ok(PresentMode::FifoRelaxed),
+            "mailbox" | "low_latency" => Ok(PresentMode::Mailbox),
+            "immediate" | "uncapped" => Ok(PresentMode::Immediate),
+            _ => Err(Error::msg(format!("unknown present mode: {}", s))),
+        }
     }
 }
 
@@ -151,11 +209,20 @@ fn choose_swapchain_format(available_formats: &[vk::SurfaceFormatKHR]) -> vk::Su
     *available_formats.first().unwrap()
 }
 
-fn choose_swapchain_present_mode(available_present_modes: &[vk::PresentModeKHR]) -> vk::PresentModeKHR {
-    for &available_present_mode in available_present_modes {
-        // or vk::PresentModeKHR::MAILBOX
-        if available_present_mode == vk::PresentModeKHR::FIFO {
-            return available_present_mode;
+fn choose_swapchain_present_mode(
+    available_present_modes: &[vk::PresentModeKHR],
+    preferred: PresentMode,
+) -> vk::PresentModeKHR {
+    for &candidate in preferred.candidates() {
+        if available_present_modes.contains(&candidate) {
+            if candidate != preferred.candidates()[0] {
+                log::warn!(
+                    "present mode {:?} is not supported, falling back to {:?}",
+                    preferred,
+                    candidate
+                );
+            }
+            return candidate;
         }
     }
 
@@ -163,7 +230,7 @@ fn choose_swapchain_present_mode(available_present_modes: &[vk::PresentModeKHR])
 }
 
 fn choose_swapchain_extent(capabilities: &vk::SurfaceCapabilitiesKHR, size: [u32; 2]) -> vk::Extent2D {
-    if capabilities.current_extent.width != u32::max_value() {
+    if capabilities.current_extent.width != u32::MAX {
         capabilities.current_extent
     } else {
         vk::Extent2D {
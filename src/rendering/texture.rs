@@ -0,0 +1,124 @@
+use super::prelude::*;
+use super::{Device, Image, ImageView, UploadManager};
+
+pub struct Texture {
+    image: Image,
+    image_view: ImageView,
+}
+
+impl Texture {
+    // single mip level texture from tightly packed RGBA8 pixels
+    pub fn from_rgba8(
+        device: Arc<Device>,
+        uploader: &mut UploadManager,
+        extent: vk::Extent2D,
+        format: vk::Format,
+        pixels: &[u8],
+    ) -> Result<Self> {
+        let expected_size = extent.width as usize * extent.height as usize * 4;
+        if pixels.len() != expected_size {
+            return Err(Error::msg(format!(
+                "texture data has {} bytes, but {}x{} RGBA8 image needs {}",
+                pixels.len(),
+                extent.width,
+                extent.height,
+                expected_size
+            )));
+        }
+
+        let image = Image::new(
+            device.clone(),
+            [extent.width, extent.height],
+            1,
+            vk::SampleCountFlags::TYPE_1,
+            format,
+            vk::ImageTiling::OPTIMAL,
+            vk::ImageUsageFlags::TRANSFER_DST | vk::ImageUsageFlags::SAMPLED,
+            vk::MemoryPropertyFlags::DEVICE_LOCAL,
+        )?;
+
+        let image_view = match ImageView::new(device, &image, format, vk::ImageAspectFlags::COLOR, 1) {
+            Ok(image_view) => image_view,
+            Err(e) => {
+                unsafe { image.destroy() };
+                return Err(e);
+            }
+        };
+
+        uploader.upload_image(&image, extent, pixels)?;
+
+        Ok(Self { image, image_view })
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.image_view.destroy();
+        self.image.destroy();
+    }
+
+    #[inline]
+    pub fn image_view(&self) -> &ImageView {
+        &self.image_view
+    }
+}
+
+pub struct Sampler {
+    device: Arc<Device>,
+    sampler: vk::Sampler,
+}
+
+impl Sampler {
+    // linear filtering with repeat addressing, anisotropic when the device supports it
+    pub fn new(device: Arc<Device>) -> Result<Self> {
+        let anisotropy_enable = device.capabilities().features.sampler_anisotropy == vk::TRUE;
+
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::REPEAT)
+            .address_mode_v(vk::SamplerAddressMode::REPEAT)
+            .address_mode_w(vk::SamplerAddressMode::REPEAT)
+            .anisotropy_enable(anisotropy_enable)
+            .max_anisotropy(if anisotropy_enable {
+                device.properties().limits.max_sampler_anisotropy
+            } else {
+                1.0
+            })
+            .min_lod(0.0)
+            .max_lod(vk::LOD_CLAMP_NONE);
+
+        Self::from_create_info(device, &sampler_create_info)
+    }
+
+    // point sampling without filtering, for reading exact texel values
+    pub fn nearest(device: Arc<Device>) -> Result<Self> {
+        let sampler_create_info = vk::SamplerCreateInfo::builder()
+            .mag_filter(vk::Filter::NEAREST)
+            .min_filter(vk::Filter::NEAREST)
+            .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .min_lod(0.0)
+            .max_lod(vk::LOD_CLAMP_NONE);
+
+        Self::from_create_info(device, &sampler_create_info)
+    }
+
+    fn from_create_info(device: Arc<Device>, sampler_create_info: &vk::SamplerCreateInfo) -> Result<Self> {
+        let sampler = unsafe { device.handle().create_sampler(sampler_create_info, None)? };
+        log::debug!("created sampler {:?}", sampler);
+
+        Ok(Self { device, sampler })
+    }
+
+    pub unsafe fn destroy(&self) {
+        self.device.handle().destroy_sampler(self.sampler, None);
+        log::debug!("dropped sampler {:?}", self.sampler);
+    }
+
+    #[inline]
+    pub fn handle(&self) -> vk::Sampler {
+        self.sampler
+    }
+}
@@ -0,0 +1,550 @@
+use std::collections::VecDeque;
+
+use super::prelude::*;
+use super::staging::STAGING_RING_SIZE;
+use super::{Buffer, CommandPool, Device, Image, StagingRing};
+
+const STAGING_ALIGNMENT: vk::DeviceSize = 16;
+
+pub struct UploadManager {
+    device: Arc<Device>,
+    transfer_command_pool: CommandPool,
+    graphics_command_pool: Option<CommandPool>,
+    staging: StagingRing,
+    copies: Vec<BufferCopy>,
+    image_copies: Vec<ImageCopy>,
+    pending_batches: VecDeque<PendingBatch>,
+    // fences are recycled instead of destroyed, since the staging ring may still query them
+    free_fences: Vec<vk::Fence>,
+}
+
+impl UploadManager {
+    pub fn new(device: Arc<Device>) -> Result<Self> {
+        let queues = *device.queues();
+
+        let transfer_command_pool = CommandPool::new(
+            device.clone(),
+            queues.transfer_queue_family,
+            vk::CommandPoolCreateFlags::TRANSIENT,
+        )?;
+
+        // acquire barriers for queue ownership transfer must be recorded on the graphics family
+        let graphics_command_pool = if queues.has_dedicated_transfer() {
+            Some(CommandPool::new(
+                device.clone(),
+                queues.graphics_queue_family,
+                vk::CommandPoolCreateFlags::TRANSIENT,
+            )?)
+        } else {
+            None
+        };
+
+        let staging = StagingRing::new(device.clone(), STAGING_RING_SIZE)?;
+
+        log::debug!(
+            "created upload manager, transfer queue family: {}, dedicated: {}",
+            queues.transfer_queue_family,
+            queues.has_dedicated_transfer()
+        );
+
+        Ok(Self {
+            device,
+            transfer_command_pool,
+            graphics_command_pool,
+            staging,
+            copies: Vec::new(),
+            image_copies: Vec::new(),
+            pending_batches: VecDeque::new(),
+            free_fences: Vec::new(),
+        })
+    }
+
+    pub unsafe fn destroy(&self) {
+        let device = self.device.handle();
+
+        for batch in self.pending_batches.iter() {
+            batch.destroy(device, &self.transfer_command_pool, self.graphics_command_pool.as_ref());
+            device.destroy_fence(batch.fence, None);
+        }
+
+        for &fence in self.free_fences.iter() {
+            device.destroy_fence(fence, None);
+        }
+
+        self.staging.destroy();
+
+        if let Some(graphics_command_pool) = &self.graphics_command_pool {
+            graphics_command_pool.destroy();
+        }
+        self.transfer_command_pool.destroy();
+    }
+
+    pub fn upload_buffer(&mut self, dst: &Buffer, dst_offset: vk::DeviceSize, data: &[u8]) -> Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let staged = self.staging.write_transfer(data, STAGING_ALIGNMENT)?;
+
+        self.copies.push(BufferCopy {
+            src_buffer: staged.buffer,
+            dst_buffer: dst.handle(),
+            dst_usage: dst.usage(),
+            region: vk::BufferCopy {
+                src_offset: staged.offset,
+                dst_offset,
+                size: staged.size,
+            },
+        });
+
+        Ok(())
+    }
+
+    // uploads the whole first mip level, the image is left in the SHADER_READ_ONLY layout
+    pub fn upload_image(&mut self, dst: &Image, extent: vk::Extent2D, data: &[u8]) -> Result<()> {
+        let staged = self.staging.write_transfer(data, STAGING_ALIGNMENT)?;
+
+        self.image_copies.push(ImageCopy {
+            src_buffer: staged.buffer,
+            dst_image: dst.handle(),
+            region: vk::BufferImageCopy {
+                buffer_offset: staged.offset,
+                buffer_row_length: 0,
+                buffer_image_height: 0,
+                image_subresource: vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1,
+                },
+                image_offset: vk::Offset3D::default(),
+                image_extent: vk::Extent3D {
+                    width: extent.width,
+                    height: extent.height,
+                    depth: 1,
+                },
+            },
+        });
+
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if self.copies.is_empty() && self.image_copies.is_empty() {
+            return Ok(());
+        }
+
+        let device = self.device.handle();
+        let queues = *self.device.queues();
+
+        let fence = match self.free_fences.pop() {
+            Some(fence) => {
+                unsafe { device.reset_fences(&[fence])? };
+                fence
+            }
+            None => unsafe { device.create_fence(&vk::FenceCreateInfo::builder(), None)? },
+        };
+
+        let transfer_command_buffer = begin_command_buffer(device, &self.transfer_command_pool)?;
+
+        let transfer_dst_barriers = self
+            .image_copies
+            .iter()
+            .map(|copy| {
+                copy.barrier(
+                    vk::AccessFlags::empty(),
+                    vk::AccessFlags::TRANSFER_WRITE,
+                    vk::ImageLayout::UNDEFINED,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    vk::QUEUE_FAMILY_IGNORED,
+                    vk::QUEUE_FAMILY_IGNORED,
+                )
+            })
+            .collect::<Vec<_>>();
+
+        unsafe {
+            if !transfer_dst_barriers.is_empty() {
+                device.cmd_pipeline_barrier(
+                    transfer_command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::DependencyFlags::empty(),
+                    &[],
+                    &[],
+                    &transfer_dst_barriers,
+                );
+            }
+
+            for copy in self.copies.iter() {
+                device.cmd_copy_buffer(
+                    transfer_command_buffer,
+                    copy.src_buffer,
+                    copy.dst_buffer,
+                    &[copy.region],
+                );
+            }
+
+            for copy in self.image_copies.iter() {
+                device.cmd_copy_buffer_to_image(
+                    transfer_command_buffer,
+                    copy.src_buffer,
+                    copy.dst_image,
+                    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                    &[copy.region],
+                );
+            }
+        }
+
+        let (image_dst_stages, image_dst_access) = image_dst_stage_and_access();
+        let dst_stages = self
+            .copies
+            .iter()
+            .fold(vk::PipelineStageFlags::empty(), |stages, copy| {
+                stages | dst_stage_and_access(copy.dst_usage).0
+            })
+            | if self.image_copies.is_empty() {
+                vk::PipelineStageFlags::empty()
+            } else {
+                image_dst_stages
+            };
+
+        // images are transitioned to the shader read layout together with the ownership transfer
+        let image_barriers = |src_access_mask, dst_access_mask, src_queue_family_index, dst_queue_family_index| {
+            self.image_copies
+                .iter()
+                .map(|copy| {
+                    copy.barrier(
+                        src_access_mask,
+                        dst_access_mask,
+                        vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                        vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                        src_queue_family_index,
+                        dst_queue_family_index,
+                    )
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let (semaphore, graphics_command_buffer) = match &self.graphics_command_pool {
+            Some(graphics_command_pool) => {
+                // release on the transfer queue
+                let release_barriers = self
+                    .copies
+                    .iter()
+                    .map(|copy| {
+                        copy.barrier(
+                            vk::AccessFlags::TRANSFER_WRITE,
+                            vk::AccessFlags::empty(),
+                            queues.transfer_queue_family,
+                            queues.graphics_queue_family,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let release_image_barriers = image_barriers(
+                    vk::AccessFlags::TRANSFER_WRITE,
+                    vk::AccessFlags::empty(),
+                    queues.transfer_queue_family,
+                    queues.graphics_queue_family,
+                );
+
+                unsafe {
+                    device.cmd_pipeline_barrier(
+                        transfer_command_buffer,
+                        vk::PipelineStageFlags::TRANSFER,
+                        vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &release_barriers,
+                        &release_image_barriers,
+                    );
+                    device.end_command_buffer(transfer_command_buffer)?;
+                }
+
+                // acquire on the graphics queue
+                let graphics_command_buffer = begin_command_buffer(device, graphics_command_pool)?;
+
+                let acquire_barriers = self
+                    .copies
+                    .iter()
+                    .map(|copy| {
+                        copy.barrier(
+                            vk::AccessFlags::empty(),
+                            dst_stage_and_access(copy.dst_usage).1,
+                            queues.transfer_queue_family,
+                            queues.graphics_queue_family,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let acquire_image_barriers = image_barriers(
+                    vk::AccessFlags::empty(),
+                    image_dst_access,
+                    queues.transfer_queue_family,
+                    queues.graphics_queue_family,
+                );
+
+                unsafe {
+                    device.cmd_pipeline_barrier(
+                        graphics_command_buffer,
+                        vk::PipelineStageFlags::TOP_OF_PIPE,
+                        dst_stages,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &acquire_barriers,
+                        &acquire_image_barriers,
+                    );
+                    device.end_command_buffer(graphics_command_buffer)?;
+                }
+
+                let semaphore = unsafe { device.create_semaphore(&vk::SemaphoreCreateInfo::builder(), None)? };
+
+                let transfer_command_buffers = [transfer_command_buffer];
+                let signal_semaphores = [semaphore];
+                let transfer_submit_infos = [vk::SubmitInfo::builder()
+                    .command_buffers(&transfer_command_buffers)
+                    .signal_semaphores(&signal_semaphores)
+                    .build()];
+
+                let graphics_command_buffers = [graphics_command_buffer];
+                let wait_stages = [dst_stages];
+                let graphics_submit_infos = [vk::SubmitInfo::builder()
+                    .wait_semaphores(&signal_semaphores)
+                    .wait_dst_stage_mask(&wait_stages)
+                    .command_buffers(&graphics_command_buffers)
+                    .build()];
+
+                unsafe {
+                    device.queue_submit(queues.transfer_queue, &transfer_submit_infos, vk::Fence::null())?;
+                    device.queue_submit(queues.graphics_queue, &graphics_submit_infos, fence)?;
+                }
+
+                (semaphore, graphics_command_buffer)
+            }
+            None => {
+                let barriers = self
+                    .copies
+                    .iter()
+                    .map(|copy| {
+                        copy.barrier(
+                            vk::AccessFlags::TRANSFER_WRITE,
+                            dst_stage_and_access(copy.dst_usage).1,
+                            vk::QUEUE_FAMILY_IGNORED,
+                            vk::QUEUE_FAMILY_IGNORED,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let image_barriers = image_barriers(
+                    vk::AccessFlags::TRANSFER_WRITE,
+                    image_dst_access,
+                    vk::QUEUE_FAMILY_IGNORED,
+                    vk::QUEUE_FAMILY_IGNORED,
+                );
+
+                unsafe {
+                    device.cmd_pipeline_barrier(
+                        transfer_command_buffer,
+                        vk::PipelineStageFlags::TRANSFER,
+                        dst_stages,
+                        vk::DependencyFlags::empty(),
+                        &[],
+                        &barriers,
+                        &image_barriers,
+                    );
+                    device.end_command_buffer(transfer_command_buffer)?;
+                }
+
+                let command_buffers = [transfer_command_buffer];
+                let submit_infos = [vk::SubmitInfo::builder().command_buffers(&command_buffers).build()];
+
+                unsafe { device.queue_submit(queues.transfer_queue, &submit_infos, fence)? };
+
+                (vk::Semaphore::null(), vk::CommandBuffer::null())
+            }
+        };
+
+        log::debug!(
+            "submitted {} buffer uploads and {} image uploads",
+            self.copies.len(),
+            self.image_copies.len()
+        );
+        self.copies.clear();
+        self.image_copies.clear();
+
+        self.staging.retire_transfers(fence);
+
+        self.pending_batches.push_back(PendingBatch {
+            fence,
+            semaphore,
+            transfer_command_buffer,
+            graphics_command_buffer,
+        });
+
+        Ok(())
+    }
+
+    pub fn collect_completed(&mut self) -> Result<()> {
+        let device = self.device.handle();
+
+        while let Some(batch) = self.pending_batches.front() {
+            if !unsafe { device.get_fence_status(batch.fence)? } {
+                break;
+            }
+
+            unsafe { batch.destroy(device, &self.transfer_command_pool, self.graphics_command_pool.as_ref()) };
+            self.free_fences.push(batch.fence);
+            self.pending_batches.pop_front();
+        }
+
+        self.staging.reclaim()
+    }
+
+    #[inline]
+    pub fn staging_mut(&mut self) -> &mut StagingRing {
+        &mut self.staging
+    }
+}
+
+struct BufferCopy {
+    src_buffer: vk::Buffer,
+    dst_buffer: vk::Buffer,
+    dst_usage: vk::BufferUsageFlags,
+    region: vk::BufferCopy,
+}
+
+impl BufferCopy {
+    fn barrier(
+        &self,
+        src_access_mask: vk::AccessFlags,
+        dst_access_mask: vk::AccessFlags,
+        src_queue_family_index: u32,
+        dst_queue_family_index: u32,
+    ) -> vk::BufferMemoryBarrier {
+        vk::BufferMemoryBarrier::builder()
+            .src_access_mask(src_access_mask)
+            .dst_access_mask(dst_access_mask)
+            .src_queue_family_index(src_queue_family_index)
+            .dst_queue_family_index(dst_queue_family_index)
+            .buffer(self.dst_buffer)
+            .offset(self.region.dst_offset)
+            .size(self.region.size)
+            .build()
+    }
+}
+
+struct ImageCopy {
+    src_buffer: vk::Buffer,
+    dst_image: vk::Image,
+    region: vk::BufferImageCopy,
+}
+
+impl ImageCopy {
+    fn barrier(
+        &self,
+        src_access_mask: vk::AccessFlags,
+        dst_access_mask: vk::AccessFlags,
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+        src_queue_family_index: u32,
+        dst_queue_family_index: u32,
+    ) -> vk::ImageMemoryBarrier {
+        vk::ImageMemoryBarrier::builder()
+            .src_access_mask(src_access_mask)
+            .dst_access_mask(dst_access_mask)
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .src_queue_family_index(src_queue_family_index)
+            .dst_queue_family_index(dst_queue_family_index)
+            .image(self.dst_image)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .build()
+    }
+}
+
+struct PendingBatch {
+    fence: vk::Fence,
+    semaphore: vk::Semaphore,
+    transfer_command_buffer: vk::CommandBuffer,
+    graphics_command_buffer: vk::CommandBuffer,
+}
+
+impl PendingBatch {
+    unsafe fn destroy(
+        &self,
+        device: &ash::Device,
+        transfer_command_pool: &CommandPool,
+        graphics_command_pool: Option<&CommandPool>,
+    ) {
+        device.free_command_buffers(transfer_command_pool.handle(), &[self.transfer_command_buffer]);
+        if let Some(graphics_command_pool) = graphics_command_pool {
+            device.free_command_buffers(graphics_command_pool.handle(), &[self.graphics_command_buffer]);
+        }
+
+        if self.semaphore != vk::Semaphore::null() {
+            device.destroy_semaphore(self.semaphore, None);
+        }
+    }
+}
+
+fn begin_command_buffer(device: &ash::Device, command_pool: &CommandPool) -> Result<vk::CommandBuffer> {
+    let allocate_info = vk::CommandBufferAllocateInfo::builder()
+        .command_pool(command_pool.handle())
+        .command_buffer_count(1)
+        .level(vk::CommandBufferLevel::PRIMARY);
+
+    let command_buffer = unsafe { device.allocate_command_buffers(&allocate_info)?[0] };
+
+    let begin_info = vk::CommandBufferBeginInfo::builder().flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+    unsafe { device.begin_command_buffer(command_buffer, &begin_info)? };
+
+    Ok(command_buffer)
+}
+
+fn image_dst_stage_and_access() -> (vk::PipelineStageFlags, vk::AccessFlags) {
+    (
+        vk::PipelineStageFlags::VERTEX_SHADER
+            | vk::PipelineStageFlags::FRAGMENT_SHADER
+            | vk::PipelineStageFlags::COMPUTE_SHADER,
+        vk::AccessFlags::SHADER_READ,
+    )
+}
+
+fn dst_stage_and_access(usage: vk::BufferUsageFlags) -> (vk::PipelineStageFlags, vk::AccessFlags) {
+    let shader_stages = vk::PipelineStageFlags::VERTEX_SHADER
+        | vk::PipelineStageFlags::FRAGMENT_SHADER
+        | vk::PipelineStageFlags::COMPUTE_SHADER;
+
+    let mut stages = vk::PipelineStageFlags::empty();
+    let mut access = vk::AccessFlags::empty();
+
+    if usage.contains(vk::BufferUsageFlags::VERTEX_BUFFER) {
+        stages |= vk::PipelineStageFlags::VERTEX_INPUT;
+        access |= vk::AccessFlags::VERTEX_ATTRIBUTE_READ;
+    }
+    if usage.contains(vk::BufferUsageFlags::INDEX_BUFFER) {
+        stages |= vk::PipelineStageFlags::VERTEX_INPUT;
+        access |= vk::AccessFlags::INDEX_READ;
+    }
+    if usage.contains(vk::BufferUsageFlags::UNIFORM_BUFFER) {
+        stages |= shader_stages;
+        access |= vk::AccessFlags::UNIFORM_READ;
+    }
+    if usage.contains(vk::BufferUsageFlags::STORAGE_BUFFER) {
+        stages |= shader_stages;
+        access |= vk::AccessFlags::SHADER_READ;
+    }
+    if usage.contains(vk::BufferUsageFlags::INDIRECT_BUFFER) {
+        stages |= vk::PipelineStageFlags::DRAW_INDIRECT;
+        access |= vk::AccessFlags::INDIRECT_COMMAND_READ;
+    }
+
+    if stages.is_empty() {
+        (vk::PipelineStageFlags::ALL_COMMANDS, vk::AccessFlags::MEMORY_READ)
+    } else {
+        (stages, access)
+    }
+}
@@ -23,15 +23,6 @@ pub fn as_ptr_vec(names: &[CString]) -> Vec<*const c_char> {
     names.iter().map(|item| item.as_ptr()).collect()
 }
 
-#[allow(unused)]
-pub fn read_shader_code<T>(path: T) -> Result<Vec<u8>>
-where
-    T: AsRef<Path>,
-{
-    let bytes = std::fs::read(path)?;
-    Ok(bytes)
-}
-
 #[allow(unused)]
 pub fn viewport(extent: vk::Extent2D, min_depth: f32, max_depth: f32) -> vk::Viewport {
     vk::Viewport {
@@ -0,0 +1,418 @@
+use super::prelude::*;
+
+// attributes a primitive can provide, the discriminant is the location in the mesh shaders
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum VertexAttribute {
+    Position = 0,
+    Normal = 1,
+    TexCoord0 = 2,
+    TexCoord1 = 3,
+    Tangent = 4,
+    Color0 = 5,
+    Joints0 = 6,
+    Weights0 = 7,
+}
+
+impl VertexAttribute {
+    pub const ALL: [Self; 8] = [
+        Self::Position,
+        Self::Normal,
+        Self::TexCoord0,
+        Self::TexCoord1,
+        Self::Tangent,
+        Self::Color0,
+        Self::Joints0,
+        Self::Weights0,
+    ];
+
+    #[inline]
+    pub fn location(self) -> u32 {
+        self as u32
+    }
+
+    pub fn format(self) -> vk::Format {
+        match self {
+            Self::Position | Self::Normal => vk::Format::R32G32B32_SFLOAT,
+            Self::TexCoord0 | Self::TexCoord1 => vk::Format::R32G32_SFLOAT,
+            Self::Tangent | Self::Color0 | Self::Weights0 => vk::Format::R32G32B32A32_SFLOAT,
+            Self::Joints0 => vk::Format::R16G16B16A16_UINT,
+        }
+    }
+
+    // size of one element in bytes
+    pub fn size(self) -> u32 {
+        match self {
+            Self::Position | Self::Normal => 12,
+            Self::TexCoord0 | Self::TexCoord1 | Self::Joints0 => 8,
+            Self::Tangent | Self::Color0 | Self::Weights0 => 16,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Position => "position",
+            Self::Normal => "normal",
+            Self::TexCoord0 => "tex_coord_0",
+            Self::TexCoord1 => "tex_coord_1",
+            Self::Tangent => "tangent",
+            Self::Color0 => "color_0",
+            Self::Joints0 => "joints_0",
+            Self::Weights0 => "weights_0",
+        }
+    }
+
+    // positions are always present, so they don't need a define
+    fn define(self) -> Option<&'static str> {
+        match self {
+            Self::Position => None,
+            Self::Normal => Some("HAS_NORMAL"),
+            Self::TexCoord0 => Some("HAS_TEX_COORD_0"),
+            Self::TexCoord1 => Some("HAS_TEX_COORD_1"),
+            Self::Tangent => Some("HAS_TANGENT"),
+            Self::Color0 => Some("HAS_COLOR_0"),
+            Self::Joints0 => Some("HAS_JOINTS_0"),
+            Self::Weights0 => Some("HAS_WEIGHTS_0"),
+        }
+    }
+
+    #[inline]
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+// interleaved vertices use one binding, split ones use one binding per attribute
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Default)]
+pub enum VertexStorage {
+    #[default]
+    Interleaved,
+    Split,
+}
+
+impl std::str::FromStr for VertexStorage {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "interleaved" => Ok(VertexStorage::Interleaved),
+            "split" => Ok(VertexStorage::Split),
+            _ => Err(Error::msg(format!("unknown vertex storage: {}", s))),
+        }
+    }
+}
+
+// attributes are always laid out in the order of their locations
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct VertexLayout {
+    attributes: u32,
+    storage: VertexStorage,
+}
+
+impl VertexLayout {
+    pub fn new(attributes: &[VertexAttribute], storage: VertexStorage) -> Self {
+        let attributes = attributes
+            .iter()
+            .fold(VertexAttribute::Position.bit(), |bits, attribute| {
+                bits | attribute.bit()
+            });
+
+        Self { attributes, storage }
+    }
+
+    #[inline]
+    pub fn contains(self, attribute: VertexAttribute) -> bool {
+        self.attributes & attribute.bit() != 0
+    }
+
+    #[inline]
+    pub fn storage(self) -> VertexStorage {
+        self.storage
+    }
+
+    pub fn attributes(self) -> impl Iterator<Item = VertexAttribute> {
+        VertexAttribute::ALL
+            .iter()
+            .copied()
+            .filter(move |attribute| self.contains(*attribute))
+    }
+
+    // size of one vertex summed over all bindings
+    pub fn vertex_size(self) -> u32 {
+        self.attributes().map(VertexAttribute::size).sum()
+    }
+
+    pub fn binding_count(self) -> usize {
+        match self.storage {
+            VertexStorage::Interleaved => 1,
+            VertexStorage::Split => self.attributes().count(),
+        }
+    }
+
+    pub fn binding_descriptions(self) -> Vec<vk::VertexInputBindingDescription> {
+        match self.storage {
+            VertexStorage::Interleaved => vec![vk::VertexInputBindingDescription {
+                binding: 0,
+                stride: self.vertex_size(),
+                input_rate: vk::VertexInputRate::VERTEX,
+            }],
+            VertexStorage::Split => self
+                .attributes()
+                .enumerate()
+                .map(|(binding, attribute)| vk::VertexInputBindingDescription {
+                    binding: binding as u32,
+                    stride: attribute.size(),
+                    input_rate: vk::VertexInputRate::VERTEX,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn attribute_descriptions(self) -> Vec<vk::VertexInputAttributeDescription> {
+        let mut offset = 0;
+        self.attributes()
+            .enumerate()
+            .map(|(index, attribute)| {
+                let (binding, attribute_offset) = match self.storage {
+                    VertexStorage::Interleaved => (0, offset),
+                    VertexStorage::Split => (index as u32, 0),
+                };
+                offset += attribute.size();
+
+                vk::VertexInputAttributeDescription {
+                    location: attribute.location(),
+                    binding,
+                    format: attribute.format(),
+                    offset: attribute_offset,
+                }
+            })
+            .collect()
+    }
+
+    // preprocessor defines which enable the optional vertex inputs in shaders
+    pub fn defines(self) -> Vec<(&'static str, &'static str)> {
+        self.attributes()
+            .filter_map(VertexAttribute::define)
+            .map(|name| (name, "1"))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for VertexLayout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = self.attributes().map(VertexAttribute::name).collect::<Vec<_>>();
+        write!(f, "{} ({:?})", names.join(", "), self.storage)
+    }
+}
+
+// vertices of one mesh with each attribute in its own stream, missing attributes are none
+#[derive(Debug, Clone, Default)]
+pub struct VertexData {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Option<Vec<[f32; 3]>>,
+    pub tex_coords_0: Option<Vec<[f32; 2]>>,
+    pub tex_coords_1: Option<Vec<[f32; 2]>>,
+    pub tangents: Option<Vec<[f32; 4]>>,
+    // always rgba, integer colors are normalized when loaded
+    pub colors: Option<Vec<[f32; 4]>>,
+    pub joints: Option<Vec<[u16; 4]>>,
+    pub weights: Option<Vec<[f32; 4]>>,
+    pub morph_targets: Vec<MorphTarget>,
+}
+
+impl VertexData {
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.positions.len()
+    }
+
+    // layout with exactly the attributes present in this data
+    pub fn layout(&self, storage: VertexStorage) -> VertexLayout {
+        let attributes = VertexAttribute::ALL
+            .iter()
+            .copied()
+            .filter(|attribute| self.stream(*attribute).is_some())
+            .collect::<Vec<_>>();
+
+        VertexLayout::new(&attributes, storage)
+    }
+
+    pub fn stream(&self, attribute: VertexAttribute) -> Option<&[u8]> {
+        match attribute {
+            VertexAttribute::Position => Some(bytemuck::cast_slice(&self.positions)),
+            VertexAttribute::Normal => self.normals.as_deref().map(bytemuck::cast_slice),
+            VertexAttribute::TexCoord0 => self.tex_coords_0.as_deref().map(bytemuck::cast_slice),
+            VertexAttribute::TexCoord1 => self.tex_coords_1.as_deref().map(bytemuck::cast_slice),
+            VertexAttribute::Tangent => self.tangents.as_deref().map(bytemuck::cast_slice),
+            VertexAttribute::Color0 => self.colors.as_deref().map(bytemuck::cast_slice),
+            VertexAttribute::Joints0 => self.joints.as_deref().map(bytemuck::cast_slice),
+            VertexAttribute::Weights0 => self.weights.as_deref().map(bytemuck::cast_slice),
+        }
+    }
+
+    // simplification and overdraw optimization only look at the positions
+    pub fn position_adapter(&self) -> Result<meshopt::VertexDataAdapter<'_>> {
+        meshopt::VertexDataAdapter::new(
+            bytemuck::cast_slice(&self.positions),
+            VertexAttribute::Position.size() as usize,
+            0,
+        )
+        .map_err(|e| Error::msg(format!("invalid vertex data: {}", e)))
+    }
+
+    // deltas of all morph targets ordered by vertex and then by target, missing deltas are zero
+    pub fn morph_deltas(&self) -> Vec<MorphDelta> {
+        let mut deltas = Vec::with_capacity(self.len() * self.morph_targets.len());
+        for vertex in 0..self.len() {
+            deltas.extend(self.morph_targets.iter().map(|target| target.delta(vertex)));
+        }
+        deltas
+    }
+
+    // appends the vertices to one byte vector per binding of the layout,
+    // attributes of the layout which are missing here are filled with zeros
+    pub fn write(&self, layout: VertexLayout, bindings: &mut [Vec<u8>]) {
+        let vertex_count = self.len();
+        match layout.storage() {
+            VertexStorage::Interleaved => {
+                let streams = layout
+                    .attributes()
+                    .map(|attribute| (attribute.size() as usize, self.stream(attribute)))
+                    .collect::<Vec<_>>();
+
+                let binding = &mut bindings[0];
+                binding.reserve(vertex_count * layout.vertex_size() as usize);
+                for vertex in 0..vertex_count {
+                    for (size, stream) in &streams {
+                        match stream {
+                            Some(stream) => binding.extend_from_slice(&stream[vertex * size..(vertex + 1) * size]),
+                            None => binding.resize(binding.len() + size, 0),
+                        }
+                    }
+                }
+            }
+            VertexStorage::Split => {
+                for (binding, attribute) in bindings.iter_mut().zip(layout.attributes()) {
+                    match self.stream(attribute) {
+                        Some(stream) => binding.extend_from_slice(stream),
+                        None => binding.resize(binding.len() + vertex_count * attribute.size() as usize, 0),
+                    }
+                }
+            }
+        }
+    }
+}
+
+// displacements of the vertices for one morph target, streams have the length of the base vertices
+#[derive(Debug, Clone, Default)]
+pub struct MorphTarget {
+    pub positions: Option<Vec<[f32; 3]>>,
+    pub normals: Option<Vec<[f32; 3]>>,
+    pub tangents: Option<Vec<[f32; 3]>>,
+}
+
+impl MorphTarget {
+    fn delta(&self, vertex: usize) -> MorphDelta {
+        let delta = |stream: &Option<Vec<[f32; 3]>>| {
+            let [x, y, z] = stream.as_ref().map_or([0.0; 3], |stream| stream[vertex]);
+            [x, y, z, 0.0]
+        };
+
+        MorphDelta {
+            position: delta(&self.positions),
+            normal: delta(&self.normals),
+            tangent: delta(&self.tangents),
+        }
+    }
+}
+
+// displacement of one vertex by one morph target, must match the morph delta struct in the mesh shaders
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct MorphDelta {
+    pub position: [f32; 4],
+    pub normal: [f32; 4],
+    pub tangent: [f32; 4],
+}
+
+unsafe impl bytemuck::Pod for MorphDelta {}
+unsafe impl bytemuck::Zeroable for MorphDelta {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // attributes are given out of order, positions are added implicitly
+    fn layout(storage: VertexStorage) -> VertexLayout {
+        VertexLayout::new(&[VertexAttribute::Color0, VertexAttribute::TexCoord0], storage)
+    }
+
+    fn bindings(layout: VertexLayout) -> Vec<(u32, u32)> {
+        layout
+            .binding_descriptions()
+            .iter()
+            .map(|binding| (binding.binding, binding.stride))
+            .collect()
+    }
+
+    fn attributes(layout: VertexLayout) -> Vec<(u32, u32, vk::Format, u32)> {
+        layout
+            .attribute_descriptions()
+            .iter()
+            .map(|attribute| {
+                (
+                    attribute.location,
+                    attribute.binding,
+                    attribute.format,
+                    attribute.offset,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn describes_interleaved_vertices() {
+        let layout = layout(VertexStorage::Interleaved);
+
+        assert_eq!(layout.binding_count(), 1);
+        assert_eq!(bindings(layout), vec![(0, 36)]);
+        assert_eq!(
+            attributes(layout),
+            vec![
+                (0, 0, vk::Format::R32G32B32_SFLOAT, 0),
+                (2, 0, vk::Format::R32G32_SFLOAT, 12),
+                (5, 0, vk::Format::R32G32B32A32_SFLOAT, 20),
+            ]
+        );
+    }
+
+    #[test]
+    fn describes_split_vertices() {
+        let layout = layout(VertexStorage::Split);
+
+        assert_eq!(layout.binding_count(), 3);
+        assert_eq!(bindings(layout), vec![(0, 12), (1, 8), (2, 16)]);
+        assert_eq!(
+            attributes(layout),
+            vec![
+                (0, 0, vk::Format::R32G32B32_SFLOAT, 0),
+                (2, 1, vk::Format::R32G32_SFLOAT, 0),
+                (5, 2, vk::Format::R32G32B32A32_SFLOAT, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn defines_optional_attributes() {
+        let interleaved = layout(VertexStorage::Interleaved);
+        let split = layout(VertexStorage::Split);
+
+        assert_eq!(interleaved.vertex_size(), 36);
+        assert_eq!(split.vertex_size(), 36);
+        assert_eq!(
+            interleaved.defines(),
+            vec![("HAS_TEX_COORD_0", "1"), ("HAS_COLOR_0", "1")]
+        );
+        // pipelines and vertex buffers are keyed by the layout, which includes the storage
+        assert_ne!(interleaved, split);
+    }
+}
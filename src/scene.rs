@@ -1,66 +1,422 @@
+use std::collections::HashSet;
+use std::path::Path;
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{Error, Result};
+use ash::vk;
 use gltf::Gltf;
 
-use crate::rendering::{CommandPool, Device, Mesh, Vertex};
+use crate::animation::{AnimationClip, AnimationPlayer};
+use crate::rendering::mesh_optimizer::{self, OptimizationReport};
+use crate::rendering::vertex::MorphTarget;
+use crate::rendering::{
+    Device, GeometryBuffers, GeometryBuilder, Material, Mesh, MeshInstance, Texture, UploadManager, VertexData,
+    VertexStorage,
+};
+use crate::skeleton::{NodeHierarchy, Skin};
 
 pub struct Scene {
+    geometry: GeometryBuffers,
     meshes: Vec<Mesh>,
+    materials: Vec<Material>,
+    textures: Vec<Texture>,
+    instances: Vec<MeshInstance>,
+    // node of each instance, instances without one are never moved
+    instance_nodes: Vec<Option<usize>>,
+    hierarchy: NodeHierarchy,
+    skins: Vec<Skin>,
+    // matrices of all skins in the order of their joint ranges
+    joint_matrices: Vec<glm::Mat4>,
+    // morph target weights of all morphed instances in the order of their weight offsets
+    morph_weights: Vec<f32>,
+    animation_player: AnimationPlayer,
 }
 
 impl Scene {
-    pub fn new<T>(device: Arc<Device>, command_pool: &CommandPool, path: T) -> Result<Self>
+    pub fn new<T>(
+        device: Arc<Device>,
+        uploader: &mut UploadManager,
+        path: T,
+        vertex_storage: VertexStorage,
+    ) -> Result<Self>
     where
         T: AsRef<std::path::Path>,
     {
+        let path = path.as_ref();
         let loaded_data = Gltf::open(path)?;
         let blob = loaded_data.blob.as_ref().unwrap();
 
+        // primitives without a material use the default one at the end of the list
+        let mut materials = loaded_data
+            .materials()
+            .map(|material| Material::from_gltf(&material))
+            .collect::<Vec<_>>();
+        let default_material = materials.len();
+        materials.push(Material::default());
+
+        // color textures are stored in sRGB, all other ones contain linear data
+        let color_images = materials
+            .iter()
+            .filter_map(|material| material.base_color_texture)
+            .collect::<HashSet<_>>();
+
+        let mut textures = Vec::with_capacity(loaded_data.images().len());
+        for image in loaded_data.images() {
+            let format = if color_images.contains(&image.index()) {
+                vk::Format::R8G8B8A8_SRGB
+            } else {
+                vk::Format::R8G8B8A8_UNORM
+            };
+
+            let pixels = load_image(&image, path, blob)?;
+            let extent = vk::Extent2D {
+                width: pixels.width(),
+                height: pixels.height(),
+            };
+
+            textures.push(Texture::from_rgba8(
+                device.clone(),
+                uploader,
+                extent,
+                format,
+                &pixels.into_raw(),
+            )?);
+        }
+
+        let mut geometry = GeometryBuilder::new(vertex_storage);
         let mut meshes = Vec::with_capacity(loaded_data.meshes().len());
+        // gltf mesh index to the index of the loaded mesh
+        let mut mesh_indices = vec![None; loaded_data.meshes().len()];
 
-        for (_, mesh) in loaded_data.meshes().enumerate() {
+        let mut total_report = OptimizationReport::default();
+        let (mut total_acmr_before, mut total_acmr_after, mut triangle_count) = (0.0, 0.0, 0);
+
+        for mesh in loaded_data.meshes() {
             let primitive = match mesh.primitives().next() {
                 Some(primitive) => primitive,
                 None => continue,
             };
+            // mesh pipelines draw triangle lists, and the optimizer and lods reorder indices as triangles
+            if primitive.mode() != gltf::mesh::Mode::Triangles {
+                log::warn!(
+                    "skipped mesh {}, {:?} primitives are not supported",
+                    mesh.index(),
+                    primitive.mode()
+                );
+                continue;
+            }
 
             let reader = primitive.reader(|_| Some(blob));
 
-            let vertices = match reader
-                .read_positions()
-                .and_then(|positions_iter| reader.read_normals().map(|normals_iter| (positions_iter, normals_iter)))
-                .map(|(positions_iter, normals_iter)| {
-                    positions_iter
-                        .zip(normals_iter)
-                        .map(|(position, normal)| Vertex {
-                            position: [position[0], -position[2], position[1]],
-                            normal: [normal[0], -normal[2], normal[1]],
+            // every attribute the primitive has ends up in its vertex layout
+            let vertices = match reader.read_positions() {
+                Some(positions) => VertexData {
+                    positions: positions.map(vector_to_z_up).collect(),
+                    normals: reader
+                        .read_normals()
+                        .map(|normals| normals.map(vector_to_z_up).collect()),
+                    tex_coords_0: reader
+                        .read_tex_coords(0)
+                        .map(|tex_coords| tex_coords.into_f32().collect()),
+                    tex_coords_1: reader
+                        .read_tex_coords(1)
+                        .map(|tex_coords| tex_coords.into_f32().collect()),
+                    tangents: reader.read_tangents().map(|tangents| {
+                        tangents
+                            .map(|tangent| {
+                                let [x, y, z] = vector_to_z_up([tangent[0], tangent[1], tangent[2]]);
+                                [x, y, z, tangent[3]]
+                            })
+                            .collect()
+                    }),
+                    colors: reader.read_colors(0).map(|colors| colors.into_rgba_f32().collect()),
+                    joints: reader.read_joints(0).map(|joints| joints.into_u16().collect()),
+                    weights: reader.read_weights(0).map(|weights| weights.into_f32().collect()),
+                    morph_targets: reader
+                        .read_morph_targets()
+                        .map(|(positions, normals, tangents)| MorphTarget {
+                            positions: positions.map(|deltas| deltas.map(vector_to_z_up).collect()),
+                            normals: normals.map(|deltas| deltas.map(vector_to_z_up).collect()),
+                            tangents: tangents.map(|deltas| deltas.map(vector_to_z_up).collect()),
                         })
-                        .collect::<Vec<Vertex>>()
-                }) {
-                Some(vertices) => vertices,
+                        .collect(),
+                },
                 None => continue,
             };
 
             let indices: Vec<_> = match reader.read_indices().unwrap() {
-                gltf::mesh::util::ReadIndices::U8(iter) => iter.map(|index| index as u16).collect(),
-                gltf::mesh::util::ReadIndices::U16(iter) => iter.map(|index| index as u16).collect(),
-                gltf::mesh::util::ReadIndices::U32(iter) => iter.map(|index| index as u16).collect(),
+                gltf::mesh::util::ReadIndices::U8(iter) => iter.map(|index| index as u32).collect(),
+                gltf::mesh::util::ReadIndices::U16(iter) => iter.map(|index| index as u32).collect(),
+                gltf::mesh::util::ReadIndices::U32(iter) => iter.collect(),
             };
 
-            meshes.push(Mesh::new(device.clone(), command_pool, &vertices, &indices)?);
+            let material = primitive.material().index().unwrap_or(default_material);
+
+            let optimized = mesh_optimizer::optimize(&vertices, &indices)?;
+            log::debug!("optimized mesh {}: {}", mesh.index(), optimized.report);
+            total_report.vertex_count_before += optimized.report.vertex_count_before;
+            total_report.vertex_count_after += optimized.report.vertex_count_after;
+            total_acmr_before += optimized.report.acmr_before * (indices.len() / 3) as f32;
+            total_acmr_after += optimized.report.acmr_after * (indices.len() / 3) as f32;
+            triangle_count += indices.len() / 3;
+
+            mesh_indices[mesh.index()] = Some(meshes.len());
+            meshes.push(geometry.add_mesh(&optimized.vertices, &optimized.indices, material)?);
+        }
+
+        // acmr of the whole scene is weighted by the number of triangles
+        if triangle_count > 0 {
+            total_report.acmr_before = total_acmr_before / triangle_count as f32;
+            total_report.acmr_after = total_acmr_after / triangle_count as f32;
+        }
+        log::info!("optimized {} meshes, {}", meshes.len(), total_report);
+
+        let geometry = geometry.build(device, uploader)?;
+        uploader.flush()?;
+
+        let hierarchy = NodeHierarchy::from_gltf(&loaded_data);
+
+        let mut skins = Vec::with_capacity(loaded_data.skins().len());
+        let mut joint_count = 0;
+        for skin in loaded_data.skins() {
+            let skin = Skin::from_gltf(&skin, blob, joint_count);
+            joint_count = skin.joint_range().end();
+            skins.push(skin);
         }
 
-        Ok(Self { meshes })
+        let mut instances = Vec::new();
+        let mut instance_nodes = Vec::new();
+        match loaded_data.default_scene().or_else(|| loaded_data.scenes().next()) {
+            Some(scene) => {
+                for node in scene.nodes() {
+                    collect_instances(
+                        &node,
+                        &hierarchy,
+                        &mesh_indices,
+                        &meshes,
+                        &skins,
+                        &mut instances,
+                        &mut instance_nodes,
+                    );
+                }
+            }
+            None => {
+                instances.extend(
+                    meshes
+                        .iter()
+                        .enumerate()
+                        .map(|(index, mesh)| MeshInstance::new(index, glm::Mat4::identity(), mesh.bounds())),
+                );
+                instance_nodes.resize(instances.len(), None);
+            }
+        }
+
+        // every morphed instance has its own weights, they start as the weights of its node or mesh
+        let mut morph_weight_count = 0;
+        for instance in &mut instances {
+            let target_count = meshes[instance.mesh].morph_target_count();
+            if target_count > 0 {
+                instance.morph_weight_offset = Some(morph_weight_count);
+                morph_weight_count += target_count;
+            }
+        }
+
+        let clips = loaded_data
+            .animations()
+            .map(|animation| AnimationClip::from_gltf(&animation, blob))
+            .collect::<Result<Vec<_>>>()?;
+        let mut animation_player = AnimationPlayer::new(clips);
+        if !animation_player.clips().is_empty() {
+            log::info!("loaded {} animations", animation_player.clips().len());
+            animation_player.play(0);
+        }
+
+        let mut result = Self {
+            geometry,
+            meshes,
+            materials,
+            textures,
+            instances,
+            instance_nodes,
+            hierarchy,
+            skins,
+            joint_matrices: vec![glm::Mat4::identity(); joint_count as usize],
+            morph_weights: vec![0.0; morph_weight_count as usize],
+            animation_player,
+        };
+        result.update_joint_matrices();
+        result.update_morph_weights();
+
+        Ok(result)
+    }
+
+    // advances the animations by dt seconds and evaluates the nodes, joints and instances they move.
+    // Returns whether transforms of instances changed
+    pub fn update(&mut self, dt: f32) -> bool {
+        if self.animation_player.clips().is_empty() {
+            return false;
+        }
+
+        self.animation_player.update(dt);
+        self.animation_player.apply(&mut self.hierarchy);
+        self.hierarchy.update();
+        self.update_joint_matrices();
+        self.update_morph_weights();
+
+        let mut changed = false;
+        for (instance, node) in self.instances.iter_mut().zip(&self.instance_nodes) {
+            let node = match node {
+                Some(node) => *node,
+                None => continue,
+            };
+
+            let transform = to_z_up(self.hierarchy.world_transform(node));
+            if transform != instance.transform {
+                instance.set_transform(transform, self.meshes[instance.mesh].bounds());
+                changed = true;
+            }
+        }
+
+        changed
+    }
+
+    fn update_joint_matrices(&mut self) {
+        for skin in &self.skins {
+            let range = skin.joint_range();
+            let joint_matrices = &mut self.joint_matrices[range.offset as usize..range.end() as usize];
+            for (target, joint_matrix) in joint_matrices.iter_mut().zip(skin.joint_matrices(&self.hierarchy)) {
+                *target = to_z_up(&joint_matrix);
+            }
+        }
+    }
+
+    // missing weights of a node are zero
+    fn update_morph_weights(&mut self) {
+        let hierarchy = &self.hierarchy;
+        for (instance, node) in self.instances.iter().zip(&self.instance_nodes) {
+            let offset = match instance.morph_weight_offset {
+                Some(offset) => offset as usize,
+                None => continue,
+            };
+            let target_count = self.meshes[instance.mesh].morph_target_count() as usize;
+            let node_weights = node
+                .and_then(|node| hierarchy.node(node))
+                .map_or(&[][..], |node| node.weights.as_slice());
+
+            let weights = &mut self.morph_weights[offset..offset + target_count];
+            for (i, weight) in weights.iter_mut().enumerate() {
+                *weight = node_weights.get(i).copied().unwrap_or(0.0);
+            }
+        }
     }
 
     pub unsafe fn destroy(&self) {
-        self.meshes.iter().for_each(|mesh| mesh.destroy());
+        self.geometry.destroy();
+        self.textures.iter().for_each(|texture| texture.destroy());
+    }
+
+    #[inline]
+    pub fn geometry(&self) -> &GeometryBuffers {
+        &self.geometry
     }
 
     #[inline]
     pub fn meshes(&self) -> &[Mesh] {
         &self.meshes
     }
+
+    #[inline]
+    pub fn materials(&self) -> &[Material] {
+        &self.materials
+    }
+
+    #[inline]
+    pub fn textures(&self) -> &[Texture] {
+        &self.textures
+    }
+
+    #[inline]
+    pub fn instances(&self) -> &[MeshInstance] {
+        &self.instances
+    }
+
+    #[inline]
+    pub fn joint_matrices(&self) -> &[glm::Mat4] {
+        &self.joint_matrices
+    }
+
+    #[inline]
+    pub fn morph_weights(&self) -> &[f32] {
+        &self.morph_weights
+    }
+
+    #[inline]
+    pub fn animation_player_mut(&mut self) -> &mut AnimationPlayer {
+        &mut self.animation_player
+    }
+}
+
+// decodes embedded or external image into RGBA8 pixels
+fn load_image(image: &gltf::Image, path: &Path, blob: &[u8]) -> Result<image::RgbaImage> {
+    let decoded = match image.source() {
+        gltf::image::Source::View { view, .. } => {
+            let data = &blob[view.offset()..view.offset() + view.length()];
+            image::load_from_memory(data)?
+        }
+        gltf::image::Source::Uri { uri, .. } => {
+            let image_path = path.parent().unwrap_or_else(|| Path::new("")).join(uri);
+            image::open(&image_path).map_err(|e| Error::msg(format!("failed to load image {:?}: {}", image_path, e)))?
+        }
+    };
+
+    Ok(decoded.into_rgba8())
+}
+
+// skinned instances are placed by their joints, the transform of their node is only used for the bounds
+// until the joints are evaluated
+fn collect_instances(
+    node: &gltf::Node,
+    hierarchy: &NodeHierarchy,
+    mesh_indices: &[Option<usize>],
+    meshes: &[Mesh],
+    skins: &[Skin],
+    instances: &mut Vec<MeshInstance>,
+    instance_nodes: &mut Vec<Option<usize>>,
+) {
+    if let Some(mesh) = node.mesh().and_then(|mesh| mesh_indices[mesh.index()]) {
+        let transform = to_z_up(hierarchy.world_transform(node.index()));
+        let mut instance = MeshInstance::new(mesh, transform, meshes[mesh].bounds());
+        instance.joints = node.skin().map(|skin| skins[skin.index()].joint_range());
+        instances.push(instance);
+        instance_nodes.push(Some(node.index()));
+    }
+
+    for child in node.children() {
+        collect_instances(
+            &child,
+            hierarchy,
+            mesh_indices,
+            meshes,
+            skins,
+            instances,
+            instance_nodes,
+        );
+    }
+}
+
+// converts a y-up gltf transform to the same z-up basis as vertices
+fn to_z_up(transform: &glm::Mat4) -> glm::Mat4 {
+    #[rustfmt::skip]
+    let basis = glm::mat4(
+        1.0, 0.0, 0.0, 0.0,
+        0.0, 0.0, -1.0, 0.0,
+        0.0, 1.0, 0.0, 0.0,
+        0.0, 0.0, 0.0, 1.0,
+    );
+    basis * transform * basis.transpose()
+}
+
+// converts a y-up gltf position or direction to the z-up basis
+fn vector_to_z_up(vector: [f32; 3]) -> [f32; 3] {
+    [vector[0], -vector[2], vector[1]]
 }
@@ -0,0 +1,59 @@
+use anyhow::{Error, Result};
+
+use crate::rendering::{DeviceSelector, PresentMode, VertexStorage};
+
+pub const DEVICE_ENV_VAR: &str = "VRS_DEVICE";
+
+#[derive(Debug, Clone, Default)]
+pub struct Settings {
+    pub present_mode: PresentMode,
+    pub device: Option<DeviceSelector>,
+    pub disable_bindless: bool,
+    pub disable_gpu_culling: bool,
+    pub vertex_storage: VertexStorage,
+}
+
+impl Settings {
+    pub fn from_args() -> Result<Self> {
+        let mut settings = Settings::default();
+
+        if let Ok(device) = std::env::var(DEVICE_ENV_VAR) {
+            settings.device = Some(device.parse()?);
+        }
+
+        settings.parse(std::env::args().skip(1))?;
+
+        Ok(settings)
+    }
+
+    fn parse<I>(&mut self, args: I) -> Result<()>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (name, value) = match arg.find('=') {
+                Some(position) => (arg[..position].to_owned(), Some(arg[position + 1..].to_owned())),
+                None => (arg, None),
+            };
+
+            let mut value = || {
+                value
+                    .clone()
+                    .or_else(|| args.next())
+                    .ok_or_else(|| Error::msg(format!("missing value for {}", name)))
+            };
+
+            match name.as_str() {
+                "--present-mode" => self.present_mode = value()?.parse()?,
+                "--device" => self.device = Some(value()?.parse()?),
+                "--no-bindless" => self.disable_bindless = true,
+                "--no-gpu-culling" => self.disable_gpu_culling = true,
+                "--vertex-storage" => self.vertex_storage = value()?.parse()?,
+                _ => return Err(Error::msg(format!("unknown argument: {}", name))),
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,151 @@
+use crate::rendering::JointRange;
+
+// transform of a node relative to its parent
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub parent: Option<usize>,
+    pub translation: glm::Vec3,
+    pub rotation: glm::Quat,
+    pub scale: glm::Vec3,
+    // morph target weights of the node's mesh
+    pub weights: Vec<f32>,
+}
+
+impl Node {
+    pub fn from_gltf(node: &gltf::Node) -> Self {
+        let (translation, rotation, scale) = node.transform().decomposed();
+
+        Self {
+            parent: None,
+            translation: glm::make_vec3(&translation),
+            rotation: glm::quat(rotation[0], rotation[1], rotation[2], rotation[3]),
+            scale: glm::make_vec3(&scale),
+            weights: node
+                .weights()
+                .or_else(|| node.mesh().and_then(|mesh| mesh.weights()))
+                .map(<[f32]>::to_vec)
+                .unwrap_or_default(),
+        }
+    }
+
+    pub fn local_transform(&self) -> glm::Mat4 {
+        glm::translation(&self.translation) * glm::quat_to_mat4(&self.rotation) * glm::scaling(&self.scale)
+    }
+}
+
+// all nodes of the file, indexed like in gltf. World transforms are in the gltf basis
+pub struct NodeHierarchy {
+    nodes: Vec<Node>,
+    // local transforms from the file, animations start from them
+    rest_nodes: Vec<Node>,
+    // parents always come before their children
+    order: Vec<usize>,
+    world_transforms: Vec<glm::Mat4>,
+}
+
+impl NodeHierarchy {
+    pub fn from_gltf(document: &gltf::Document) -> Self {
+        let mut nodes = document.nodes().map(|node| Node::from_gltf(&node)).collect::<Vec<_>>();
+        let mut children = vec![Vec::new(); nodes.len()];
+        for node in document.nodes() {
+            for child in node.children() {
+                nodes[child.index()].parent = Some(node.index());
+                children[node.index()].push(child.index());
+            }
+        }
+
+        let mut order = Vec::with_capacity(nodes.len());
+        let mut stack = (0..nodes.len())
+            .rev()
+            .filter(|&index| nodes[index].parent.is_none())
+            .collect::<Vec<_>>();
+        while let Some(index) = stack.pop() {
+            order.push(index);
+            stack.extend(children[index].iter().rev());
+        }
+
+        let mut result = Self {
+            world_transforms: vec![glm::Mat4::identity(); nodes.len()],
+            rest_nodes: nodes.clone(),
+            nodes,
+            order,
+        };
+        result.update();
+
+        result
+    }
+
+    // must be called after local transforms change
+    pub fn update(&mut self) {
+        for &index in &self.order {
+            let node = &self.nodes[index];
+            let local_transform = node.local_transform();
+            self.world_transforms[index] = match node.parent {
+                Some(parent) => self.world_transforms[parent] * local_transform,
+                None => local_transform,
+            };
+        }
+    }
+
+    pub fn reset_to_rest(&mut self) {
+        self.nodes.clone_from(&self.rest_nodes);
+    }
+
+    #[inline]
+    pub fn node(&self, node: usize) -> Option<&Node> {
+        self.nodes.get(node)
+    }
+
+    #[inline]
+    pub fn node_mut(&mut self, node: usize) -> Option<&mut Node> {
+        self.nodes.get_mut(node)
+    }
+
+    #[inline]
+    pub fn world_transform(&self, node: usize) -> &glm::Mat4 {
+        &self.world_transforms[node]
+    }
+}
+
+// joints of a skin are nodes, their matrices are stored after the ones of all previous skins
+pub struct Skin {
+    joints: Vec<usize>,
+    inverse_bind_matrices: Vec<glm::Mat4>,
+    joint_offset: u32,
+}
+
+impl Skin {
+    pub fn from_gltf(skin: &gltf::Skin, blob: &[u8], joint_offset: u32) -> Self {
+        let joints = skin.joints().map(|joint| joint.index()).collect::<Vec<_>>();
+
+        // missing matrices mean the joints are already in the bind pose
+        let mut inverse_bind_matrices: Vec<glm::Mat4> = skin
+            .reader(|_| Some(blob))
+            .read_inverse_bind_matrices()
+            .map(|matrices| matrices.map(|matrix| glm::make_mat4(&matrix.concat())).collect())
+            .unwrap_or_default();
+        inverse_bind_matrices.resize(joints.len(), glm::Mat4::identity());
+
+        Self {
+            joints,
+            inverse_bind_matrices,
+            joint_offset,
+        }
+    }
+
+    #[inline]
+    pub fn joint_range(&self) -> JointRange {
+        JointRange {
+            offset: self.joint_offset,
+            count: self.joints.len() as u32,
+        }
+    }
+
+    // transforms bind pose vertices of the mesh to the current pose in the gltf world
+    pub fn joint_matrices<'a>(&'a self, hierarchy: &'a NodeHierarchy) -> impl Iterator<Item = glm::Mat4> + 'a {
+        self.joints
+            .iter()
+            .zip(&self.inverse_bind_matrices)
+            .map(move |(&joint, inverse_bind_matrix)| hierarchy.world_transform(joint) * inverse_bind_matrix)
+    }
+}